@@ -0,0 +1,104 @@
+//! Load test of the websocket connections: many concurrent clients connecting, registering,
+//! sending requests and disconnecting through the `/chat` route.
+//! The server shards run in parallel, the improvement is visible on multi-core machines.
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::Runtime;
+use warp::{test::WsClient, Filter};
+
+use chat_server::{
+    config::Config,
+    rate_limit::{RateLimit, RateLimits},
+    registry_actor::RegistryHandle,
+    server_actor::ServerHandle,
+    websocket::{handle_connection, ClientMessage, ServerMessage},
+    UserId,
+};
+
+const CLIENTS: usize = 500;
+const REQUESTS_PER_CLIENT: usize = 10;
+
+/// Receives the server messages until the reply to a `ListDirectChannels` request.
+async fn direct_channels(client: &mut WsClient) {
+    loop {
+        let message = client.recv().await.unwrap();
+        let message: ServerMessage = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        if let ServerMessage::DirectChannels { .. } = message {
+            return;
+        }
+    }
+}
+
+/// Runs the lifecycle of `CLIENTS` concurrent websocket clients.
+async fn clients<F>(route: F)
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply + Send,
+{
+    let clients = (0..CLIENTS).map(|i| {
+        let route = route.clone();
+        tokio::spawn(async move {
+            let mut client = warp::test::ws()
+                .path("/chat")
+                .handshake(route)
+                .await
+                .unwrap();
+            let request = serde_json::to_string(&ClientMessage::ListDirectChannels {
+                user: UserId::new(&format!("user-{}", i)).unwrap(),
+            })
+            .unwrap();
+            for _ in 0..REQUESTS_PER_CLIENT {
+                client.send_text(request.clone()).await;
+                direct_channels(&mut client).await;
+            }
+        })
+    });
+    for client in futures::future::join_all(clients).await {
+        client.unwrap();
+    }
+}
+
+fn connections(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let unlimited = RateLimit::new(u32::MAX, f64::MAX);
+    let rate_limits = RateLimits {
+        connection: unlimited,
+        user: unlimited,
+        channel: unlimited,
+        webhook: unlimited,
+    };
+    let data_dir = tempfile::tempdir().unwrap();
+
+    let mut group = c.benchmark_group("connections");
+    group.throughput(Throughput::Elements(CLIENTS as u64));
+    for shards in [1, 2, 4, 8] {
+        let config = Arc::new(Config {
+            shards,
+            rate_limits,
+            data_dir: data_dir.path().into(),
+            ..Config::default()
+        });
+        let (server, registry) = runtime.block_on(async {
+            (
+                Arc::new(ServerHandle::with_config(config.clone())),
+                Arc::new(RegistryHandle::with_config(config)),
+            )
+        });
+        let route = warp::path("chat")
+            .and(warp::ws())
+            .map(move |ws: warp::ws::Ws| {
+                let (server, registry) = (server.clone(), registry.clone());
+                ws.on_upgrade(|socket| async {
+                    let _ = handle_connection(socket, server, registry).await;
+                })
+            });
+        group.bench_with_input(BenchmarkId::new("shards", shards), &shards, |b, _| {
+            b.iter(|| runtime.block_on(clients(route.clone())))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, connections);
+criterion_main!(benches);
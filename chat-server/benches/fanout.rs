@@ -0,0 +1,90 @@
+//! Throughput of delivering a chat message to all the subscribed members of a channel.
+//! Every subscriber runs in its own task like the websocket connections do.
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::{runtime::Runtime, sync::mpsc};
+
+use chat_server::{
+    channel::{Channel, Membership, Role},
+    channel_actor::ChannelHandle,
+    config::Config,
+    new_id,
+    outgoing_webhook::WebhookHandle,
+    rate_limit::RateLimit,
+    UserId,
+};
+
+const MEMBERS: [usize; 3] = [100, 1_000, 5_000];
+
+/// Spawns a channel with `members` subscribers, returns the channel and the receiver of the deliveries.
+async fn setup(
+    config: Arc<Config>,
+    members: usize,
+) -> (ChannelHandle, mpsc::UnboundedReceiver<String>) {
+    let mut channel = Channel::new(new_id(), format!("bench-{}", members));
+    for i in 0..members {
+        channel.members.insert(
+            UserId::new(&format!("user-{}", i)).unwrap(),
+            Membership::new(Role::Member),
+        );
+    }
+    let webhooks = WebhookHandle::new(config.clone());
+    let channel = ChannelHandle::create(channel, config, webhooks, Default::default())
+        .await
+        .unwrap();
+
+    let (delivered_tx, delivered_rx) = mpsc::unbounded_channel();
+    for i in 0..members {
+        let (mut subscription, _) = channel
+            .subscribe(UserId::new(&format!("user-{}", i)).unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        let delivered = delivered_tx.clone();
+        tokio::spawn(async move {
+            while let Ok(publication) = subscription.recv().await {
+                // Same cost as forwarding the publication to a websocket
+                let _ = delivered.send(publication.text.clone());
+            }
+        });
+    }
+    (channel, delivered_rx)
+}
+
+fn fanout(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config {
+        data_dir: dir.path().into(),
+        ..Config::default()
+    };
+    // Only the delivery is measured
+    config.rate_limits.channel = RateLimit::new(u32::MAX, f64::MAX);
+    let config = Arc::new(config);
+    let runtime = Runtime::new().unwrap();
+    let sender = UserId::new("user-0").unwrap();
+
+    let mut group = c.benchmark_group("fanout");
+    for members in MEMBERS {
+        let (channel, mut delivered) = runtime.block_on(setup(config.clone(), members));
+        group.throughput(Throughput::Elements(members as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(members),
+            &members,
+            |b, &members| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        channel.add_message(sender, "hello".into()).await.unwrap();
+                        for _ in 0..members {
+                            delivered.recv().await.unwrap();
+                        }
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
@@ -0,0 +1,890 @@
+//! File attachments of the messages.
+//! Files are uploaded over HTTP before being referenced by a `SendMessage`, they are stored once
+//! per content (see [`BlobStore`]) and downloaded by their id, the hex encoded SHA-256 of the content.
+//! Both require a session, a download also requires to be allowed to read a channel with a message
+//! the file is attached to.
+//! The blobs referenced by no message of any channel are garbage collected.
+//! The images get a thumbnail (see [`crate::thumbnail`]), downloaded by the id of the image.
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+use warp::{
+    http::StatusCode,
+    hyper::{body::Bytes, Body},
+    Buf, Reply,
+};
+
+use crate::{
+    channel,
+    config::Config,
+    errors::{Error, Result},
+    new_id,
+    registry_actor::RegistryHandle,
+    server_actor::ServerHandle,
+    thumbnail::{self, Job, ThumbnailPool},
+    ID,
+};
+
+/// Maximum number of attachments of a single message.
+pub const MAX_ATTACHMENTS: usize = 10;
+
+/// Maximum length in characters of an attachment name.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Maximum number of uploads and downloads in progress, the others wait for one to complete.
+const MAX_CONCURRENT_TRANSFERS: usize = 64;
+
+/// Size in bytes of the chunks a blob is downloaded in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file attached to a message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    /// Id of the blob holding the content.
+    pub id: String,
+    /// File name given by the sender.
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+    /// Width in pixels of an image.
+    pub width: Option<u32>,
+    /// Height in pixels of an image.
+    pub height: Option<u32>,
+}
+
+/// Reference of an uploaded blob in a `SendMessage`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachmentRef {
+    pub id: String,
+    /// Defaults to the blob id
+    #[serde(default)]
+    pub name: String,
+}
+
+/// An uploaded file, shared by all the attachments with the same content.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Blob {
+    /// Hex encoded SHA-256 of the content.
+    pub id: String,
+    /// Type given by the first upload of the content.
+    pub mime_type: String,
+    pub size: u64,
+    /// Set for the images in a supported format, they get a thumbnail.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+/// Content addressed store of the uploaded files.
+/// A blob is stored in `<blob dir>/<first 2 hex digits>/<id>` next to its metadata (`<id>.meta`)
+/// and thumbnail (`<id>.thumb`), uploads are written to `<blob dir>/tmp` first and renamed once complete.
+pub struct BlobStore {
+    config: Arc<Config>,
+    thumbnails: ThumbnailPool,
+    // Bounds the uploads and downloads in progress
+    transfers: Arc<Semaphore>,
+}
+
+impl BlobStore {
+    pub fn new(config: Arc<Config>) -> Self {
+        let thumbnails = ThumbnailPool::new(&config);
+        Self {
+            config,
+            thumbnails,
+            transfers: Arc::new(Semaphore::new(MAX_CONCURRENT_TRANSFERS)),
+        }
+    }
+
+    /// Stores `content` unless a blob with the same content exists, see [`BlobStore::store_stream`].
+    pub async fn store(&self, content: &[u8], mime_type: &str) -> Result<(Blob, bool)> {
+        let chunk = Bytes::copy_from_slice(content);
+        self.store_stream(futures::stream::iter(Some(Ok(chunk))), mime_type)
+            .await
+    }
+
+    /// Stores the content read from `content` unless a blob with the same content exists.
+    /// The content is written to a temporary file as it is received, never held in memory.
+    /// Returns the blob and whether it was created.
+    pub async fn store_stream<S>(&self, content: S, mime_type: &str) -> Result<(Blob, bool)>
+    where
+        S: Stream<Item = Result<Bytes>>,
+    {
+        let mime_type = mime_type.to_ascii_lowercase();
+        if !self.config.attachments.accepts(&mime_type) {
+            return Err(Error::UnsupportedMediaType(mime_type));
+        }
+        let _permit = self
+            .transfers
+            .acquire()
+            .await
+            .map_err(|err| Error::Generic(err.to_string()))?;
+        let tmp_dir = self.tmp_dir();
+        tokio::fs::create_dir_all(&tmp_dir).await?;
+        let tmp_blob = tmp_dir.join(new_id().to_string());
+        let stored = self.write_upload(&tmp_blob, content, mime_type).await;
+        // Nothing to keep if the upload failed or the content is already stored
+        if !matches!(stored, Ok((_, true))) {
+            match tokio::fs::remove_file(&tmp_blob).await {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => warn!(%err, path = ?tmp_blob, "Upload not removed"),
+            }
+        }
+        stored
+    }
+
+    /// Writes the upload `content` to `tmp_blob` and stores it as a new blob if needed.
+    async fn write_upload<S>(
+        &self,
+        tmp_blob: &std::path::Path,
+        content: S,
+        mime_type: String,
+    ) -> Result<(Blob, bool)>
+    where
+        S: Stream<Item = Result<Bytes>>,
+    {
+        futures::pin_mut!(content);
+        let max_size = self.config.attachments.max_size_bytes;
+        let mut file = tokio::fs::File::create(tmp_blob).await?;
+        let mut digest = ring::digest::Context::new(&ring::digest::SHA256);
+        let mut size = 0;
+        while let Some(chunk) = content.next().await {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+            if size > max_size {
+                return Err(Error::AttachmentTooLarge(max_size));
+            }
+            digest.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        drop(file);
+        if size == 0 {
+            return Err(Error::InvalidAttachment("empty file".into()));
+        }
+        let id: String = digest
+            .finish()
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        if let Some(blob) = self.get(&id).await? {
+            // Uploaded again, not to be collected before it is referenced
+            self.touch(&blob.id).await?;
+            self.queue_thumbnail(&blob).await?;
+            return Ok((blob, false));
+        }
+
+        let dimensions = if mime_type.starts_with("image/") {
+            let path = tmp_blob.to_owned();
+            tokio::task::spawn_blocking(move || thumbnail::file_dimensions(&path))
+                .await
+                .map_err(|err| Error::Generic(err.to_string()))?
+        } else {
+            None
+        };
+        let blob = Blob {
+            id,
+            mime_type,
+            size,
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+        };
+        let tmp_dir = self.tmp_dir();
+        tokio::fs::create_dir_all(self.shard_dir(&blob.id)).await?;
+        // The metadata goes first: a blob is only visible once complete and described
+        let tmp_meta = tmp_dir.join(new_id().to_string());
+        tokio::fs::write(&tmp_meta, serde_json::to_vec(&blob)?).await?;
+        tokio::fs::rename(&tmp_meta, self.meta_path(&blob.id)).await?;
+        tokio::fs::rename(tmp_blob, self.blob_path(&blob.id)).await?;
+        self.queue_thumbnail(&blob).await?;
+        Ok((blob, true))
+    }
+
+    /// Queues the generation of the thumbnail of an image without one.
+    /// Returns false if the thumbnail exists or its generation is already queued or failed.
+    async fn queue_thumbnail(&self, blob: &Blob) -> Result<bool> {
+        if blob.width.is_none() {
+            return Ok(false);
+        }
+        let target = self.thumbnail_path(&blob.id);
+        match tokio::fs::metadata(&target).await {
+            Ok(_) => return Ok(false),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => Err(err)?,
+        }
+        let tmp_dir = self.tmp_dir();
+        tokio::fs::create_dir_all(&tmp_dir).await?;
+        let queued = self
+            .thumbnails
+            .enqueue(Job {
+                source: self.blob_path(&blob.id),
+                tmp: tmp_dir.join(new_id().to_string()),
+                target,
+            })
+            .await;
+        Ok(queued)
+    }
+
+    /// Queues the generation of the thumbnails of the stored images without one,
+    /// e.g. those queued before a restart.
+    /// Returns the number of images queued.
+    pub async fn recover_thumbnails(&self) -> Result<usize> {
+        let mut queued = 0;
+        let mut dirs = match tokio::fs::read_dir(self.config.blob_dir()).await {
+            Ok(dirs) => dirs,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => Err(err)?,
+        };
+        while let Some(dir) = dirs.next_entry().await? {
+            if !dir.file_type().await?.is_dir() || dir.path() == self.tmp_dir() {
+                continue;
+            }
+            let mut blobs = tokio::fs::read_dir(dir.path()).await?;
+            while let Some(blob) = blobs.next_entry().await? {
+                let id = match blob.file_name().into_string() {
+                    Ok(id) if is_valid_id(&id) => id,
+                    _ => continue,
+                };
+                if let Some(blob) = self.get(&id).await? {
+                    if self.queue_thumbnail(&blob).await? {
+                        queued += 1;
+                    }
+                }
+            }
+        }
+        Ok(queued)
+    }
+
+    /// Gets the blob with `id`, `None` if it does not exist.
+    pub async fn get(&self, id: &str) -> Result<Option<Blob>> {
+        if !is_valid_id(id) {
+            return Ok(None);
+        }
+        // The metadata of an upload in progress exists without its blob
+        let found = match tokio::fs::metadata(self.blob_path(id)).await {
+            Ok(_) => tokio::fs::read(self.meta_path(id)).await,
+            Err(err) => Err(err),
+        };
+        match found {
+            Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err)?,
+        }
+    }
+
+    /// Opens the blob with `id` to read its content.
+    pub async fn open(&self, id: &str) -> Result<(Blob, tokio::fs::File)> {
+        let blob = self.get(id).await?.ok_or(Error::AttachmentNotFound)?;
+        match tokio::fs::File::open(self.blob_path(id)).await {
+            Ok(file) => Ok((blob, file)),
+            // Collected in the meantime
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Err(Error::AttachmentNotFound)
+            }
+            Err(err) => Err(err)?,
+        }
+    }
+
+    /// Reads the PNG thumbnail of the image with `id`.
+    /// Fails with [`Error::AttachmentNotFound`] until the thumbnail is generated.
+    pub async fn read_thumbnail(&self, id: &str) -> Result<Vec<u8>> {
+        if !is_valid_id(id) {
+            return Err(Error::AttachmentNotFound);
+        }
+        match tokio::fs::read(self.thumbnail_path(id)).await {
+            Ok(content) => Ok(content),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Err(Error::AttachmentNotFound)
+            }
+            Err(err) => Err(err)?,
+        }
+    }
+
+    /// Resolves the attachments referenced by a message.
+    /// The blobs are marked as used so that they are not collected before the message is stored.
+    pub async fn resolve(&self, refs: Vec<AttachmentRef>) -> Result<Vec<Attachment>> {
+        if refs.len() > MAX_ATTACHMENTS {
+            return Err(Error::InvalidAttachment(format!(
+                "at most {} attachments per message",
+                MAX_ATTACHMENTS
+            )));
+        }
+        let mut attachments = Vec::with_capacity(refs.len());
+        for r in refs {
+            let blob = self.get(&r.id).await?.ok_or(Error::AttachmentNotFound)?;
+            let name = r.name.trim();
+            if name.chars().count() > MAX_NAME_LENGTH || name.chars().any(char::is_control) {
+                return Err(Error::InvalidAttachment(format!("invalid name {:?}", name)));
+            }
+            let name = if name.is_empty() {
+                blob.id.clone()
+            } else {
+                name.to_string()
+            };
+            self.touch(&blob.id).await?;
+            attachments.push(Attachment {
+                id: blob.id,
+                name,
+                mime_type: blob.mime_type,
+                size: blob.size,
+                width: blob.width,
+                height: blob.height,
+            });
+        }
+        Ok(attachments)
+    }
+
+    /// Deletes the blobs referenced by no message that were neither uploaded nor referenced
+    /// during the grace period, along with the stale uploads and the metadata and thumbnails
+    /// left without a blob (e.g. a thumbnail generated while its image was deleted).
+    /// Returns the number of deleted blobs.
+    pub async fn collect_garbage(&self) -> Result<usize> {
+        let grace = Duration::from_secs(self.config.attachments.gc_grace_seconds);
+        let mut referenced = HashSet::new();
+        // Read from the data files so that the channels with an unreadable info keep their blobs
+        for channel_id in channel::data_file_ids(&self.config).await? {
+            for message in channel::load_messages(&self.config, channel_id).await? {
+                referenced.extend(message.attachments.into_iter().map(|a| a.id));
+            }
+        }
+
+        let mut deleted = 0;
+        let mut dirs = match tokio::fs::read_dir(self.config.blob_dir()).await {
+            Ok(dirs) => dirs,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => Err(err)?,
+        };
+        while let Some(dir) = dirs.next_entry().await? {
+            if !dir.file_type().await?.is_dir() {
+                continue;
+            }
+            if dir.path() == self.tmp_dir() {
+                let mut uploads = tokio::fs::read_dir(dir.path()).await?;
+                while let Some(upload) = uploads.next_entry().await? {
+                    if is_older_than(&upload.path(), grace).await? {
+                        tokio::fs::remove_file(upload.path()).await?;
+                    }
+                }
+                continue;
+            }
+            let mut blobs = tokio::fs::read_dir(dir.path()).await?;
+            while let Some(blob) = blobs.next_entry().await? {
+                let id = match blob.file_name().into_string() {
+                    Ok(id) if is_valid_id(&id) => id,
+                    Ok(name) => {
+                        self.collect_orphan(&name, grace).await?;
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if referenced.contains(&id) || !is_older_than(&blob.path(), grace).await? {
+                    continue;
+                }
+                tokio::fs::remove_file(blob.path()).await?;
+                for path in [self.meta_path(&id), self.thumbnail_path(&id)] {
+                    match tokio::fs::remove_file(path).await {
+                        Ok(()) => {}
+                        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                        Err(err) => Err(err)?,
+                    }
+                }
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Deletes the metadata or thumbnail file `name` if its blob does not exist,
+    /// the metadata of an upload in progress is older than its blob by at most the grace period.
+    async fn collect_orphan(&self, name: &str, grace: Duration) -> Result {
+        let id = match name.split_once('.') {
+            Some((id, "meta")) | Some((id, "thumb")) if is_valid_id(id) => id,
+            _ => return Ok(()),
+        };
+        let path = self.shard_dir(id).join(name);
+        match tokio::fs::metadata(self.blob_path(id)).await {
+            Ok(_) => return Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => Err(err)?,
+        }
+        // Deleted along with its blob since the folder was listed
+        match is_older_than(&path, grace).await {
+            Ok(false) => return Ok(()),
+            Ok(true) => {}
+            Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => Err(err)?,
+        }
+        Ok(())
+    }
+
+    /// Sets the modification time of the blob with `id` to now.
+    async fn touch(&self, id: &str) -> Result {
+        let path = self.blob_path(id);
+        tokio::task::spawn_blocking(move || {
+            std::fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(SystemTime::now())
+        })
+        .await
+        .map_err(|err| Error::Generic(err.to_string()))??;
+        Ok(())
+    }
+
+    /// Folder of the blobs sharing the first 2 hex digits of `id`.
+    fn shard_dir(&self, id: &str) -> PathBuf {
+        self.config.blob_dir().join(&id[..2])
+    }
+
+    fn blob_path(&self, id: &str) -> PathBuf {
+        self.shard_dir(id).join(id)
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.blob_path(id).with_extension("meta")
+    }
+
+    fn thumbnail_path(&self, id: &str) -> PathBuf {
+        self.blob_path(id).with_extension("thumb")
+    }
+
+    fn tmp_dir(&self) -> PathBuf {
+        self.config.blob_dir().join("tmp")
+    }
+}
+
+/// Checks that `id` is a hex encoded SHA-256 (lowercase).
+fn is_valid_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+async fn is_older_than(path: &std::path::Path, age: Duration) -> Result<bool> {
+    let modified = tokio::fs::metadata(path).await?.modified()?;
+    Ok(SystemTime::now()
+        .duration_since(modified)
+        .is_ok_and(|elapsed| elapsed >= age))
+}
+
+/// Collects the unreferenced blobs periodically, never returns.
+/// The missing thumbnails are queued again on start and after every collection.
+pub async fn run_garbage_collection(blobs: Arc<BlobStore>) {
+    let period = Duration::from_secs(blobs.config.attachments.gc_interval_seconds);
+    let mut interval = tokio::time::interval(period);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        match blobs.recover_thumbnails().await {
+            Ok(0) => {}
+            Ok(queued) => info!(queued, "Missing thumbnails queued"),
+            Err(err) => error!(%err, "Thumbnails recovery error"),
+        }
+        interval.tick().await;
+        match blobs.collect_garbage().await {
+            Ok(0) => {}
+            Ok(deleted) => info!(deleted, "Unreferenced attachments collected"),
+            Err(err) => error!(%err, "Attachments garbage collection error"),
+        }
+    }
+}
+
+/// Channel a download is authorized by.
+#[derive(Debug, Deserialize)]
+pub struct DownloadQuery {
+    /// A channel with a message the file is attached to.
+    pub channel_id: ID,
+}
+
+/// Stores an uploaded file of type `content_type` (the MIME type parameters are ignored)
+/// on behalf of the user of the session in the `authorization` header (`Bearer <session id>`).
+/// Replies `201 Created` with the blob, whether the content was already stored or not.
+pub async fn handle_upload<S, B>(
+    content_type: Option<String>,
+    authorization: Option<String>,
+    content: S,
+    server: Arc<ServerHandle>,
+    registry: Arc<RegistryHandle>,
+) -> std::result::Result<warp::reply::Response, Infallible>
+where
+    S: Stream<Item = std::result::Result<B, warp::Error>>,
+    B: Buf,
+{
+    let user = match server.authenticate(authorization.as_deref()).await {
+        Ok(user) => user,
+        Err(err) => return Ok(error_reply(&err)),
+    };
+    let mime_type = content_type
+        .as_deref()
+        .and_then(|content_type| content_type.split(';').next())
+        .map(str::trim)
+        .unwrap_or("application/octet-stream");
+    let content = content.map(|chunk| match chunk {
+        Ok(mut chunk) => Ok(chunk.copy_to_bytes(chunk.remaining())),
+        Err(err) => Err(Error::InvalidAttachment(err.to_string())),
+    });
+    let reply = match registry.blobs().store_stream(content, mime_type).await {
+        Ok((blob, created)) => {
+            info!(%user, id = %blob.id, size = blob.size, created, "Attachment uploaded");
+            // The same status either way, it must not tell whether a content is stored
+            warp::reply::with_status(warp::reply::json(&blob), StatusCode::CREATED).into_response()
+        }
+        Err(err) => error_reply(&err),
+    };
+    Ok(reply)
+}
+
+/// Replies with the content of the blob `id` to the user of the session in the `authorization` header.
+/// Only the images are displayed inline, the other files are downloaded.
+pub async fn handle_download(
+    id: String,
+    authorization: Option<String>,
+    query: DownloadQuery,
+    server: Arc<ServerHandle>,
+    registry: Arc<RegistryHandle>,
+) -> std::result::Result<warp::reply::Response, Infallible> {
+    if let Err(err) = authorize_download(&id, authorization, &query, &server, &registry).await {
+        return Ok(error_reply(&err));
+    }
+    let blobs = registry.blobs();
+    // Held until the content is sent
+    let permit = match blobs.transfers.clone().acquire_owned().await {
+        Ok(permit) => permit,
+        Err(err) => return Ok(error_reply(&Error::Generic(err.to_string()))),
+    };
+    let (blob, file) = match blobs.open(&id).await {
+        Ok(opened) => opened,
+        Err(err) => return Ok(error_reply(&err)),
+    };
+    let chunks = futures::stream::unfold(Some((file, permit)), |state| async move {
+        let (mut file, permit) = state?;
+        let mut chunk = vec![0; CHUNK_SIZE];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some((Ok(Bytes::from(chunk)), Some((file, permit))))
+            }
+            Err(err) => Some((Err(err), None)),
+        }
+    });
+    // SVG images may run scripts
+    let inline = blob.mime_type.starts_with("image/") && blob.mime_type != "image/svg+xml";
+    let disposition = if inline { "inline" } else { "attachment" };
+    Ok(content_reply(
+        Body::wrap_stream(chunks),
+        &blob.mime_type,
+        disposition,
+    ))
+}
+
+/// Replies with the PNG thumbnail of the image `id`, `404 Not Found` until it is generated.
+/// Authorized like a download of the image.
+pub async fn handle_thumbnail(
+    id: String,
+    authorization: Option<String>,
+    query: DownloadQuery,
+    server: Arc<ServerHandle>,
+    registry: Arc<RegistryHandle>,
+) -> std::result::Result<warp::reply::Response, Infallible> {
+    if let Err(err) = authorize_download(&id, authorization, &query, &server, &registry).await {
+        return Ok(error_reply(&err));
+    }
+    let reply = match registry.blobs().read_thumbnail(&id).await {
+        Ok(content) => content_reply(content.into(), "image/png", "inline"),
+        Err(err) => error_reply(&err),
+    };
+    Ok(reply)
+}
+
+/// Checks that the user of the session may read the channel of the query
+/// and that a message of the channel has the blob `id` attached.
+async fn authorize_download(
+    id: &str,
+    authorization: Option<String>,
+    query: &DownloadQuery,
+    server: &ServerHandle,
+    registry: &RegistryHandle,
+) -> Result {
+    let user = server.authenticate(authorization.as_deref()).await?;
+    let channel = registry.get_channel(query.channel_id).await?;
+    if !channel.has_attachment(user, id.to_string()).await? {
+        return Err(Error::AttachmentNotFound);
+    }
+    Ok(())
+}
+
+fn content_reply(content: Body, mime_type: &str, disposition: &str) -> warp::reply::Response {
+    let mut reply = warp::reply::Response::new(content);
+    let headers = reply.headers_mut();
+    for (name, value) in [
+        ("content-type", mime_type),
+        ("content-disposition", disposition),
+        ("x-content-type-options", "nosniff"),
+        // The content of an id never changes, but only the authorized users may get it
+        ("cache-control", "private, max-age=31536000, immutable"),
+    ] {
+        if let Ok(value) = value.parse() {
+            headers.insert(name, value);
+        }
+    }
+    reply
+}
+
+fn error_reply(err: &Error) -> warp::reply::Response {
+    let status = match err {
+        Error::SessionNotFound => StatusCode::UNAUTHORIZED,
+        Error::NotAMember | Error::UserBanned => StatusCode::FORBIDDEN,
+        Error::AttachmentNotFound | Error::ChannelNotFound => StatusCode::NOT_FOUND,
+        Error::AttachmentTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        Error::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        Error::InvalidAttachment(_) => StatusCode::BAD_REQUEST,
+        err => {
+            error!(%err, "Attachment error");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    if status != StatusCode::INTERNAL_SERVER_ERROR {
+        warn!(%err, "Invalid attachment request");
+    }
+    warp::reply::with_status(err.to_string(), status).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{channel::Visibility, config::Attachments};
+    use std::collections::HashMap;
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    async fn read(blobs: &BlobStore, id: &str) -> Result<Vec<u8>> {
+        let (_, mut file) = blobs.open(id).await?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).await?;
+        Ok(content)
+    }
+
+    #[tokio::test]
+    async fn test_store_resolve_and_collect() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().into(),
+            attachments: Attachments {
+                max_size_bytes: 16,
+                gc_grace_seconds: 0,
+                ..Attachments::default()
+            },
+            ..Config::default()
+        });
+        let registry = RegistryHandle::with_config(config.clone());
+        let blobs = registry.blobs();
+
+        let (kept, created) = blobs.store(b"hello", "text/plain").await.unwrap();
+        assert!(created);
+        assert_eq!(
+            kept.id,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        // Uploading the content again postpones its collection
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(blobs.blob_path(&kept.id))
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+        let (again, created) = blobs.store(b"hello", "TEXT/PLAIN").await.unwrap();
+        assert!(!created);
+        assert_eq!(again, kept);
+        let modified = std::fs::metadata(blobs.blob_path(&kept.id))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert!(modified > an_hour_ago);
+        let (dropped, _) = blobs.store(b"bye", "text/plain").await.unwrap();
+        assert!(matches!(
+            blobs.store(b"<p>", "text/html").await,
+            Err(Error::UnsupportedMediaType(_))
+        ));
+        assert!(matches!(
+            blobs.store(&[0; 17], "text/plain").await,
+            Err(Error::AttachmentTooLarge(16))
+        ));
+
+        let refs = vec![AttachmentRef {
+            id: kept.id.clone(),
+            name: " notes.txt ".into(),
+        }];
+        let attachments = blobs.resolve(refs).await.unwrap();
+        assert_eq!(attachments[0].name, "notes.txt");
+        assert_eq!(attachments[0].size, 5);
+        let missing = vec![AttachmentRef {
+            id: "../../etc/passwd".into(),
+            name: String::new(),
+        }];
+        assert!(matches!(
+            blobs.resolve(missing).await,
+            Err(Error::AttachmentNotFound)
+        ));
+
+        let owner = crate::UserId::from("alice");
+        let channel = registry
+            .create_channel(owner, "files".into(), Visibility::Public)
+            .await
+            .unwrap();
+        let message = channel
+            .add_message_with_attachments(owner, "see attached".into(), attachments)
+            .await
+            .unwrap();
+        assert_eq!(message.attachments[0].id, kept.id);
+
+        assert_eq!(blobs.collect_garbage().await.unwrap(), 1);
+        assert_eq!(read(blobs, &kept.id).await.unwrap(), b"hello");
+        assert!(matches!(
+            read(blobs, &dropped.id).await,
+            Err(Error::AttachmentNotFound)
+        ));
+        // Nothing left behind by the uploads
+        let tmp = std::fs::read_dir(blobs.tmp_dir()).unwrap();
+        assert_eq!(tmp.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_transfers_authorized() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().into(),
+            ..Config::default()
+        });
+        let registry = Arc::new(RegistryHandle::with_config(config.clone()));
+        let server = Arc::new(ServerHandle::with_config(config));
+        let mut sessions = HashMap::new();
+        for user in ["alice", "bob"] {
+            let (sender, _) = mpsc::unbounded_channel();
+            let (subscriptions, _) = mpsc::unbounded_channel();
+            let connection_id = server.connect(sender, subscriptions).await.unwrap();
+            let session_id = server
+                .register_user(connection_id, user.into())
+                .await
+                .unwrap();
+            sessions.insert(user, format!("Bearer {}", session_id));
+        }
+        let server_filter = warp::any().map(move || server.clone());
+        let registry_filter = {
+            let registry = registry.clone();
+            warp::any().map(move || registry.clone())
+        };
+        let upload = warp::path("attachments")
+            .and(warp::post())
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::stream())
+            .and(server_filter.clone())
+            .and(registry_filter.clone())
+            .and_then(handle_upload);
+        let download = warp::path!("attachments" / String)
+            .and(warp::get())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::query::<DownloadQuery>())
+            .and(server_filter)
+            .and(registry_filter)
+            .and_then(handle_download);
+
+        let uploaded = warp::test::request()
+            .method("POST")
+            .path("/attachments")
+            .header("content-type", "text/plain")
+            .body("secret")
+            .reply(&upload)
+            .await;
+        assert_eq!(uploaded.status(), StatusCode::UNAUTHORIZED);
+        let mut ids = Vec::new();
+        // Not telling whether the content was already stored
+        for user in ["alice", "bob"] {
+            let uploaded = warp::test::request()
+                .method("POST")
+                .path("/attachments")
+                .header("content-type", "text/plain")
+                .header("authorization", &sessions[user])
+                .body("secret")
+                .reply(&upload)
+                .await;
+            assert_eq!(uploaded.status(), StatusCode::CREATED);
+            let blob: Blob = serde_json::from_slice(uploaded.body()).unwrap();
+            ids.push(blob.id);
+        }
+        assert_eq!(ids[0], ids[1]);
+        let id = ids.remove(0);
+
+        let alice = crate::UserId::from("alice");
+        let private = registry
+            .create_channel(alice, "private".into(), Visibility::Private)
+            .await
+            .unwrap();
+        let refs = vec![AttachmentRef {
+            id: id.clone(),
+            name: String::new(),
+        }];
+        let attachments = registry.blobs().resolve(refs).await.unwrap();
+        let message = private
+            .add_message_with_attachments(alice, "secret".into(), attachments)
+            .await
+            .unwrap();
+        let public = registry
+            .create_channel(alice, "public".into(), Visibility::Public)
+            .await
+            .unwrap();
+
+        let get = |channel_id: ID, authorization: Option<&str>| {
+            let mut request = warp::test::request()
+                .path(&format!("/attachments/{}?channel_id={}", id, channel_id));
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            request.reply(&download)
+        };
+        let downloaded = get(private.channel_id(), Some(&sessions["alice"])).await;
+        assert_eq!(downloaded.status(), StatusCode::OK);
+        assert_eq!(downloaded.body().as_ref(), b"secret");
+        assert_eq!(
+            get(private.channel_id(), None).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        // Bob uploaded the same content but cannot read the channel
+        assert_eq!(
+            get(private.channel_id(), Some(&sessions["bob"]))
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        // Bob can read the public channel but no message has the file attached
+        assert_eq!(
+            get(public.channel_id(), Some(&sessions["bob"]))
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        // Not attached anymore once the message is deleted
+        private.delete_message(alice, message.seq).await.unwrap();
+        assert_eq!(
+            get(private.channel_id(), Some(&sessions["alice"]))
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
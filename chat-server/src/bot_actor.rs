@@ -0,0 +1,570 @@
+//! Bots: users connected over the websocket protocol that handle the slash commands they registered.
+//! A bot connection authenticates with the token of its bot, receives a `CommandInvoked` for every
+//! invocation of its commands and answers with `RespondToCommand`, the response is only sent to the invoker.
+//! The commands of a bot are only dispatched in the channels the bot is a member of.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, info_span, Instrument};
+
+use crate::{
+    channel::{new_token, secrets_eq},
+    commands::{self, Builtin},
+    config::Config,
+    directory::Directory,
+    errors::{Error, Result},
+    metrics::metrics,
+    new_id,
+    telemetry::Traced,
+    UserId, ID,
+};
+
+/// Time a bot is given to respond to an invocation.
+const INVOCATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A bot registered by `owner`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bot {
+    /// The user the bot connects as.
+    pub name: UserId,
+    pub owner: UserId,
+    /// Names (without the slash) of the commands dispatched to the bot.
+    pub commands: Vec<String>,
+    /// Secret the bot connections authenticate with.
+    pub token: String,
+}
+
+/// An invocation of a bot command waiting for the response of the bot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub id: ID,
+    pub bot: UserId,
+    pub invoker: UserId,
+    pub channel_id: ID,
+    pub command: String,
+    created: Instant,
+}
+
+/// Bot registry implementation as an actor resource
+/// inspired by https://ryhl.io/blog/actors-with-tokio/
+enum BotCommand {
+    Register {
+        owner: UserId,
+        name: UserId,
+        commands: Vec<String>,
+        reply_to: oneshot::Sender<Result<Bot>>,
+    },
+    Delete {
+        owner: UserId,
+        name: UserId,
+        reply_to: oneshot::Sender<Result>,
+    },
+    Authenticate {
+        token: String,
+        reply_to: oneshot::Sender<Result<UserId>>,
+    },
+    Commands {
+        reply_to: oneshot::Sender<Result<Vec<(String, UserId)>>>,
+    },
+    Invoke {
+        invoker: UserId,
+        channel_id: ID,
+        command: String,
+        reply_to: oneshot::Sender<Result<Invocation>>,
+    },
+    Respond {
+        bot: UserId,
+        invocation_id: ID,
+        reply_to: oneshot::Sender<Result<Invocation>>,
+    },
+    MarkUsers {
+        reply_to: oneshot::Sender<Result>,
+    },
+}
+
+impl BotCommand {
+    /// Name of the command in the metrics.
+    fn name(&self) -> &'static str {
+        match self {
+            BotCommand::Register { .. } => "register",
+            BotCommand::Delete { .. } => "delete",
+            BotCommand::Authenticate { .. } => "authenticate",
+            BotCommand::Commands { .. } => "commands",
+            BotCommand::Invoke { .. } => "invoke",
+            BotCommand::Respond { .. } => "respond",
+            BotCommand::MarkUsers { .. } => "mark_users",
+        }
+    }
+}
+
+struct BotActor {
+    receiver: mpsc::Receiver<Traced<BotCommand>>,
+    // Internal state
+    config: Arc<Config>,
+    // Maps bot name -> bot
+    bots: HashMap<UserId, Bot>,
+    // Maps command name -> bot handling it
+    commands: HashMap<String, UserId>,
+    // Maps invocation id -> invocation waiting for its response
+    invocations: HashMap<ID, Invocation>,
+    // Index of the channel members, the bot names must not be taken by users
+    directory: Arc<Directory>,
+}
+
+impl BotActor {
+    fn new(
+        config: Arc<Config>,
+        directory: Arc<Directory>,
+        receiver: mpsc::Receiver<Traced<BotCommand>>,
+    ) -> Self {
+        BotActor {
+            receiver,
+            config,
+            bots: HashMap::new(),
+            commands: HashMap::new(),
+            invocations: HashMap::new(),
+            directory,
+        }
+    }
+
+    async fn on_start(&mut self) -> Result {
+        let bots: Vec<Bot> = match tokio::fs::read(self.config.bots_path()).await {
+            Ok(bytes) => bincode::deserialize(&bytes)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => Err(err)?,
+        };
+        for bot in bots {
+            for command in &bot.commands {
+                self.commands.insert(command.clone(), bot.name);
+            }
+            self.bots.insert(bot.name, bot);
+        }
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, msg: BotCommand) {
+        match msg {
+            BotCommand::Register {
+                owner,
+                name,
+                commands,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.register(owner, name, commands).await);
+            }
+            BotCommand::Delete {
+                owner,
+                name,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.delete(&owner, &name).await);
+            }
+            BotCommand::Authenticate { token, reply_to } => {
+                let bot = self
+                    .bots
+                    .values()
+                    .find(|bot| secrets_eq(&bot.token, &token))
+                    .map(|bot| bot.name)
+                    .ok_or(Error::BotNotFound);
+                let _ = reply_to.send(bot);
+            }
+            BotCommand::Commands { reply_to } => {
+                let mut commands: Vec<_> = self
+                    .commands
+                    .iter()
+                    .map(|(command, bot)| (command.clone(), *bot))
+                    .collect();
+                commands.sort();
+                let _ = reply_to.send(Ok(commands));
+            }
+            BotCommand::Invoke {
+                invoker,
+                channel_id,
+                command,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.invoke(invoker, channel_id, command));
+            }
+            BotCommand::Respond {
+                bot,
+                invocation_id,
+                reply_to,
+            } => {
+                let invocation = match self.invocations.get(&invocation_id) {
+                    Some(invocation)
+                        if invocation.bot == bot
+                            && invocation.created.elapsed() < INVOCATION_TIMEOUT =>
+                    {
+                        Ok(invocation.clone())
+                    }
+                    _ => Err(Error::InvocationNotFound),
+                };
+                let _ = reply_to.send(invocation);
+            }
+            BotCommand::MarkUsers { reply_to } => {
+                let bots = self
+                    .bots
+                    .values()
+                    .flat_map(|bot| vec![&bot.name, &bot.owner]);
+                let invocations = self
+                    .invocations
+                    .values()
+                    .flat_map(|invocation| vec![&invocation.bot, &invocation.invoker]);
+                bots.chain(self.commands.values())
+                    .chain(invocations)
+                    .for_each(UserId::mark);
+                let _ = reply_to.send(Ok(()));
+            }
+        }
+    }
+
+    async fn register(
+        &mut self,
+        owner: UserId,
+        name: UserId,
+        mut commands: Vec<String>,
+    ) -> Result<Bot> {
+        // A user posting in a channel is one of its members
+        if self.bots.contains_key(&name) || self.directory.is_member_anywhere(&name) {
+            return Err(Error::BotNameTaken);
+        }
+        commands.sort();
+        commands.dedup();
+        for command in &commands {
+            if !commands::is_valid_name(command) {
+                return Err(Error::InvalidCommand(format!(
+                    "Invalid command name {:?}",
+                    command
+                )));
+            }
+            if Builtin::from_name(command).is_some() || self.commands.contains_key(command) {
+                return Err(Error::CommandTaken(command.clone()));
+            }
+        }
+        let bot = Bot {
+            name,
+            owner,
+            commands,
+            token: new_token(),
+        };
+        for command in &bot.commands {
+            self.commands.insert(command.clone(), name);
+        }
+        self.bots.insert(name, bot.clone());
+        self.save().await?;
+        info!(bot = %bot.name, owner = %bot.owner, "Bot registered");
+        Ok(bot)
+    }
+
+    /// Deletes the bot `name` on behalf of its owner, the connections of the bot stay open.
+    async fn delete(&mut self, owner: &UserId, name: &UserId) -> Result {
+        match self.bots.get(name) {
+            Some(bot) if bot.owner == *owner => {}
+            Some(_) => return Err(Error::InsufficientRole),
+            None => return Err(Error::BotNotFound),
+        }
+        self.bots.remove(name);
+        self.commands.retain(|_, bot| bot != name);
+        self.invocations
+            .retain(|_, invocation| invocation.bot != *name);
+        self.save().await
+    }
+
+    fn invoke(&mut self, invoker: UserId, channel_id: ID, command: String) -> Result<Invocation> {
+        let now = Instant::now();
+        self.invocations
+            .retain(|_, invocation| now.duration_since(invocation.created) < INVOCATION_TIMEOUT);
+        let bot = self
+            .commands
+            .get(&command)
+            .cloned()
+            .ok_or_else(|| Error::UnknownCommand(command.clone()))?;
+        if !self.directory.is_member(channel_id, &bot) {
+            return Err(Error::BotNotAMember(bot));
+        }
+        let invocation = Invocation {
+            id: new_id(),
+            bot,
+            invoker,
+            channel_id,
+            command,
+            created: now,
+        };
+        self.invocations.insert(invocation.id, invocation.clone());
+        Ok(invocation)
+    }
+
+    /// Saves all the bots.
+    async fn save(&self) -> Result {
+        tokio::fs::create_dir_all(&self.config.data_dir).await?;
+        let bots: Vec<_> = self.bots.values().collect();
+        tokio::fs::write(self.config.bots_path(), bincode::serialize(&bots)?).await?;
+        Ok(())
+    }
+}
+
+async fn run(mut actor: BotActor) {
+    let span = info_span!("bots");
+    if let Err(err) = actor.on_start().instrument(span.clone()).await {
+        error!(parent: &span, %err, "Bot actor initialization error");
+        return; // Note here the actor terminates
+    }
+    while let Some(Traced {
+        command,
+        span,
+        sent,
+    }) = actor.receiver.recv().await
+    {
+        let name = command.name();
+        // Handled within the span of the sender
+        let span = info_span!(parent: &span, "bots");
+        actor.handle_message(command).instrument(span).await;
+        metrics().observe_request("bots", name, sent.elapsed());
+    }
+}
+
+/// Handle of the [`BotActor`]
+/// Provides the public interface of the actor
+#[derive(Clone)]
+pub struct BotHandle {
+    sender: mpsc::Sender<Traced<BotCommand>>,
+}
+
+impl BotHandle {
+    /// Spawns the actor, loading the bots stored on disk.
+    pub fn new(config: Arc<Config>, directory: Arc<Directory>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.mailbox_size);
+        tokio::spawn(run(BotActor::new(config, directory, receiver)));
+        Self { sender }
+    }
+
+    /// Registers the bot `name` handling `commands` on behalf of `owner`.
+    /// The returned bot carries the token its connections authenticate with.
+    pub async fn register(
+        &self,
+        owner: UserId,
+        name: UserId,
+        commands: Vec<String>,
+    ) -> Result<Bot> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = BotCommand::Register {
+            owner,
+            name,
+            commands,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Deletes the bot `name` on behalf of its owner.
+    pub async fn delete(&self, owner: UserId, name: UserId) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = BotCommand::Delete {
+            owner,
+            name,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets the bot authenticated by `token`, fails with [`Error::BotNotFound`] if the token is invalid.
+    pub async fn authenticate(&self, token: String) -> Result<UserId> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = BotCommand::Authenticate { token, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Lists the bot commands along with their bot, ordered by command.
+    pub async fn commands(&self) -> Result<Vec<(String, UserId)>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = BotCommand::Commands { reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Records an invocation of `command` by `invoker` in the channel,
+    /// fails with [`Error::UnknownCommand`] if no bot handles the command
+    /// and with [`Error::BotNotAMember`] if its bot is not a member of the channel.
+    pub async fn invoke(
+        &self,
+        invoker: UserId,
+        channel_id: ID,
+        command: String,
+    ) -> Result<Invocation> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = BotCommand::Invoke {
+            invoker,
+            channel_id,
+            command,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets the invocation the bot responds to, fails with [`Error::InvocationNotFound`]
+    /// unless the invocation is one of `bot` and has not expired.
+    /// A bot may respond several times (e.g. progress updates) until the invocation expires.
+    pub async fn respond(&self, bot: UserId, invocation_id: ID) -> Result<Invocation> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = BotCommand::Respond {
+            bot,
+            invocation_id,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Marks the bots, their owners and invokers (see [`crate::user::collect_unused`]).
+    pub async fn mark_users(&self) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = BotCommand::MarkUsers { reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{users, TestEnv};
+
+    #[tokio::test]
+    async fn test_bot_lifecycle() {
+        let env = TestEnv::new();
+        let directory = Arc::new(Directory::default());
+        let bots = BotHandle::new(env.config.clone(), directory.clone());
+        let (alice, bob, _) = users();
+        let deployer = UserId::from("deployer");
+        let channel_id = new_id();
+        directory.set_members(channel_id, vec![alice, bob]);
+
+        let bot = bots
+            .register(alice, deployer, vec!["deploy".into(), "rollback".into()])
+            .await
+            .unwrap();
+        assert!(matches!(
+            bots.register(bob, deployer, vec![]).await,
+            Err(Error::BotNameTaken)
+        ));
+        assert!(matches!(
+            bots.register(bob, "other".into(), vec!["deploy".into()])
+                .await,
+            Err(Error::CommandTaken(_))
+        ));
+        assert!(matches!(
+            bots.register(bob, "other".into(), vec!["topic".into()])
+                .await,
+            Err(Error::CommandTaken(_))
+        ));
+        assert!(matches!(
+            bots.register(bob, "other".into(), vec!["Bad name".into()])
+                .await,
+            Err(Error::InvalidCommand(_))
+        ));
+        // The name of a user
+        assert!(matches!(
+            bots.register(bob, alice, vec![]).await,
+            Err(Error::BotNameTaken)
+        ));
+
+        // Persisted across restarts
+        let bots = BotHandle::new(env.config.clone(), directory.clone());
+        assert_eq!(
+            bots.authenticate(bot.token.clone()).await.unwrap(),
+            deployer
+        );
+        assert!(matches!(
+            bots.authenticate("invalid".into()).await,
+            Err(Error::BotNotFound)
+        ));
+
+        assert!(matches!(
+            bots.invoke(bob, channel_id, "deploy".into()).await,
+            Err(Error::BotNotAMember(_))
+        ));
+        directory.set_members(channel_id, vec![alice, bob, deployer]);
+        let invocation = bots.invoke(bob, channel_id, "deploy".into()).await.unwrap();
+        assert_eq!(invocation.bot, deployer);
+        assert!(matches!(
+            bots.invoke(bob, channel_id, "unknown".into()).await,
+            Err(Error::UnknownCommand(_))
+        ));
+        let responded = bots.respond(deployer, invocation.id).await.unwrap();
+        assert_eq!(responded.invoker, bob);
+        // Only the bot of the command can respond
+        assert!(matches!(
+            bots.respond(bob, invocation.id).await,
+            Err(Error::InvocationNotFound)
+        ));
+
+        assert!(matches!(
+            bots.delete(bob, deployer).await,
+            Err(Error::InsufficientRole)
+        ));
+        bots.delete(alice, deployer).await.unwrap();
+        assert!(bots.commands().await.unwrap().is_empty());
+        assert!(bots.authenticate(bot.token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deleted_bot_releases_commands() {
+        let env = TestEnv::new();
+        let directory = Arc::new(Directory::default());
+        let bots = BotHandle::new(env.config.clone(), directory.clone());
+        let (alice, bob, _) = users();
+        let (deployer, builder) = (UserId::from("deployer"), UserId::from("builder"));
+        let bot = bots
+            .register(alice, deployer, vec!["deploy".into(), "deploy".into()])
+            .await
+            .unwrap();
+        assert_eq!(bot.commands, vec!["deploy".to_string()]);
+        bots.register(bob, builder, vec!["build".into()])
+            .await
+            .unwrap();
+        assert_eq!(
+            bots.commands().await.unwrap(),
+            vec![("build".into(), builder), ("deploy".into(), deployer)]
+        );
+        let channel_id = new_id();
+        directory.set_members(channel_id, vec![alice, deployer]);
+        let invocation = bots
+            .invoke(alice, channel_id, "deploy".into())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            bots.delete(alice, UserId::from("nobody")).await,
+            Err(Error::BotNotFound)
+        ));
+        bots.delete(alice, deployer).await.unwrap();
+        // The pending invocations of the bot are dropped
+        assert!(matches!(
+            bots.respond(deployer, invocation.id).await,
+            Err(Error::InvocationNotFound)
+        ));
+        assert!(matches!(
+            bots.invoke(alice, channel_id, "deploy".into()).await,
+            Err(Error::UnknownCommand(_))
+        ));
+        // The command can be registered by another bot
+        bots.register(bob, UserId::from("shipper"), vec!["deploy".into()])
+            .await
+            .unwrap();
+        assert_eq!(bots.commands().await.unwrap().len(), 2);
+    }
+}
@@ -1,5 +1,7 @@
+use bincode::Options;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
@@ -7,14 +9,264 @@ use tokio::io::AsyncWriteExt;
 use chrono::{serde::ts_milliseconds, DateTime, Utc};
 
 use crate::errors::{Error, Result};
-use crate::{new_id, UserId, CHANNEL_DATA_FOLDER, CHANNEL_INFO_FOLDER, ID};
+use tracing::warn;
+
+use crate::{
+    attachment::Attachment, channel_info, config::Config, new_id, outgoing_webhook::EventKind,
+    UserId, ID,
+};
+use warp::http::Uri;
+
+/// Namespace used for deriving the deterministic ids of direct channels.
+const DIRECT_CHANNEL_NAMESPACE: uuid::Uuid =
+    uuid::Uuid::from_u128(0x6c1f_9a0e_3b7d_4c52_8e21_d4a3_5f60_b9c7);
+
+/// Computes the id of the direct channel between two users.
+/// The id does not depend on the order of the users.
+pub fn direct_channel_id(first: &UserId, second: &UserId) -> ID {
+    let (a, b) = if first <= second {
+        (first, second)
+    } else {
+        (second, first)
+    };
+    let name = format!("{}\0{}", a, b);
+    uuid::Uuid::new_v5(&DIRECT_CHANNEL_NAMESPACE, name.as_bytes())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChannelKind {
+    /// Regular channel any user can post into.
+    Group,
+    /// Private 1:1 conversation between the two users.
+    Direct(UserId, UserId),
+}
+
+/// Who can see, read and join a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Visibility {
+    /// Anyone can read the channel, posting joins the channel implicitly.
+    Public,
+    /// The channel is known to everybody but only invited users can join it.
+    InviteOnly,
+    /// The channel is hidden from non members (it appears as not found) and only invited users can join it.
+    Private,
+}
+
+/// Role of a channel member, roles are ordered by their privileges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Role {
+    Member,
+    /// Can moderate members.
+    Moderator,
+    /// Can moderate members and moderators and assign roles.
+    Owner,
+}
+
+/// Membership record of a user in a channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Membership {
+    pub role: Role,
+    /// The member cannot post until the given time.
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+impl Membership {
+    pub fn new(role: Role) -> Self {
+        Self {
+            role,
+            muted_until: None,
+        }
+    }
+
+    pub fn is_muted(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.muted_until, Some(until) if until > now)
+    }
+}
+
+/// A channel member as reported to clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelMember {
+    pub user: UserId,
+    pub role: Role,
+}
+
+/// Moderation actions applied by owners and moderators to other users of a channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action")]
+pub enum Moderation {
+    /// Prevents the member from posting for the given duration.
+    Mute {
+        seconds: u32,
+    },
+    Unmute,
+    /// Removes the user from the channel and prevents them from reading or joining it again.
+    Ban,
+    Unban,
+    /// Removes the member from the channel.
+    Kick,
+}
+
+/// Retention of the messages of a channel, the log compaction drops the messages beyond
+/// either limit (0 disables a limit).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retention {
+    #[serde(default)]
+    pub max_age_seconds: u64,
+    #[serde(default)]
+    pub max_messages: u64,
+}
+
+impl Retention {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age_seconds == 0 && self.max_messages == 0
+    }
+
+    /// Counts the leading `messages` (in sequence order) beyond the retention at `now`.
+    /// Only a prefix is dropped so that the remaining messages all follow the compacted ones.
+    pub fn expired(&self, messages: &[Message], now: DateTime<Utc>) -> usize {
+        let mut expired = 0;
+        if self.max_messages > 0 {
+            expired = messages.len().saturating_sub(self.max_messages as usize);
+        }
+        if self.max_age_seconds > 0 {
+            let max_age = chrono::Duration::seconds(self.max_age_seconds as i64);
+            let too_old = messages
+                .iter()
+                .take_while(|m| now - m.created > max_age)
+                .count();
+            expired = expired.max(too_old);
+        }
+        expired
+    }
+}
+
+/// Unread messages of a member in a channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnreadCount {
+    pub channel_id: ID,
+    pub unread: usize,
+    /// Sequence number of the last message read (`None` if none).
+    pub last_read: Option<u64>,
+}
+
+/// Messages of a channel following a sequence number, see [`ChannelHandle::get_messages_after`].
+///
+/// [`ChannelHandle::get_messages_after`]: crate::channel_actor::ChannelHandle::get_messages_after
+#[derive(Debug, Clone)]
+pub struct MessagesAfter {
+    pub messages: Vec<Message>,
+    /// Set if some of the messages following the sequence number are not in `messages`.
+    pub gap: Option<Gap>,
+}
+
+/// Why messages are missing from a replay, the client reloads the channel history instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gap {
+    /// More than [`MAX_HISTORY_SIZE`] messages followed, only the latest ones are replayed.
+    ///
+    /// [`MAX_HISTORY_SIZE`]: crate::MAX_HISTORY_SIZE
+    Truncated,
+    /// The sequence number is ahead of the last message of the channel.
+    UnknownAnchor,
+    /// Messages following the sequence number were dropped by the retention of the channel.
+    Compacted,
+}
+
+/// Summary of a direct channel from the point of view of one of its participants.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DirectChannel {
+    pub channel_id: ID,
+    pub peer: UserId,
+}
+
+/// Incoming webhook of a channel, external systems post into the channel over HTTP
+/// as the bot user `name`, authenticated by a secret token.
+/// The token is only given to the creator of the webhook, the channel keeps its hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IncomingWebhook {
+    pub id: ID,
+    /// The bot user the messages are posted as.
+    pub name: UserId,
+    /// Hex encoded SHA-256 of the token (see [`hash_token`]).
+    pub token_hash: String,
+    pub created_by: UserId,
+}
+
+/// Outgoing webhook of a channel, the channel events of the subscribed kinds are POSTed to `url`
+/// signed with `secret` (see [`crate::outgoing_webhook`]).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutgoingWebhook {
+    pub id: ID,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<EventKind>,
+    pub created_by: UserId,
+}
+
+/// Checks that `url` is an absolute http(s) URL.
+fn validate_webhook_url(url: &str) -> Result {
+    let uri: Uri = url
+        .parse()
+        .map_err(|_| Error::InvalidWebhookUrl(url.into()))?;
+    match (uri.scheme_str(), uri.host()) {
+        (Some("http") | Some("https"), Some(_)) => Ok(()),
+        _ => Err(Error::InvalidWebhookUrl(url.into())),
+    }
+}
+
+/// Maximum length in characters of a channel topic.
+pub const MAX_TOPIC_LENGTH: usize = 250;
+
+/// Generates the secret token of a webhook or a bot.
+pub(crate) fn new_token() -> String {
+    format!("{:032x}{:032x}", new_id().as_u128(), new_id().as_u128())
+}
+
+/// Hashes the token of an incoming webhook, the hash is stored instead of the token.
+pub(crate) fn hash_token(token: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+    digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Compares two secrets in a time independent of their content.
+pub(crate) fn secrets_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Channel {
     pub id: ID,
     pub name: String,
+    /// Topic of the channel (set by `/topic`).
     pub description: String,
-    pub users: HashSet<UserId>,
+    pub kind: ChannelKind,
+    pub visibility: Visibility,
+    pub members: HashMap<UserId, Membership>,
+    /// Users with a pending invitation to join the channel.
+    pub invitations: HashSet<UserId>,
+    pub bans: HashSet<UserId>,
+    /// Minimum interval between two messages of the same member (0 disables slow mode).
+    /// Moderators and owners are not affected.
+    pub slow_mode_seconds: u32,
+    /// Maps member -> sequence number of the last message read by the member.
+    pub read_cursors: HashMap<UserId, u64>,
+    /// Maps webhook id -> incoming webhook.
+    pub incoming_webhooks: HashMap<ID, IncomingWebhook>,
+    /// Maps webhook id -> outgoing webhook.
+    pub outgoing_webhooks: HashMap<ID, OutgoingWebhook>,
+    pub retention: Retention,
+    /// Sequence number of the last message dropped by the log compaction (0 if none),
+    /// the sequence numbers keep increasing from it even if all the messages are dropped.
+    pub compacted_seq: u64,
 }
 
 impl Channel {
@@ -23,56 +275,468 @@ impl Channel {
             id,
             name,
             description: String::new(),
-            users: HashSet::default(),
+            kind: ChannelKind::Group,
+            visibility: Visibility::Public,
+            members: HashMap::default(),
+            invitations: HashSet::default(),
+            bans: HashSet::default(),
+            slow_mode_seconds: 0,
+            read_cursors: HashMap::default(),
+            incoming_webhooks: HashMap::default(),
+            outgoing_webhooks: HashMap::default(),
+            retention: Retention::default(),
+            compacted_seq: 0,
+        }
+    }
+
+    /// Creates the direct channel between `first` and `second`.
+    /// Both users are members of the channel from the start.
+    pub fn new_direct(first: UserId, second: UserId) -> Self {
+        let mut members = HashMap::default();
+        members.insert(first, Membership::new(Role::Member));
+        members.insert(second, Membership::new(Role::Member));
+        Self {
+            id: direct_channel_id(&first, &second),
+            name: format!("{}, {}", first, second),
+            description: String::new(),
+            kind: ChannelKind::Direct(first, second),
+            visibility: Visibility::Private,
+            members,
+            invitations: HashSet::default(),
+            bans: HashSet::default(),
+            slow_mode_seconds: 0,
+            read_cursors: HashMap::default(),
+            incoming_webhooks: HashMap::default(),
+            outgoing_webhooks: HashMap::default(),
+            retention: Retention::default(),
+            compacted_seq: 0,
+        }
+    }
+
+    pub fn is_direct(&self) -> bool {
+        matches!(self.kind, ChannelKind::Direct(..))
+    }
+
+    /// Returns the other participant of a direct channel (if `user` participates in it).
+    pub fn direct_peer(&self, user: &UserId) -> Option<&UserId> {
+        match &self.kind {
+            ChannelKind::Direct(a, b) if a == user => Some(b),
+            ChannelKind::Direct(a, b) if b == user => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn is_member(&self, user: &UserId) -> bool {
+        self.members.contains_key(user)
+    }
+
+    pub fn role(&self, user: &UserId) -> Option<Role> {
+        self.members.get(user).map(|m| m.role)
+    }
+
+    /// Checks that `user` can read the channel (history and members).
+    /// Non members of private channels get [`Error::ChannelNotFound`] so that the channel existence is not leaked.
+    pub fn authorize_read(&self, user: &UserId) -> Result {
+        if self.is_member(user) {
+            return Ok(());
+        }
+        if self.bans.contains(user) {
+            return Err(Error::UserBanned);
+        }
+        match self.visibility {
+            Visibility::Public => Ok(()),
+            Visibility::InviteOnly => Err(Error::NotAMember),
+            Visibility::Private => Err(Error::ChannelNotFound),
+        }
+    }
+
+    /// Adds `user` to the channel members.
+    /// Only public channels can be joined without an invitation, a pending invitation is accepted.
+    pub fn join(&mut self, user: UserId) -> Result {
+        if self.is_member(&user) {
+            return Ok(());
+        }
+        self.authorize_join(&user)?;
+        self.invitations.remove(&user);
+        self.members.insert(user, Membership::new(Role::Member));
+        Ok(())
+    }
+
+    /// Checks that the non member `user` can join the channel.
+    fn authorize_join(&self, user: &UserId) -> Result {
+        if self.bans.contains(user) {
+            return Err(Error::UserBanned);
+        }
+        if self.invitations.contains(user) || self.visibility == Visibility::Public {
+            return Ok(());
+        }
+        match self.visibility {
+            Visibility::Private => Err(Error::ChannelNotFound),
+            _ => Err(Error::InvitationRequired),
+        }
+    }
+
+    /// Checks that `user` can post into the channel at `now`:
+    /// either a member that is not muted or a user that can join the channel.
+    pub fn authorize_post(&self, user: &UserId, now: DateTime<Utc>) -> Result {
+        match self.members.get(user) {
+            Some(Membership {
+                muted_until: Some(until),
+                ..
+            }) if *until > now => Err(Error::UserMuted(*until)),
+            Some(_) => Ok(()),
+            None => {
+                self.authorize_read(user)?;
+                self.authorize_join(user)
+            }
+        }
+    }
+
+    /// Checks that `user` can edit `message`: only its sender can, as a member not muted.
+    /// The messages posted through the incoming webhooks cannot be edited.
+    pub fn authorize_edit(&self, user: &UserId, message: &Message, now: DateTime<Utc>) -> Result {
+        self.authorize_read(user)?;
+        match self.members.get(user) {
+            None => Err(Error::NotAMember),
+            Some(_) if message.webhook.is_some() || message.sender != *user => {
+                Err(Error::InsufficientRole)
+            }
+            Some(Membership {
+                muted_until: Some(until),
+                ..
+            }) if *until > now => Err(Error::UserMuted(*until)),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Checks that `user` can delete `message`: its sender or a moderator can.
+    pub fn authorize_delete(&self, user: &UserId, message: &Message) -> Result {
+        self.authorize_read(user)?;
+        match self.role(user) {
+            None => Err(Error::NotAMember),
+            Some(role) if role >= Role::Moderator => Ok(()),
+            Some(_) if message.webhook.is_none() && message.sender == *user => Ok(()),
+            Some(_) => Err(Error::InsufficientRole),
+        }
+    }
+
+    /// Invites `user` to the channel on behalf of the member `by`.
+    pub fn invite(&mut self, by: &UserId, user: UserId) -> Result {
+        self.authorize_read(by)?;
+        if !self.is_member(by) {
+            return Err(Error::NotAMember);
+        }
+        if self.is_direct() {
+            return Err(Error::DirectChannelMembership);
+        }
+        if self.bans.contains(&user) {
+            return Err(Error::UserBanned);
+        }
+        if !self.is_member(&user) {
+            self.invitations.insert(user);
+        }
+        Ok(())
+    }
+
+    /// Checks that `by` outranks `user` and is at least a moderator.
+    fn authorize_moderation(&self, by: &UserId, user: &UserId) -> Result {
+        self.authorize_read(by)?;
+        let role = self.role(by).ok_or(Error::NotAMember)?;
+        if role < Role::Moderator || by == user {
+            return Err(Error::InsufficientRole);
+        }
+        match self.role(user) {
+            Some(target) if target >= role => Err(Error::InsufficientRole),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies `moderation` to `user` on behalf of `by`.
+    pub fn moderate(
+        &mut self,
+        by: &UserId,
+        user: &UserId,
+        moderation: &Moderation,
+        now: DateTime<Utc>,
+    ) -> Result {
+        self.authorize_moderation(by, user)?;
+        match moderation {
+            Moderation::Mute { seconds } => {
+                let member = self.members.get_mut(user).ok_or(Error::NotAMember)?;
+                member.muted_until = Some(now + chrono::Duration::seconds(*seconds as i64));
+            }
+            Moderation::Unmute => {
+                let member = self.members.get_mut(user).ok_or(Error::NotAMember)?;
+                member.muted_until = None;
+            }
+            Moderation::Ban => {
+                self.members.remove(user);
+                self.read_cursors.remove(user);
+                self.invitations.remove(user);
+                self.bans.insert(*user);
+            }
+            Moderation::Unban => {
+                self.bans.remove(user);
+            }
+            Moderation::Kick => {
+                self.members.remove(user).ok_or(Error::NotAMember)?;
+                self.read_cursors.remove(user);
+            }
+        }
+        Ok(())
+    }
+
+    /// Assigns `role` to the member `user`, only owners can assign roles.
+    pub fn set_role(&mut self, by: &UserId, user: &UserId, role: Role) -> Result {
+        self.authorize_read(by)?;
+        if self.role(by) != Some(Role::Owner) || by == user {
+            return Err(Error::InsufficientRole);
+        }
+        let member = self.members.get_mut(user).ok_or(Error::NotAMember)?;
+        member.role = role;
+        Ok(())
+    }
+
+    /// Checks that `by` is at least a moderator.
+    pub fn authorize_moderator(&self, by: &UserId) -> Result {
+        self.authorize_read(by)?;
+        if self.role(by).is_none_or(|role| role < Role::Moderator) {
+            return Err(Error::InsufficientRole);
+        }
+        Ok(())
+    }
+
+    /// Sets the slow mode interval on behalf of the moderator `by`.
+    pub fn set_slow_mode(&mut self, by: &UserId, seconds: u32) -> Result {
+        self.authorize_moderator(by)?;
+        self.slow_mode_seconds = seconds;
+        Ok(())
+    }
+
+    /// Sets the retention of the messages, only owners can set it.
+    pub fn set_retention(&mut self, by: &UserId, retention: Retention) -> Result {
+        self.authorize_read(by)?;
+        if self.role(by) != Some(Role::Owner) {
+            return Err(Error::InsufficientRole);
+        }
+        self.retention = retention;
+        Ok(())
+    }
+
+    /// Sets the topic on behalf of `by`, a moderator of a group channel or a participant of a direct channel.
+    pub fn set_topic(&mut self, by: &UserId, topic: String) -> Result {
+        if self.is_direct() {
+            if !self.is_member(by) {
+                return Err(Error::NotAMember);
+            }
+        } else {
+            self.authorize_moderator(by)?;
+        }
+        if topic.chars().count() > MAX_TOPIC_LENGTH {
+            return Err(Error::InvalidCommand(format!(
+                "The topic is limited to {} characters",
+                MAX_TOPIC_LENGTH
+            )));
+        }
+        self.description = topic;
+        Ok(())
+    }
+
+    /// Creates an incoming webhook posting as the bot user `name` on behalf of the moderator `by`.
+    /// Returns the webhook with its token, which cannot be retrieved later.
+    pub fn create_incoming_webhook(
+        &mut self,
+        by: &UserId,
+        name: UserId,
+    ) -> Result<(IncomingWebhook, String)> {
+        self.authorize_moderator(by)?;
+        let token = new_token();
+        let webhook = IncomingWebhook {
+            id: new_id(),
+            name,
+            token_hash: hash_token(&token),
+            created_by: *by,
+        };
+        self.incoming_webhooks.insert(webhook.id, webhook.clone());
+        Ok((webhook, token))
+    }
+
+    /// Lists the incoming webhooks (ordered by name) on behalf of the moderator `by`.
+    pub fn list_incoming_webhooks(&self, by: &UserId) -> Result<Vec<IncomingWebhook>> {
+        self.authorize_moderator(by)?;
+        let mut webhooks: Vec<_> = self.incoming_webhooks.values().cloned().collect();
+        webhooks.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        Ok(webhooks)
+    }
+
+    /// Deletes an incoming webhook on behalf of the moderator `by`, its token is rejected from now on.
+    pub fn delete_incoming_webhook(&mut self, by: &UserId, webhook_id: &ID) -> Result {
+        self.authorize_moderator(by)?;
+        self.incoming_webhooks
+            .remove(webhook_id)
+            .ok_or(Error::WebhookNotFound)?;
+        Ok(())
+    }
+
+    /// Finds the incoming webhook authenticated by `token`.
+    pub fn find_incoming_webhook(&self, token: &str) -> Result<&IncomingWebhook> {
+        // All the hashes are compared so that the time does not depend on which one matches
+        let token_hash = hash_token(token);
+        self.incoming_webhooks
+            .values()
+            .fold(None, |found, webhook| {
+                match secrets_eq(&webhook.token_hash, &token_hash) {
+                    true => Some(webhook),
+                    false => found,
+                }
+            })
+            .ok_or(Error::WebhookNotFound)
+    }
+
+    /// Lists the channel members ordered by role (and name).
+    pub fn list_members(&self) -> Vec<ChannelMember> {
+        let mut members: Vec<_> = self
+            .members
+            .iter()
+            .map(|(user, m)| ChannelMember {
+                user: *user,
+                role: m.role,
+            })
+            .collect();
+        members.sort_by(|a, b| b.role.cmp(&a.role).then_with(|| a.user.cmp(&b.user)));
+        members
+    }
+
+    /// Accepts or declines the pending invitation of `user`.
+    pub fn respond_to_invitation(&mut self, user: UserId, accept: bool) -> Result {
+        if !self.invitations.contains(&user) {
+            return Err(Error::InvitationNotFound);
+        }
+        if accept {
+            self.join(user)
+        } else {
+            self.invitations.remove(&user);
+            Ok(())
         }
     }
 
-    pub fn get_data_path(&self) -> String {
-        format!("{}/{:x}", CHANNEL_DATA_FOLDER, self.id.as_u128())
+    /// Marks the users referenced by the channel (see [`crate::user::collect_unused`]).
+    pub fn mark_users(&self) {
+        if let ChannelKind::Direct(first, second) = &self.kind {
+            first.mark();
+            second.mark();
+        }
+        let webhooks = self
+            .incoming_webhooks
+            .values()
+            .flat_map(|webhook| vec![&webhook.name, &webhook.created_by]);
+        self.members
+            .keys()
+            .chain(&self.invitations)
+            .chain(&self.bans)
+            .chain(self.read_cursors.keys())
+            .chain(webhooks)
+            .chain(
+                self.outgoing_webhooks
+                    .values()
+                    .map(|webhook| &webhook.created_by),
+            )
+            .for_each(UserId::mark);
+    }
+
+    /// Gets the file path to be used for storing the channel messages.
+    pub fn get_data_path(&self, config: &Config) -> PathBuf {
+        data_path(config, self.id)
     }
 
     /// Gets the file path to be used for storing the channel info.
-    pub fn get_info_path(&self) -> String {
-        format!("{}{:x}", CHANNEL_INFO_FOLDER, self.id.as_u128())
+    pub fn get_info_path(&self, config: &Config) -> PathBuf {
+        info_path(config, self.id)
     }
 
     /// Loads an existings channel (info) from disk by `channel_id`
-    pub async fn load(channel_id: ID) -> Result<Self> {
-        let filename = format!("{}/{:x}", CHANNEL_INFO_FOLDER, channel_id.as_u128());
-        let path = std::path::Path::new(&filename);
+    pub async fn load(config: &Config, channel_id: ID) -> Result<Self> {
+        let path = info_path(config, channel_id);
         if !path.exists() {
             return Err(Error::ChannelNotFound);
         }
         let mut file = tokio::fs::OpenOptions::new().read(true).open(path).await?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf).await?;
-        Ok(bincode::deserialize(&buf)?)
+        channel_info::decode(&buf)
     }
 
-    /// Saves a channel (info) to disk
-    pub async fn save(&self) -> Result {
-        tokio::fs::create_dir_all(CHANNEL_INFO_FOLDER).await?;
+    /// Saves a channel (info) to disk.
+    /// The info is written to a temporary file first, then renamed over the previous info
+    /// so that an interrupted save leaves the previous info intact.
+    pub async fn save(&self, config: &Config) -> Result {
+        tokio::fs::create_dir_all(config.channel_info_dir()).await?;
+        let path = self.get_info_path(config);
+        let tmp_path = path.with_extension("tmp");
         let mut file = tokio::fs::OpenOptions::new()
             .create(true)
             .write(true)
-            .open(self.get_info_path())
+            .truncate(true)
+            .open(&tmp_path)
             .await?;
-        let bytes = bincode::serialize(self)?;
-        let mut buf: &[u8] = bytes.as_slice();
-        file.write_buf(&mut buf).await?;
-        file.flush().await?;
+        let bytes = channel_info::encode(self)?;
+        file.write_all(&bytes).await?;
+        file.sync_data().await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
         Ok(())
     }
 
-    /// Attempts to load an existings channel by id.
-    /// On [`Error::ChannelNotFound`] failure creates and saves a new channel.
-    pub async fn load_or_create(channel_id: ID) -> Result<Self> {
-        let channel = match Channel::load(channel_id).await {
+    /// Saves the channel info and forces both the info and data files to disk.
+    pub async fn flush(&self, config: &Config) -> Result {
+        self.save(config).await?;
+        tokio::fs::File::open(self.get_info_path(config))
+            .await?
+            .sync_all()
+            .await?;
+        match tokio::fs::File::open(self.get_data_path(config)).await {
+            Ok(file) => file.sync_all().await?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => Err(err)?,
+        }
+        Ok(())
+    }
+
+    /// Loads the info of all the channels stored on disk.
+    /// The info files that cannot be read are logged and skipped.
+    pub async fn load_all(config: &Config) -> Result<Vec<Self>> {
+        let mut channels = Vec::new();
+        let mut entries = match tokio::fs::read_dir(config.channel_info_dir()).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(channels),
+            Err(err) => Err(err)?,
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let channel_id = match entry
+                .file_name()
+                .to_str()
+                .and_then(|name| u128::from_str_radix(name, 16).ok())
+            {
+                Some(id) => uuid::Uuid::from_u128(id),
+                None => continue,
+            };
+            match Channel::load(config, channel_id).await {
+                Ok(channel) => channels.push(channel),
+                Err(err) => {
+                    warn!(%channel_id, %err, "Skipping unreadable channel info");
+                }
+            }
+        }
+        Ok(channels)
+    }
+
+    /// Attempts to load the existing channel with the id of `channel`.
+    /// On [`Error::ChannelNotFound`] failure saves `channel` as a new channel.
+    pub async fn load_or_create(config: &Config, channel: Channel) -> Result<Self> {
+        let channel = match Channel::load(config, channel.id).await {
             Ok(c) => c,
             Err(Error::ChannelNotFound) => {
-                let c = Channel::new(channel_id, format!("Channel #{:x}", channel_id.as_u128()));
-                c.save().await?;
-                c
+                channel.save(config).await?;
+                channel
             }
             Err(err) => Err(err)?,
         };
@@ -82,14 +746,347 @@ impl Channel {
 
     /// Attempts to add a new message to the channel disk file.
     /// The disk file is treated as append only immutable log.
-    pub async fn add_message(&mut self, user: UserId, content: String) -> Result<Message> {
-        let m = Message::new(self.id, user.clone(), content);
-        // TODO remove this for now we append a new user on every channel message
-        self.users.insert(user);
+    /// Posting to a public channel joins it, other channels accept messages only from their (not muted) members.
+    /// `seq` must be greater than the sequence numbers of the existing messages.
+    pub async fn add_message(
+        &mut self,
+        config: &Config,
+        user: UserId,
+        content: String,
+        attachments: Vec<Attachment>,
+        seq: u64,
+    ) -> Result<Message> {
+        self.authorize_post(&user, Utc::now())?;
+        if !self.is_member(&user) {
+            self.join(user)?;
+            self.save(config).await?;
+        }
+        let mut m = Message::new(self.id, user, content, seq);
+        m.attachments = attachments;
+        self.append(config, &m).await?;
+        Ok(m)
+    }
 
-        // TODO implement file access
+    /// Creates an outgoing webhook POSTing the events of the given kinds (all of them if empty) to `url`
+    /// on behalf of the moderator `by`.
+    pub fn create_outgoing_webhook(
+        &mut self,
+        by: &UserId,
+        url: String,
+        mut events: Vec<EventKind>,
+    ) -> Result<OutgoingWebhook> {
+        self.authorize_moderator(by)?;
+        validate_webhook_url(&url)?;
+        if events.is_empty() {
+            events = EventKind::ALL.to_vec();
+        }
+        events.sort();
+        events.dedup();
+        let webhook = OutgoingWebhook {
+            id: new_id(),
+            url,
+            secret: new_token(),
+            events,
+            created_by: *by,
+        };
+        self.outgoing_webhooks.insert(webhook.id, webhook.clone());
+        Ok(webhook)
+    }
+
+    /// Lists the outgoing webhooks (ordered by URL) on behalf of the moderator `by`.
+    pub fn list_outgoing_webhooks(&self, by: &UserId) -> Result<Vec<OutgoingWebhook>> {
+        self.authorize_moderator(by)?;
+        let mut webhooks: Vec<_> = self.outgoing_webhooks.values().cloned().collect();
+        webhooks.sort_by(|a, b| a.url.cmp(&b.url).then_with(|| a.id.cmp(&b.id)));
+        Ok(webhooks)
+    }
+
+    /// Deletes an outgoing webhook on behalf of the moderator `by`.
+    /// Note: the channel actor then cancels the deliveries already queued to the webhook.
+    pub fn delete_outgoing_webhook(&mut self, by: &UserId, webhook_id: &ID) -> Result {
+        self.authorize_moderator(by)?;
+        self.outgoing_webhooks
+            .remove(webhook_id)
+            .ok_or(Error::WebhookNotFound)?;
+        Ok(())
+    }
+
+    /// The outgoing webhooks subscribed to the events of `kind`.
+    pub fn outgoing_webhooks_for(&self, kind: EventKind) -> Vec<OutgoingWebhook> {
+        self.outgoing_webhooks
+            .values()
+            .filter(|webhook| webhook.events.contains(&kind))
+            .cloned()
+            .collect()
+    }
+
+    /// Adds a new message posted through the incoming webhook with `webhook_id` as its bot user.
+    /// The bot user does not become a member of the channel.
+    pub async fn add_webhook_message(
+        &self,
+        config: &Config,
+        webhook_id: &ID,
+        content: String,
+        seq: u64,
+    ) -> Result<Message> {
+        let webhook = self
+            .incoming_webhooks
+            .get(webhook_id)
+            .ok_or(Error::WebhookNotFound)?;
+        let mut m = Message::new(self.id, webhook.name, content, seq);
+        m.webhook = Some(webhook.id);
+        self.append(config, &m).await?;
         Ok(m)
     }
+
+    /// Replaces the content of `message` on behalf of `user`, appending its tombstone.
+    /// Returns the edited message.
+    pub async fn edit_message(
+        &self,
+        config: &Config,
+        user: &UserId,
+        mut message: Message,
+        content: String,
+    ) -> Result<Message> {
+        self.authorize_edit(user, &message, Utc::now())?;
+        let tombstone = Tombstone::Edited {
+            seq: message.seq,
+            content: content.clone(),
+        };
+        self.append_record(config, &encode_tombstone(&tombstone)?)
+            .await?;
+        message.content = content;
+        Ok(message)
+    }
+
+    /// Deletes `message` on behalf of `user`, appending its tombstone.
+    pub async fn delete_message(
+        &self,
+        config: &Config,
+        user: &UserId,
+        message: &Message,
+    ) -> Result {
+        self.authorize_delete(user, message)?;
+        let tombstone = Tombstone::Deleted { seq: message.seq };
+        self.append_record(config, &encode_tombstone(&tombstone)?)
+            .await
+    }
+
+    /// Appends `message` to the channel data file.
+    async fn append(&self, config: &Config, message: &Message) -> Result {
+        self.append_record(config, &encode_record(message)?).await
+    }
+
+    /// Appends an encoded record to the channel data file.
+    async fn append_record(&self, config: &Config, record: &[u8]) -> Result {
+        tokio::fs::create_dir_all(config.channel_data_dir()).await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.get_data_path(config))
+            .await?;
+        file.write_all(record).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Rewrites the data file without the messages beyond the retention at `now`, the tombstones applied.
+    /// The info is saved first so that the sequence numbers never go back, the data file is replaced
+    /// atomically. Returns the remaining messages, `None` if no message is dropped and no tombstone applies.
+    /// Note: the caller must prevent appends in the meantime (see [`crate::channel_actor`]).
+    pub async fn compact(
+        &mut self,
+        config: &Config,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Vec<Message>>> {
+        let Log {
+            mut messages,
+            last_seq,
+            tombstones,
+        } = self.load_log(config).await?;
+        let dropped = if self.retention.is_unlimited() {
+            0
+        } else {
+            self.retention.expired(&messages, now)
+        };
+        if dropped == 0 && tombstones == 0 {
+            return Ok(None);
+        }
+        let kept = messages.split_off(dropped);
+        if dropped > 0 {
+            self.compacted_seq = messages[dropped - 1].seq;
+            self.save(config).await?;
+        }
+
+        let path = self.get_data_path(config);
+        let tmp_path = path.with_extension("compact");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        for message in &kept {
+            file.write_all(&encode_record(message)?).await?;
+        }
+        // The last messages may be deleted, their tombstone keeps their sequence number
+        if last_seq > kept.last().map_or(self.compacted_seq, |m| m.seq) {
+            let tombstone = Tombstone::Deleted { seq: last_seq };
+            file.write_all(&encode_tombstone(&tombstone)?).await?;
+        }
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(Some(kept))
+    }
+
+    /// Reads all the messages of the channel data file in the order they were added.
+    pub async fn load_messages(&self, config: &Config) -> Result<Vec<Message>> {
+        load_messages(config, self.id).await
+    }
+
+    /// Reads the channel data file.
+    pub async fn load_log(&self, config: &Config) -> Result<Log> {
+        load_log(config, self.id).await
+    }
+}
+
+/// Reads all the messages of the data file of the channel with `channel_id`.
+pub async fn load_messages(config: &Config, channel_id: ID) -> Result<Vec<Message>> {
+    Ok(load_log(config, channel_id).await?.messages)
+}
+
+/// Reads the data file of the channel with `channel_id`.
+pub async fn load_log(config: &Config, channel_id: ID) -> Result<Log> {
+    let buf = match tokio::fs::read(data_path(config, channel_id)).await {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Log {
+                messages: Vec::new(),
+                last_seq: 0,
+                tombstones: 0,
+            })
+        }
+        Err(err) => Err(err)?,
+    };
+    decode_records(&buf)
+}
+
+/// Gets the ids of the channels having a data file, whether their info can be read or not.
+pub async fn data_file_ids(config: &Config) -> Result<Vec<ID>> {
+    let mut ids = Vec::new();
+    let mut entries = match tokio::fs::read_dir(config.channel_data_dir()).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(ids),
+        Err(err) => Err(err)?,
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| u128::from_str_radix(name, 16).ok())
+        {
+            ids.push(uuid::Uuid::from_u128(id));
+        }
+    }
+    Ok(ids)
+}
+
+/// Gets the file path of the info of the channel with `channel_id`.
+fn info_path(config: &Config, channel_id: ID) -> PathBuf {
+    config
+        .channel_info_dir()
+        .join(format!("{:x}", channel_id.as_u128()))
+}
+
+/// Gets the file path of the messages of the channel with `channel_id`.
+fn data_path(config: &Config, channel_id: ID) -> PathBuf {
+    config
+        .channel_data_dir()
+        .join(format!("{:x}", channel_id.as_u128()))
+}
+
+/// Encodes a single data file record as a little endian `u32` length followed by the bincode payload.
+fn encode_record(message: &Message) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(message)?;
+    let mut record = Vec::with_capacity(payload.len() + 4);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// Change of an earlier message, appended to the data file.
+/// The tombstones are applied to the messages when the data file is read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Tombstone {
+    Edited { seq: u64, content: String },
+    Deleted { seq: u64 },
+}
+
+/// High bit of the length of a tombstone record, the records of the messages are much shorter.
+const TOMBSTONE_RECORD: u32 = 1 << 31;
+
+/// Encodes a tombstone record, its length is flagged with [`TOMBSTONE_RECORD`].
+fn encode_tombstone(tombstone: &Tombstone) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(tombstone)?;
+    let mut record = Vec::with_capacity(payload.len() + 4);
+    record.extend_from_slice(&(payload.len() as u32 | TOMBSTONE_RECORD).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// Messages of a data file, its tombstones applied.
+pub struct Log {
+    pub messages: Vec<Message>,
+    /// Sequence number of the last message written, deleted or not (0 if none).
+    pub last_seq: u64,
+    /// Number of tombstones applied to a message of the data file, a compaction drops them.
+    pub tombstones: usize,
+}
+
+/// Bincode options of the data file records, a record must be consumed entirely.
+fn record_options() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding()
+}
+
+/// Decodes the records of a data file, applying the tombstones.
+/// A truncated trailing record (interrupted append) is ignored,
+/// any other record that is not exactly a [`Message`] or a [`Tombstone`] fails the decoding.
+fn decode_records(mut buf: &[u8]) -> Result<Log> {
+    let mut messages: Vec<Message> = Vec::new();
+    let mut last_seq = 0;
+    let mut tombstones = 0;
+    while buf.len() >= 4 {
+        let header = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let len = (header & !TOMBSTONE_RECORD) as usize;
+        if buf.len() < 4 + len {
+            break;
+        }
+        let record = &buf[4..4 + len];
+        if header & TOMBSTONE_RECORD != 0 {
+            match record_options().deserialize(record)? {
+                Tombstone::Edited { seq, content } => {
+                    if let Ok(position) = messages.binary_search_by_key(&seq, |m| m.seq) {
+                        messages[position].content = content;
+                        tombstones += 1;
+                    }
+                }
+                // Kept by a compaction for the sequence number only if the message is not in the file
+                Tombstone::Deleted { seq } => {
+                    if let Ok(position) = messages.binary_search_by_key(&seq, |m| m.seq) {
+                        messages.remove(position);
+                        tombstones += 1;
+                    }
+                    last_seq = last_seq.max(seq);
+                }
+            }
+            buf = &buf[4 + len..];
+            continue;
+        }
+        let message: Message = record_options().deserialize(record)?;
+        last_seq = message.seq;
+        messages.push(message);
+        buf = &buf[4 + len..];
+    }
+    Ok(Log {
+        messages,
+        last_seq,
+        tombstones,
+    })
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -100,16 +1097,395 @@ pub struct Message {
     #[serde(with = "ts_milliseconds")]
     pub created: DateTime<Utc>,
     pub content: String,
+    /// Position of the message in the channel, strictly increasing (starting at 1).
+    pub seq: u64,
+    /// Files attached to the message.
+    pub attachments: Vec<Attachment>,
+    /// Incoming webhook the message was posted through, the sender is then the name of the webhook
+    /// and not a user (it may be the name of one).
+    pub webhook: Option<ID>,
 }
 
 impl Message {
-    pub fn new(channel_id: ID, sender: UserId, content: String) -> Self {
+    pub fn new(channel_id: ID, sender: UserId, content: String, seq: u64) -> Self {
         Self {
             id: new_id(),
             channel_id,
             sender,
             created: Utc::now(),
             content,
+            seq,
+            attachments: Vec::new(),
+            webhook: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direct_channel_id_is_symmetric() {
+        let a: UserId = "alice".into();
+        let b: UserId = "bob".into();
+        assert_eq!(direct_channel_id(&a, &b), direct_channel_id(&b, &a));
+        assert_ne!(direct_channel_id(&a, &b), direct_channel_id(&a, &a));
+
+        let c = Channel::new_direct(b, a);
+        assert_eq!(c.id, direct_channel_id(&a, &b));
+        assert_eq!(c.direct_peer(&a), Some(&b));
+        assert_eq!(c.direct_peer(&b), Some(&a));
+        assert_eq!(c.direct_peer(&"carol".into()), None);
+    }
+
+    #[test]
+    fn test_invitations() {
+        let owner: UserId = "alice".into();
+        let guest: UserId = "bob".into();
+        let mut c = Channel::new(new_id(), "secret".into());
+        c.visibility = Visibility::Private;
+        c.members.insert(owner, Membership::new(Role::Owner));
+
+        assert!(matches!(
+            c.authorize_read(&guest),
+            Err(Error::ChannelNotFound)
+        ));
+        assert!(matches!(c.join(guest), Err(Error::ChannelNotFound)));
+        assert!(matches!(
+            c.invite(&guest, owner),
+            Err(Error::ChannelNotFound)
+        ));
+
+        c.invite(&owner, guest).unwrap();
+        c.respond_to_invitation(guest, false).unwrap();
+        assert!(matches!(
+            c.respond_to_invitation(guest, true),
+            Err(Error::InvitationNotFound)
+        ));
+
+        c.invite(&owner, guest).unwrap();
+        c.respond_to_invitation(guest, true).unwrap();
+        assert!(c.authorize_read(&guest).is_ok());
+    }
+
+    #[test]
+    fn test_moderation() {
+        let owner: UserId = "alice".into();
+        let moderator: UserId = "bob".into();
+        let member: UserId = "carol".into();
+        let now = Utc::now();
+        let mut c = Channel::new(new_id(), "ops".into());
+        c.members.insert(owner, Membership::new(Role::Owner));
+        c.join(moderator).unwrap();
+        c.join(member).unwrap();
+
+        assert!(matches!(
+            c.moderate(&moderator, &member, &Moderation::Kick, now),
+            Err(Error::InsufficientRole)
+        ));
+        c.set_role(&owner, &moderator, Role::Moderator).unwrap();
+        assert!(matches!(
+            c.moderate(&moderator, &owner, &Moderation::Ban, now),
+            Err(Error::InsufficientRole)
+        ));
+
+        c.moderate(&moderator, &member, &Moderation::Mute { seconds: 60 }, now)
+            .unwrap();
+        assert!(c.members[&member].is_muted(now));
+        assert!(!c.members[&member].is_muted(now + chrono::Duration::seconds(61)));
+
+        c.moderate(&moderator, &member, &Moderation::Ban, now)
+            .unwrap();
+        assert!(!c.is_member(&member));
+        assert!(matches!(c.join(member), Err(Error::UserBanned)));
+        assert!(matches!(c.authorize_read(&member), Err(Error::UserBanned)));
+
+        c.moderate(&owner, &member, &Moderation::Unban, now)
+            .unwrap();
+        c.join(member).unwrap();
+        assert_eq!(c.list_members()[0].user, owner);
+    }
+
+    #[test]
+    fn test_edit_and_delete_authorized() {
+        let owner: UserId = "alice".into();
+        let sender: UserId = "bob".into();
+        let member: UserId = "carol".into();
+        let outsider: UserId = "eve".into();
+        let now = Utc::now();
+        let mut c = Channel::new(new_id(), "ops".into());
+        c.members.insert(owner, Membership::new(Role::Owner));
+        c.join(sender).unwrap();
+        c.join(member).unwrap();
+        let message = Message::new(c.id, sender, "hello".into(), 1);
+
+        // Only the sender edits, any member is refused and so is the owner
+        c.authorize_edit(&sender, &message, now).unwrap();
+        for user in [&member, &owner] {
+            assert!(matches!(
+                c.authorize_edit(user, &message, now),
+                Err(Error::InsufficientRole)
+            ));
+        }
+        assert!(matches!(
+            c.authorize_edit(&outsider, &message, now),
+            Err(Error::NotAMember)
+        ));
+        c.moderate(&owner, &sender, &Moderation::Mute { seconds: 60 }, now)
+            .unwrap();
+        assert!(matches!(
+            c.authorize_edit(&sender, &message, now),
+            Err(Error::UserMuted(_))
+        ));
+
+        // The sender and the moderators delete, the other members do not
+        c.authorize_delete(&sender, &message).unwrap();
+        c.authorize_delete(&owner, &message).unwrap();
+        assert!(matches!(
+            c.authorize_delete(&member, &message),
+            Err(Error::InsufficientRole)
+        ));
+        assert!(matches!(
+            c.authorize_delete(&outsider, &message),
+            Err(Error::NotAMember)
+        ));
+        c.set_role(&owner, &member, Role::Moderator).unwrap();
+        c.authorize_delete(&member, &message).unwrap();
+
+        // Nobody edits the messages of the incoming webhooks
+        let (webhook, _) = c.create_incoming_webhook(&owner, "ci".into()).unwrap();
+        let mut posted = Message::new(c.id, webhook.name, "built".into(), 2);
+        posted.webhook = Some(webhook.id);
+        assert!(matches!(
+            c.authorize_edit(&webhook.name, &posted, now),
+            Err(Error::NotAMember)
+        ));
+        assert!(matches!(
+            c.authorize_delete(&sender, &posted),
+            Err(Error::InsufficientRole)
+        ));
+    }
+
+    #[test]
+    fn test_incoming_webhooks() {
+        let owner: UserId = "alice".into();
+        let member: UserId = "bob".into();
+        let mut c = Channel::new(new_id(), "ops".into());
+        c.members.insert(owner, Membership::new(Role::Owner));
+        c.join(member).unwrap();
+
+        assert!(matches!(
+            c.create_incoming_webhook(&member, "ci".into()),
+            Err(Error::InsufficientRole)
+        ));
+        let (webhook, token) = c.create_incoming_webhook(&owner, "ci".into()).unwrap();
+        let (other, other_token) = c.create_incoming_webhook(&owner, "alerts".into()).unwrap();
+        assert_ne!(token, other_token);
+        assert_ne!(webhook.token_hash, token);
+        assert_eq!(c.list_incoming_webhooks(&owner).unwrap()[0].id, other.id);
+
+        assert_eq!(c.find_incoming_webhook(&token).unwrap().id, webhook.id);
+        assert!(matches!(
+            c.find_incoming_webhook(&token[1..]),
+            Err(Error::WebhookNotFound)
+        ));
+        assert!(matches!(
+            c.find_incoming_webhook(&webhook.token_hash),
+            Err(Error::WebhookNotFound)
+        ));
+
+        c.delete_incoming_webhook(&owner, &webhook.id).unwrap();
+        assert!(c.find_incoming_webhook(&token).is_err());
+        assert!(matches!(
+            c.delete_incoming_webhook(&owner, &webhook.id),
+            Err(Error::WebhookNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_outgoing_webhooks() {
+        let owner: UserId = "alice".into();
+        let mut c = Channel::new(new_id(), "ops".into());
+        c.members.insert(owner, Membership::new(Role::Owner));
+
+        assert!(matches!(
+            c.create_outgoing_webhook(&owner, "ftp://example.com".into(), vec![]),
+            Err(Error::InvalidWebhookUrl(_))
+        ));
+        assert!(c
+            .create_outgoing_webhook(&owner, "/relative".into(), vec![])
+            .is_err());
+        let all = c
+            .create_outgoing_webhook(&owner, "https://example.com/hook".into(), vec![])
+            .unwrap();
+        assert_eq!(all.events, EventKind::ALL.to_vec());
+        let messages = c
+            .create_outgoing_webhook(
+                &owner,
+                "http://localhost:8080/".into(),
+                vec![EventKind::MessageCreated, EventKind::MessageCreated],
+            )
+            .unwrap();
+        assert_eq!(messages.events, vec![EventKind::MessageCreated]);
+
+        assert_eq!(c.outgoing_webhooks_for(EventKind::MessageCreated).len(), 2);
+        assert_eq!(c.outgoing_webhooks_for(EventKind::MemberJoined), vec![all]);
+        c.delete_outgoing_webhook(&owner, &messages.id).unwrap();
+        assert_eq!(c.list_outgoing_webhooks(&owner).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            data_dir: dir.path().into(),
+            ..Config::default()
+        };
+        let owner = UserId::from("alice");
+        let mut channel = Channel::new(new_id(), "ops".into());
+        channel.members.insert(owner, Membership::new(Role::Owner));
+        for seq in 1..=5 {
+            let content = format!("message {}", seq);
+            channel
+                .add_message(&config, owner, content, Vec::new(), seq)
+                .await
+                .unwrap();
+        }
+        let now = Utc::now();
+        assert!(channel.compact(&config, now).await.unwrap().is_none());
+
+        let retention = Retention {
+            max_age_seconds: 0,
+            max_messages: 2,
+        };
+        assert!(matches!(
+            channel.set_retention(&"bob".into(), retention),
+            Err(Error::InsufficientRole)
+        ));
+        channel.set_retention(&owner, retention).unwrap();
+        let kept = channel.compact(&config, now).await.unwrap().unwrap();
+        let seqs: Vec<_> = kept.iter().map(|m| m.seq).collect();
+        assert_eq!(seqs, vec![4, 5]);
+        assert_eq!(channel.compacted_seq, 3);
+        assert_eq!(channel.load_messages(&config).await.unwrap().len(), 2);
+        assert!(channel.compact(&config, now).await.unwrap().is_none());
+
+        channel.retention.max_age_seconds = 60;
+        let later = now + chrono::Duration::seconds(61);
+        assert!(channel
+            .compact(&config, later)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+        // Sequence numbers keep increasing across restarts
+        let loaded = Channel::load(&config, channel.id).await.unwrap();
+        assert_eq!(loaded.compacted_seq, 5);
+        assert!(loaded.load_messages(&config).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_records_roundtrip() {
+        let channel_id = new_id();
+        let first = Message::new(channel_id, "alice".into(), "hello".into(), 1);
+        let second = Message::new(channel_id, "bob".into(), "world".into(), 2);
+        let mut buf = encode_record(&first).unwrap();
+        buf.extend(encode_record(&second).unwrap());
+        // Interrupted append
+        buf.extend(&encode_record(&first).unwrap()[..6]);
+
+        let messages = decode_records(&buf).unwrap().messages;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].id, first.id);
+        assert_eq!(messages[1].content, "world");
+        assert_eq!(messages[1].seq, 2);
+    }
+
+    #[test]
+    fn test_tombstones_applied() {
+        let channel_id = new_id();
+        let mut buf = Vec::new();
+        for seq in 1..=3 {
+            let message = Message::new(channel_id, "alice".into(), "hello".into(), seq);
+            buf.extend(encode_record(&message).unwrap());
+        }
+        let edited = Tombstone::Edited {
+            seq: 1,
+            content: "hi".into(),
+        };
+        buf.extend(encode_tombstone(&edited).unwrap());
+        buf.extend(encode_tombstone(&Tombstone::Deleted { seq: 3 }).unwrap());
+
+        let log = decode_records(&buf).unwrap();
+        let contents: Vec<_> = log.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["hi", "hello"]);
+        // The sequence numbers are not reused
+        assert_eq!(log.last_seq, 3);
+        assert_eq!(log.tombstones, 2);
+    }
+
+    #[tokio::test]
+    async fn test_compaction_applies_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            data_dir: dir.path().into(),
+            ..Config::default()
+        };
+        let owner = UserId::from("alice");
+        let mut channel = Channel::new(new_id(), "ops".into());
+        channel.members.insert(owner, Membership::new(Role::Owner));
+        let mut messages = Vec::new();
+        for seq in 1..=3 {
+            let content = format!("message {}", seq);
+            messages.push(
+                channel
+                    .add_message(&config, owner, content, Vec::new(), seq)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let first = messages.remove(0);
+        channel
+            .edit_message(&config, &owner, first, "edited".into())
+            .await
+            .unwrap();
+        channel
+            .delete_message(&config, &owner, &messages[1])
+            .await
+            .unwrap();
+
+        // Nothing expires, the retention is unlimited
+        let now = Utc::now();
+        let kept = channel.compact(&config, now).await.unwrap().unwrap();
+        let contents: Vec<_> = kept.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["edited", "message 2"]);
+        assert_eq!(channel.compacted_seq, 0);
+        let log = channel.load_log(&config).await.unwrap();
+        assert_eq!(log.tombstones, 0);
+        assert_eq!(log.last_seq, 3);
+        // Only the tombstone keeping the last sequence number is left
+        assert!(channel.compact(&config, now).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_undecodable_records_fail() {
+        let message = Message::new(new_id(), "alice".into(), "hello".into(), 1);
+        let record = encode_record(&message).unwrap();
+        // Shorter and longer than the encoded message
+        let mut short = record.clone();
+        short.pop();
+        let mut long = record.clone();
+        long.push(0);
+        for mut corrupted in vec![short, long].into_iter() {
+            let len = corrupted.len() as u32 - 4;
+            corrupted[..4].copy_from_slice(&len.to_le_bytes());
+            assert!(matches!(decode_records(&corrupted), Err(Error::Bincode(_))));
+        }
+
+        // An interrupted append is not an error
+        let mut truncated = record.clone();
+        truncated.extend_from_slice(&record[..record.len() - 1]);
+        assert_eq!(decode_records(&truncated).unwrap().messages.len(), 1);
+    }
+}
@@ -1,86 +1,1015 @@
-use tokio::sync::{mpsc, oneshot};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::{channel::Channel, errors::Error, ID, MAX_MAILBOX_SIZE};
+use chrono::Utc;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{debug, error, info, info_span, Instrument, Span};
+
+use crate::{
+    attachment::Attachment,
+    channel::{
+        Channel, ChannelMember, Gap, IncomingWebhook, MessagesAfter, Moderation, OutgoingWebhook,
+        Retention, Role, UnreadCount,
+    },
+    config::Config,
+    directory::Directory,
+    errors::Error,
+    metrics::metrics,
+    outgoing_webhook::{Delivery, EventKind, WebhookEvent, WebhookHandle},
+    rate_limit::TokenBucket,
+    search::{SearchIndex, SearchQuery},
+    telemetry::Traced,
+    websocket::ServerMessage,
+    ID, MAX_HISTORY_SIZE,
+};
 use crate::{channel::Message, errors::Result, UserId};
 
+/// A message published to all the subscribers of a channel, serialized once for all of them.
+#[derive(Debug)]
+pub struct Publication {
+    pub message: ServerMessage,
+    pub text: String,
+    /// Span the message was published from, the deliveries to the connections follow from it.
+    pub span: Span,
+}
+
+/// Receiver of the publications of a channel.
+pub type Subscription = broadcast::Receiver<Arc<Publication>>;
+
 /// Channel implementation as an actor resource
 /// inspired by https://ryhl.io/blog/actors-with-tokio/
 enum ChannelCommand {
     AddMessage {
         user: UserId,
         content: String,
+        attachments: Vec<Attachment>,
+        reply_to: oneshot::Sender<Result<Message>>,
+    },
+    AddWebhookMessage {
+        token: String,
+        content: String,
+        reply_to: oneshot::Sender<Result<Message>>,
+    },
+    EditMessage {
+        user: UserId,
+        seq: u64,
+        content: String,
         reply_to: oneshot::Sender<Result<Message>>,
     },
-    GetChannelUsers(
-        oneshot::Sender<Result<Vec<UserId>>>, // TODO good case for small vec usage
-    ),
+    DeleteMessage {
+        user: UserId,
+        seq: u64,
+        reply_to: oneshot::Sender<Result>,
+    },
+    Subscribe {
+        user: UserId,
+        reply_to: oneshot::Sender<Result<Option<(Subscription, u64)>>>,
+    },
+    GetMembers {
+        user: UserId,
+        reply_to: oneshot::Sender<Result<Vec<ChannelMember>>>,
+    },
+    GetHistory {
+        user: UserId,
+        limit: usize,
+        before: Option<u64>,
+        reply_to: oneshot::Sender<Result<Vec<Message>>>,
+    },
+    GetMessagesAfter {
+        user: UserId,
+        after: u64,
+        reply_to: oneshot::Sender<Result<MessagesAfter>>,
+    },
+    Join {
+        user: UserId,
+        reply_to: oneshot::Sender<Result>,
+    },
+    Invite {
+        by: UserId,
+        user: UserId,
+        reply_to: oneshot::Sender<Result>,
+    },
+    RespondToInvitation {
+        user: UserId,
+        accept: bool,
+        reply_to: oneshot::Sender<Result>,
+    },
+    Moderate {
+        by: UserId,
+        user: UserId,
+        moderation: Moderation,
+        reply_to: oneshot::Sender<Result>,
+    },
+    SetRole {
+        by: UserId,
+        user: UserId,
+        role: Role,
+        reply_to: oneshot::Sender<Result>,
+    },
+    SetSlowMode {
+        by: UserId,
+        seconds: u32,
+        reply_to: oneshot::Sender<Result>,
+    },
+    SetRetention {
+        by: UserId,
+        retention: Retention,
+        reply_to: oneshot::Sender<Result>,
+    },
+    Compact {
+        reply_to: oneshot::Sender<Result<usize>>,
+    },
+    GetTopic {
+        user: UserId,
+        reply_to: oneshot::Sender<Result<String>>,
+    },
+    SetTopic {
+        by: UserId,
+        topic: String,
+        reply_to: oneshot::Sender<Result>,
+    },
+    CreateIncomingWebhook {
+        by: UserId,
+        name: UserId,
+        reply_to: oneshot::Sender<Result<(IncomingWebhook, String)>>,
+    },
+    ListIncomingWebhooks {
+        by: UserId,
+        reply_to: oneshot::Sender<Result<Vec<IncomingWebhook>>>,
+    },
+    DeleteIncomingWebhook {
+        by: UserId,
+        webhook_id: ID,
+        reply_to: oneshot::Sender<Result>,
+    },
+    CreateOutgoingWebhook {
+        by: UserId,
+        url: String,
+        events: Vec<EventKind>,
+        reply_to: oneshot::Sender<Result<OutgoingWebhook>>,
+    },
+    ListOutgoingWebhooks {
+        by: UserId,
+        reply_to: oneshot::Sender<Result<Vec<OutgoingWebhook>>>,
+    },
+    DeleteOutgoingWebhook {
+        by: UserId,
+        webhook_id: ID,
+        reply_to: oneshot::Sender<Result>,
+    },
+    ListDeadLetters {
+        by: UserId,
+        reply_to: oneshot::Sender<Result<Vec<Delivery>>>,
+    },
+    MarkRead {
+        user: UserId,
+        seq: u64,
+        reply_to: oneshot::Sender<Result<bool>>,
+    },
+    GetUnreadCount {
+        user: UserId,
+        reply_to: oneshot::Sender<Result<Option<UnreadCount>>>,
+    },
+    Search {
+        user: UserId,
+        query: SearchQuery,
+        reply_to: oneshot::Sender<Result<Option<Vec<Message>>>>,
+    },
+    HasAttachment {
+        user: UserId,
+        id: String,
+        reply_to: oneshot::Sender<Result<bool>>,
+    },
+    MarkUsers {
+        reply_to: oneshot::Sender<Result>,
+    },
+    Stop {
+        reply_to: oneshot::Sender<Result>,
+    },
+}
+
+impl ChannelCommand {
+    /// Name of the command in the metrics.
+    fn name(&self) -> &'static str {
+        match self {
+            ChannelCommand::AddMessage { .. } => "add_message",
+            ChannelCommand::AddWebhookMessage { .. } => "add_webhook_message",
+            ChannelCommand::EditMessage { .. } => "edit_message",
+            ChannelCommand::DeleteMessage { .. } => "delete_message",
+            ChannelCommand::Subscribe { .. } => "subscribe",
+            ChannelCommand::GetMembers { .. } => "get_members",
+            ChannelCommand::GetHistory { .. } => "get_history",
+            ChannelCommand::GetMessagesAfter { .. } => "get_messages_after",
+            ChannelCommand::Join { .. } => "join",
+            ChannelCommand::Invite { .. } => "invite",
+            ChannelCommand::RespondToInvitation { .. } => "respond_to_invitation",
+            ChannelCommand::Moderate { .. } => "moderate",
+            ChannelCommand::SetRole { .. } => "set_role",
+            ChannelCommand::SetSlowMode { .. } => "set_slow_mode",
+            ChannelCommand::SetRetention { .. } => "set_retention",
+            ChannelCommand::Compact { .. } => "compact",
+            ChannelCommand::GetTopic { .. } => "get_topic",
+            ChannelCommand::SetTopic { .. } => "set_topic",
+            ChannelCommand::CreateIncomingWebhook { .. } => "create_incoming_webhook",
+            ChannelCommand::ListIncomingWebhooks { .. } => "list_incoming_webhooks",
+            ChannelCommand::DeleteIncomingWebhook { .. } => "delete_incoming_webhook",
+            ChannelCommand::CreateOutgoingWebhook { .. } => "create_outgoing_webhook",
+            ChannelCommand::ListOutgoingWebhooks { .. } => "list_outgoing_webhooks",
+            ChannelCommand::DeleteOutgoingWebhook { .. } => "delete_outgoing_webhook",
+            ChannelCommand::ListDeadLetters { .. } => "list_dead_letters",
+            ChannelCommand::MarkRead { .. } => "mark_read",
+            ChannelCommand::GetUnreadCount { .. } => "get_unread_count",
+            ChannelCommand::Search { .. } => "search",
+            ChannelCommand::HasAttachment { .. } => "has_attachment",
+            ChannelCommand::MarkUsers { .. } => "mark_users",
+            ChannelCommand::Stop { .. } => "stop",
+        }
+    }
 }
 
 struct ChannelActor {
-    receiver: mpsc::Receiver<ChannelCommand>,
+    receiver: mpsc::Receiver<Traced<ChannelCommand>>,
     // Internal state
     channel_id: ID,
+    config: Arc<Config>,
+    // Channel to save in case it does not exist yet (if `None` the channel must already exist)
+    template: Option<Channel>,
     channel: Option<Channel>,
+    // Limits the messages posted into the channel by all the users
+    rate_limiter: TokenBucket,
+    // Maps user -> time of the last message (used only in slow mode)
+    last_posts: HashMap<UserId, Instant>,
+    // Maps incoming webhook id -> rate limiter of the messages posted through the webhook
+    webhook_rate_limiters: HashMap<ID, TokenBucket>,
+    // All the channel messages in order, loaded on start (history, unread counts and search hits are served from them)
+    messages: Vec<Message>,
+    // Sequence number of the last message added, deleted or not (0 if none)
+    last_seq: u64,
+    // Full-text index of the sequence numbers of the channel messages, built on start
+    index: SearchIndex,
+    // Ids of the blobs attached to the channel messages
+    attachments: HashSet<String>,
+    // Delivers the publications of the channel to the subscribed connections
+    broadcast: broadcast::Sender<Arc<Publication>>,
+    // Delivers the channel events to the outgoing webhooks
+    webhooks: WebhookHandle,
+    // Index of the members of all the channels, updated on every membership change
+    directory: Arc<Directory>,
+    // Replied once the channel is loaded (or created)
+    started: Option<oneshot::Sender<Result>>,
+    // Replied once the channel is flushed after a stop request
+    stop: Option<oneshot::Sender<Result>>,
 }
 
 impl ChannelActor {
-    fn new(channel_id: ID, receiver: mpsc::Receiver<ChannelCommand>) -> Self {
+    fn new(
+        channel_id: ID,
+        template: Option<Channel>,
+        config: Arc<Config>,
+        receiver: mpsc::Receiver<Traced<ChannelCommand>>,
+        broadcast: broadcast::Sender<Arc<Publication>>,
+        webhooks: WebhookHandle,
+        directory: Arc<Directory>,
+    ) -> Self {
         ChannelActor {
             receiver,
             channel_id,
+            rate_limiter: TokenBucket::new(config.rate_limits.channel),
+            config,
+            template,
             channel: None,
+            last_posts: HashMap::new(),
+            webhook_rate_limiters: HashMap::new(),
+            messages: Vec::new(),
+            last_seq: 0,
+            index: SearchIndex::default(),
+            attachments: HashSet::new(),
+            broadcast,
+            webhooks,
+            directory,
+            started: None,
+            stop: None,
         }
     }
 
     async fn on_start(&mut self) -> Result {
-        let c = Channel::load_or_create(self.channel_id).await?;
+        let c = match self.template.take() {
+            Some(template) => Channel::load_or_create(&self.config, template).await?,
+            None => Channel::load(&self.config, self.channel_id).await?,
+        };
+        let log = c.load_log(&self.config).await?;
+        self.last_seq = log.last_seq.max(c.compacted_seq);
         self.channel.replace(c);
+        self.index_members();
+        self.index_messages(log.messages);
         Ok(())
     }
 
+    /// Updates the members of the channel in the directory.
+    fn index_members(&self) {
+        if let Some(c) = &self.channel {
+            self.directory
+                .set_members(self.channel_id, c.members.keys().cloned());
+        }
+    }
+
+    /// Replaces the indexes of the channel messages.
+    fn index_messages(&mut self, messages: Vec<Message>) {
+        self.index = SearchIndex::default();
+        self.attachments.clear();
+        for m in &messages {
+            self.index.add(m);
+            self.attachments
+                .extend(m.attachments.iter().map(|a| a.id.clone()));
+        }
+        self.messages = messages;
+    }
+
+    fn channel(&mut self) -> Result<&mut Channel> {
+        self.channel.as_mut().ok_or(Error::ChannelNotFound)
+    }
+
     async fn handle_message(&mut self, msg: ChannelCommand) {
         match msg {
             ChannelCommand::AddMessage {
                 user,
                 content,
+                attachments,
                 reply_to,
             } => {
-                if let Some(c) = self.channel.as_mut() {
-                    let res = c.add_message(user, content).await;
-                    let _ = reply_to.send(res);
-                } else {
-                    let _ = reply_to.send(Err(Error::ChannelNotFound));
-                }
+                let _ = reply_to.send(self.add_message(user, content, attachments).await);
+            }
+            ChannelCommand::AddWebhookMessage {
+                token,
+                content,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.add_webhook_message(&token, content).await);
+            }
+            ChannelCommand::EditMessage {
+                user,
+                seq,
+                content,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.edit_message(user, seq, content).await);
+            }
+            ChannelCommand::DeleteMessage {
+                user,
+                seq,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.delete_message(user, seq).await);
+            }
+            ChannelCommand::Subscribe { user, reply_to } => {
+                let _ = reply_to.send(self.subscribe(&user));
+            }
+            ChannelCommand::GetMembers { user, reply_to } => {
+                let _ = reply_to.send(self.get_members(&user));
+            }
+            ChannelCommand::GetHistory {
+                user,
+                limit,
+                before,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.get_history(&user, limit, before).await);
+            }
+            ChannelCommand::GetMessagesAfter {
+                user,
+                after,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.get_messages_after(&user, after).await);
+            }
+            ChannelCommand::Join { user, reply_to } => {
+                let _ = reply_to.send(self.join(user).await);
+            }
+            ChannelCommand::Invite { by, user, reply_to } => {
+                let _ = reply_to.send(self.invite(&by, user).await);
+            }
+            ChannelCommand::RespondToInvitation {
+                user,
+                accept,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.respond_to_invitation(user, accept).await);
+            }
+            ChannelCommand::Moderate {
+                by,
+                user,
+                moderation,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.moderate(&by, &user, &moderation).await);
+            }
+            ChannelCommand::SetRole {
+                by,
+                user,
+                role,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.set_role(&by, &user, role).await);
+            }
+            ChannelCommand::SetSlowMode {
+                by,
+                seconds,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.set_slow_mode(&by, seconds).await);
+            }
+            ChannelCommand::SetRetention {
+                by,
+                retention,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.set_retention(&by, retention).await);
+            }
+            ChannelCommand::Compact { reply_to } => {
+                let _ = reply_to.send(self.compact().await);
+            }
+            ChannelCommand::GetTopic { user, reply_to } => {
+                let topic = self.channel().and_then(|c| {
+                    c.authorize_read(&user)?;
+                    Ok(c.description.clone())
+                });
+                let _ = reply_to.send(topic);
+            }
+            ChannelCommand::HasAttachment { user, id, reply_to } => {
+                let found = self
+                    .channel()
+                    .and_then(|c| c.authorize_read(&user))
+                    .map(|()| self.attachments.contains(&id));
+                let _ = reply_to.send(found);
+            }
+            ChannelCommand::SetTopic {
+                by,
+                topic,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.set_topic(&by, topic).await);
+            }
+            ChannelCommand::CreateIncomingWebhook { by, name, reply_to } => {
+                let _ = reply_to.send(self.create_incoming_webhook(&by, name).await);
+            }
+            ChannelCommand::ListIncomingWebhooks { by, reply_to } => {
+                let _ = reply_to.send(self.channel().and_then(|c| c.list_incoming_webhooks(&by)));
+            }
+            ChannelCommand::DeleteIncomingWebhook {
+                by,
+                webhook_id,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.delete_incoming_webhook(&by, &webhook_id).await);
             }
-            ChannelCommand::GetChannelUsers(reply_to) => {
-                if let Some(c) = self.channel.as_ref() {
-                    // FIXME!!! the `.cloned()` here is realy realy BAD.
-                    // We need to send an bunch of UserIds over a channel but the type currently is String
-                    // That means that each time we cloning the WHOLE string id in order to send it over the Channel which degrades performance with
-                    // uneccessary heap allocations.
-                    // One possible solution is to use Rc<UserId> but this means that the Channel implementation should change.
-                    // A better solution is to enforce the user id type to be Copy
-                    // For now we just ignore these issues as we are struggling to make the code functional :)
-                    let users: Vec<_> = c.users.iter().cloned().collect();
-                    let _ = reply_to.send(Ok(users));
-                } else {
-                    let _ = reply_to.send(Err(Error::ChannelNotFound));
+            ChannelCommand::CreateOutgoingWebhook {
+                by,
+                url,
+                events,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.create_outgoing_webhook(&by, url, events).await);
+            }
+            ChannelCommand::ListOutgoingWebhooks { by, reply_to } => {
+                let _ = reply_to.send(self.channel().and_then(|c| c.list_outgoing_webhooks(&by)));
+            }
+            ChannelCommand::DeleteOutgoingWebhook {
+                by,
+                webhook_id,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.delete_outgoing_webhook(&by, &webhook_id).await);
+            }
+            ChannelCommand::ListDeadLetters { by, reply_to } => {
+                let _ = reply_to.send(self.list_dead_letters(&by).await);
+            }
+            ChannelCommand::MarkRead {
+                user,
+                seq,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.mark_read(user, seq).await);
+            }
+            ChannelCommand::GetUnreadCount { user, reply_to } => {
+                let _ = reply_to.send(self.get_unread_count(&user));
+            }
+            ChannelCommand::Search {
+                user,
+                query,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.search(&user, &query));
+            }
+            ChannelCommand::MarkUsers { reply_to } => {
+                if let Some(c) = &self.channel {
+                    c.mark_users();
                 }
+                self.last_posts.keys().for_each(UserId::mark);
+                self.messages.iter().for_each(|m| m.sender.mark());
+                let _ = reply_to.send(Ok(()));
+            }
+            ChannelCommand::Stop { reply_to } => {
+                // The commands already in the mailbox are still handled, the new ones are refused
+                self.receiver.close();
+                self.stop = Some(reply_to);
+            }
+        }
+    }
+
+    /// Persists the channel state before the actor stops.
+    async fn flush(&self) -> Result {
+        let c = self.channel.as_ref().ok_or(Error::ChannelNotFound)?;
+        c.flush(&self.config).await
+    }
+
+    async fn add_message(
+        &mut self,
+        user: UserId,
+        content: String,
+        attachments: Vec<Attachment>,
+    ) -> Result<Message> {
+        // Authorized first so that the refused messages do not consume the channel rate limit
+        self.channel()?.authorize_post(&user, Utc::now())?;
+        let now = Instant::now();
+        let slow_mode = self.check_slow_mode(&user, now)?;
+        self.rate_limiter
+            .try_acquire(now)
+            .map_err(Error::RateLimited)?;
+
+        let seq = self.next_seq();
+        let c = self.channel.as_mut().ok_or(Error::ChannelNotFound)?;
+        // Posting into a public channel joins it
+        let joined = !c.is_member(&user);
+        let message = c
+            .add_message(&self.config, user, content, attachments, seq)
+            .await?;
+        self.appended(&message);
+        if joined {
+            self.index_members();
+        }
+        if let Some(interval) = slow_mode {
+            self.last_posts
+                .retain(|_, last| now.saturating_duration_since(*last) < interval);
+            self.last_posts.insert(user, now);
+        }
+        if joined {
+            self.emit(WebhookEvent::MemberJoined { user });
+        }
+        self.emit(WebhookEvent::MessageCreated {
+            message: message.clone(),
+        });
+        Ok(message)
+    }
+
+    /// Adds a message posted through the incoming webhook authenticated by `token`.
+    async fn add_webhook_message(&mut self, token: &str, content: String) -> Result<Message> {
+        let now = Instant::now();
+        let webhook_id = self.channel()?.find_incoming_webhook(token)?.id;
+        let webhook_rate_limit = self.config.rate_limits.webhook;
+        self.webhook_rate_limiters
+            .entry(webhook_id)
+            .or_insert_with(|| TokenBucket::new(webhook_rate_limit))
+            .try_acquire(now)
+            .map_err(Error::RateLimited)?;
+        self.rate_limiter
+            .try_acquire(now)
+            .map_err(Error::RateLimited)?;
+
+        let seq = self.next_seq();
+        let c = self.channel.as_ref().ok_or(Error::ChannelNotFound)?;
+        let message = c
+            .add_webhook_message(&self.config, &webhook_id, content, seq)
+            .await?;
+        self.appended(&message);
+        self.emit(WebhookEvent::MessageCreated {
+            message: message.clone(),
+        });
+        Ok(message)
+    }
+
+    /// Replaces the content of the message `seq` on behalf of its sender `user`.
+    async fn edit_message(&mut self, user: UserId, seq: u64, content: String) -> Result<Message> {
+        self.channel()?.authorize_read(&user)?;
+        let position = self.position(seq)?;
+        let c = self.channel.as_ref().ok_or(Error::ChannelNotFound)?;
+        // Authorized first so that the refused edits do not consume the channel rate limit
+        c.authorize_edit(&user, &self.messages[position], Utc::now())?;
+        self.rate_limiter
+            .try_acquire(Instant::now())
+            .map_err(Error::RateLimited)?;
+        let message = c
+            .edit_message(
+                &self.config,
+                &user,
+                self.messages[position].clone(),
+                content,
+            )
+            .await?;
+        self.index.remove(&self.messages[position]);
+        self.index.add(&message);
+        self.messages[position] = message.clone();
+        let _ = publish(
+            &self.broadcast,
+            ServerMessage::MessageEdited(message.clone()),
+        );
+        self.emit(WebhookEvent::MessageEdited {
+            message: message.clone(),
+        });
+        Ok(message)
+    }
+
+    /// Deletes the message `seq` on behalf of its sender or of a moderator `user`.
+    async fn delete_message(&mut self, user: UserId, seq: u64) -> Result {
+        self.channel()?.authorize_read(&user)?;
+        let position = self.position(seq)?;
+        let c = self.channel.as_ref().ok_or(Error::ChannelNotFound)?;
+        // Authorized first so that the refused deletes do not consume the channel rate limit
+        c.authorize_delete(&user, &self.messages[position])?;
+        self.rate_limiter
+            .try_acquire(Instant::now())
+            .map_err(Error::RateLimited)?;
+        c.delete_message(&self.config, &user, &self.messages[position])
+            .await?;
+        let message = self.messages.remove(position);
+        self.index.remove(&message);
+        for attachment in &message.attachments {
+            self.attachments.remove(&attachment.id);
+        }
+        let _ = publish(
+            &self.broadcast,
+            ServerMessage::MessageDeleted {
+                channel_id: self.channel_id,
+                seq,
+                by: user,
+            },
+        );
+        self.emit(WebhookEvent::MessageDeleted {
+            seq,
+            sender: message.sender,
+            by: user,
+        });
+        Ok(())
+    }
+
+    /// Position of the message `seq` in the channel messages.
+    fn position(&self, seq: u64) -> Result<usize> {
+        self.messages
+            .binary_search_by_key(&seq, |m| m.seq)
+            .map_err(|_| Error::MessageNotFound)
+    }
+
+    /// Sequence number of the next message.
+    fn next_seq(&self) -> u64 {
+        self.last_seq + 1
+    }
+
+    /// Indexes and publishes a message just appended to the channel log.
+    fn appended(&mut self, message: &Message) {
+        debug!(seq = message.seq, message_id = %message.id, "Message persisted");
+        metrics().messages_persisted.inc();
+        self.last_seq = message.seq;
+        self.messages.push(message.clone());
+        self.index.add(message);
+        self.attachments
+            .extend(message.attachments.iter().map(|a| a.id.clone()));
+        // Published by the actor so that the subscribers receive the messages in sequence order
+        let _ = publish(&self.broadcast, ServerMessage::ChatMessage(message.clone()));
+    }
+
+    /// Checks the slow mode interval of `user`, returns the interval if slow mode applies to the user.
+    fn check_slow_mode(&mut self, user: &UserId, now: Instant) -> Result<Option<Duration>> {
+        let c = self.channel()?;
+        if c.slow_mode_seconds == 0 || c.role(user).is_some_and(|role| role >= Role::Moderator) {
+            return Ok(None);
+        }
+        let interval = Duration::from_secs(c.slow_mode_seconds as u64);
+        if let Some(last) = self.last_posts.get(user) {
+            let elapsed = now.saturating_duration_since(*last);
+            if elapsed < interval {
+                return Err(Error::RateLimited(interval - elapsed));
             }
         }
+        Ok(Some(interval))
     }
+
+    /// Subscribes a connection of `user` to the channel publications (`None` if not a member).
+    fn subscribe(&mut self, user: &UserId) -> Result<Option<(Subscription, u64)>> {
+        let c = self.channel()?;
+        if !c.is_member(user) {
+            return Ok(None);
+        }
+        Ok(Some((self.broadcast.subscribe(), self.last_seq)))
+    }
+
+    fn get_members(&mut self, user: &UserId) -> Result<Vec<ChannelMember>> {
+        let c = self.channel()?;
+        c.authorize_read(user)?;
+        Ok(c.list_members())
+    }
+
+    async fn get_history(
+        &mut self,
+        user: &UserId,
+        limit: usize,
+        before: Option<u64>,
+    ) -> Result<Vec<Message>> {
+        self.channel()?.authorize_read(user)?;
+        let end = match before {
+            Some(before) => self.messages.partition_point(|m| m.seq < before),
+            None => self.messages.len(),
+        };
+        let start = end.saturating_sub(limit.min(MAX_HISTORY_SIZE));
+        Ok(self.messages[start..end].to_vec())
+    }
+
+    async fn get_messages_after(&mut self, user: &UserId, after: u64) -> Result<MessagesAfter> {
+        let c = self.channel()?;
+        c.authorize_read(user)?;
+        let compacted = after < c.compacted_seq;
+        if after > self.last_seq {
+            return Ok(MessagesAfter {
+                messages: Vec::new(),
+                gap: Some(Gap::UnknownAnchor),
+            });
+        }
+        let messages = &self.messages;
+        let first = messages.partition_point(|m| m.seq <= after);
+        let start = first.max(messages.len().saturating_sub(MAX_HISTORY_SIZE));
+        let gap = if compacted {
+            Some(Gap::Compacted)
+        } else {
+            (start > first).then_some(Gap::Truncated)
+        };
+        Ok(MessagesAfter {
+            messages: messages[start..].to_vec(),
+            gap,
+        })
+    }
+
+    /// Searches the messages of the channel (`None` if `user` is not a member), the latest first.
+    fn search(&mut self, user: &UserId, query: &SearchQuery) -> Result<Option<Vec<Message>>> {
+        if !self.channel()?.is_member(user) {
+            return Ok(None);
+        }
+        Ok(Some(self.index.search(query, &self.messages)))
+    }
+
+    async fn join(&mut self, user: UserId) -> Result {
+        let c = self.channel()?;
+        if !c.is_member(&user) {
+            c.join(user)?;
+            self.save().await?;
+            self.emit(WebhookEvent::MemberJoined { user });
+        }
+        Ok(())
+    }
+
+    async fn invite(&mut self, by: &UserId, user: UserId) -> Result {
+        let c = self.channel()?;
+        c.invite(by, user)?;
+        self.save().await
+    }
+
+    async fn respond_to_invitation(&mut self, user: UserId, accept: bool) -> Result {
+        let c = self.channel()?;
+        c.respond_to_invitation(user, accept)?;
+        self.save().await?;
+        if accept {
+            self.emit(WebhookEvent::MemberJoined { user });
+        }
+        Ok(())
+    }
+
+    async fn moderate(&mut self, by: &UserId, user: &UserId, moderation: &Moderation) -> Result {
+        let c = self.channel()?;
+        let was_member = c.is_member(user);
+        c.moderate(by, user, moderation, chrono::Utc::now())?;
+        self.save().await?;
+        // Published by the actor so that no message posted afterwards reaches a kicked or banned user:
+        // the connections unsubscribe when they receive it
+        let moderated = ServerMessage::Moderated {
+            channel_id: self.channel_id,
+            user: *user,
+            by: *by,
+            moderation: moderation.clone(),
+        };
+        let _ = publish(&self.broadcast, moderated);
+        if was_member && matches!(moderation, Moderation::Kick | Moderation::Ban) {
+            self.emit(WebhookEvent::MemberRemoved {
+                user: *user,
+                by: *by,
+                moderation: moderation.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn set_role(&mut self, by: &UserId, user: &UserId, role: Role) -> Result {
+        let c = self.channel()?;
+        c.set_role(by, user, role)?;
+        self.save().await?;
+        self.emit(WebhookEvent::RoleChanged {
+            user: *user,
+            by: *by,
+            role,
+        });
+        Ok(())
+    }
+
+    async fn set_slow_mode(&mut self, by: &UserId, seconds: u32) -> Result {
+        let c = self.channel()?;
+        c.set_slow_mode(by, seconds)?;
+        self.save().await
+    }
+
+    async fn set_retention(&mut self, by: &UserId, retention: Retention) -> Result {
+        let c = self.channel()?;
+        c.set_retention(by, retention)?;
+        self.save().await
+    }
+
+    /// Drops the messages beyond the channel retention from the log.
+    /// No message is appended meanwhile since the actor handles one command at a time.
+    async fn compact(&mut self) -> Result<usize> {
+        let c = self.channel.as_mut().ok_or(Error::ChannelNotFound)?;
+        let kept = match c.compact(&self.config, Utc::now()).await? {
+            Some(kept) => kept,
+            None => return Ok(0),
+        };
+        let dropped = self.messages.len() - kept.len();
+        info!(
+            dropped,
+            compacted_seq = c.compacted_seq,
+            "Channel log compacted"
+        );
+        metrics().messages_compacted.inc_by(dropped as u64);
+        self.index_messages(kept);
+        Ok(dropped)
+    }
+
+    async fn set_topic(&mut self, by: &UserId, topic: String) -> Result {
+        let c = self.channel()?;
+        c.set_topic(by, topic)?;
+        self.save().await
+    }
+
+    async fn create_incoming_webhook(
+        &mut self,
+        by: &UserId,
+        name: UserId,
+    ) -> Result<(IncomingWebhook, String)> {
+        let c = self.channel()?;
+        let created = c.create_incoming_webhook(by, name)?;
+        self.save().await?;
+        Ok(created)
+    }
+
+    async fn delete_incoming_webhook(&mut self, by: &UserId, webhook_id: &ID) -> Result {
+        let c = self.channel()?;
+        c.delete_incoming_webhook(by, webhook_id)?;
+        self.webhook_rate_limiters.remove(webhook_id);
+        self.save().await
+    }
+
+    async fn create_outgoing_webhook(
+        &mut self,
+        by: &UserId,
+        url: String,
+        events: Vec<EventKind>,
+    ) -> Result<OutgoingWebhook> {
+        let c = self.channel()?;
+        let webhook = c.create_outgoing_webhook(by, url, events)?;
+        self.save().await?;
+        Ok(webhook)
+    }
+
+    async fn delete_outgoing_webhook(&mut self, by: &UserId, webhook_id: &ID) -> Result {
+        let c = self.channel()?;
+        c.delete_outgoing_webhook(by, webhook_id)?;
+        self.save().await?;
+        self.webhooks.cancel(*webhook_id).await
+    }
+
+    async fn list_dead_letters(&mut self, by: &UserId) -> Result<Vec<Delivery>> {
+        let c = self.channel()?;
+        c.authorize_moderator(by)?;
+        self.webhooks.dead_letters(self.channel_id).await
+    }
+
+    /// Queues the deliveries of `event` to the outgoing webhooks subscribed to it, without waiting.
+    /// Failures are only logged, the event itself already happened.
+    fn emit(&self, event: WebhookEvent) {
+        let webhooks = match self.channel.as_ref() {
+            Some(c) => c.outgoing_webhooks_for(event.kind()),
+            None => return,
+        };
+        if webhooks.is_empty() {
+            return;
+        }
+        if let Err(err) = self.webhooks.enqueue(self.channel_id, webhooks, event) {
+            error!(%err, "Queuing the webhook deliveries failed");
+        }
+    }
+
+    /// Saves the channel info.
+    async fn save(&self) -> Result {
+        let c = self.channel.as_ref().ok_or(Error::ChannelNotFound)?;
+        c.save(&self.config).await?;
+        // The membership changes are all saved
+        self.index_members();
+        Ok(())
+    }
+
+    /// Moves the read cursor of `user` to the message `seq`.
+    /// The cursor only moves forward, returns `false` if the message is already read.
+    async fn mark_read(&mut self, user: UserId, seq: u64) -> Result<bool> {
+        let last_seq = self.last_seq;
+        let c = self.channel()?;
+        c.authorize_read(&user)?;
+        if !c.is_member(&user) {
+            return Err(Error::NotAMember);
+        }
+        if seq == 0 || seq > last_seq {
+            return Err(Error::MessageNotFound);
+        }
+        if c.read_cursors
+            .get(&user)
+            .is_some_and(|current| *current >= seq)
+        {
+            return Ok(false);
+        }
+        c.read_cursors.insert(user, seq);
+        self.save().await?;
+        Ok(true)
+    }
+
+    /// Counts the messages of the other members after the read cursor of `user` (`None` if not a member).
+    fn get_unread_count(&mut self, user: &UserId) -> Result<Option<UnreadCount>> {
+        let c = self.channel()?;
+        if !c.is_member(user) {
+            return Ok(None);
+        }
+        let channel_id = c.id;
+        let last_read = c.read_cursors.get(user).copied();
+        let start = self
+            .messages
+            .partition_point(|m| m.seq <= last_read.unwrap_or(0));
+        let unread = self.messages[start..]
+            .iter()
+            .filter(|m| m.sender != *user)
+            .count();
+        Ok(Some(UnreadCount {
+            channel_id,
+            unread,
+            last_read,
+        }))
+    }
+}
+
+/// Serializes `message` and sends it to the current subscribers.
+fn publish(broadcast: &broadcast::Sender<Arc<Publication>>, message: ServerMessage) -> Result {
+    let text = serde_json::to_string(&message)?;
+    // Fails only if there are no subscribers
+    let subscribers = broadcast
+        .send(Arc::new(Publication {
+            message,
+            text,
+            span: Span::current(),
+        }))
+        .unwrap_or(0);
+    debug!(subscribers, "Published");
+    Ok(())
 }
 
 async fn run(mut actor: ChannelActor) {
-    if let Err(err) = actor.on_start().await {
-        eprintln!(
-            "Channel actor {} initialization error {}",
-            &actor.channel_id, &err
-        );
+    let channel_id = actor.channel_id;
+    let span = info_span!("channel", %channel_id);
+    let started = actor.on_start().instrument(span.clone()).await;
+    if let Err(err) = &started {
+        error!(parent: &span, %err, "Channel actor initialization error");
+    }
+    let failed = started.is_err();
+    if let Some(reply_to) = actor.started.take() {
+        let _ = reply_to.send(started);
+    }
+    if failed {
         return; // Note here the actor terminates
     }
-    while let Some(msg) = actor.receiver.recv().await {
-        actor.handle_message(msg).await;
+    while let Some(Traced {
+        command,
+        span,
+        sent,
+    }) = actor.receiver.recv().await
+    {
+        let name = command.name();
+        // Handled within the span of the sender
+        let span = info_span!(parent: &span, "channel", %channel_id);
+        actor.handle_message(command).instrument(span).await;
+        metrics().observe_request("channel", name, sent.elapsed());
+    }
+    if let Some(reply_to) = actor.stop.take() {
+        let _ = reply_to.send(actor.flush().instrument(span).await);
     }
 }
 
@@ -88,34 +1017,698 @@ async fn run(mut actor: ChannelActor) {
 /// Provides the public interface of the actor
 #[derive(Clone)]
 pub struct ChannelHandle {
-    sender: mpsc::Sender<ChannelCommand>,
+    channel_id: ID,
+    sender: mpsc::Sender<Traced<ChannelCommand>>,
+    broadcast: broadcast::Sender<Arc<Publication>>,
 }
 
 impl ChannelHandle {
-    pub fn new(channel_id: ID) -> Self {
-        let (sender, receiver) = mpsc::channel(MAX_MAILBOX_SIZE);
-        let server = ChannelActor::new(channel_id, receiver);
+    /// Spawns the actor of an existing channel.
+    pub fn new(
+        channel_id: ID,
+        config: Arc<Config>,
+        webhooks: WebhookHandle,
+        directory: Arc<Directory>,
+    ) -> Self {
+        Self::spawn(channel_id, None, config, webhooks, directory, None)
+    }
+
+    /// Spawns the actor of `channel`, saving it first if it does not exist yet.
+    /// Returns once the channel is loaded (or created), fails if the actor could not start.
+    pub async fn create(
+        channel: Channel,
+        config: Arc<Config>,
+        webhooks: WebhookHandle,
+        directory: Arc<Directory>,
+    ) -> Result<Self> {
+        let (started, rx) = oneshot::channel();
+        let handle = Self::spawn(
+            channel.id,
+            Some(channel),
+            config,
+            webhooks,
+            directory,
+            Some(started),
+        );
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        Ok(handle)
+    }
+
+    fn spawn(
+        channel_id: ID,
+        template: Option<Channel>,
+        config: Arc<Config>,
+        webhooks: WebhookHandle,
+        directory: Arc<Directory>,
+        started: Option<oneshot::Sender<Result>>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(config.mailbox_size);
+        let (broadcast, _) = broadcast::channel(config.broadcast_capacity);
+        let mut server = ChannelActor::new(
+            channel_id,
+            template,
+            config,
+            receiver,
+            broadcast.clone(),
+            webhooks,
+            directory,
+        );
+        server.started = started;
         tokio::spawn(run(server));
-        Self { sender }
+        Self {
+            channel_id,
+            sender,
+            broadcast,
+        }
+    }
+
+    pub fn channel_id(&self) -> ID {
+        self.channel_id
+    }
+
+    /// Free slots of the actor mailbox.
+    pub(crate) fn mailbox_capacity(&self) -> usize {
+        self.sender.capacity()
     }
 
     pub async fn add_message(&self, user: UserId, content: String) -> Result<Message> {
+        self.add_message_with_attachments(user, content, Vec::new())
+            .await
+    }
+
+    /// Adds a message referencing `attachments`, resolved beforehand (see [`BlobStore::resolve`]).
+    ///
+    /// [`BlobStore::resolve`]: crate::attachment::BlobStore::resolve
+    pub async fn add_message_with_attachments(
+        &self,
+        user: UserId,
+        content: String,
+        attachments: Vec<Attachment>,
+    ) -> Result<Message> {
         let (reply_to, rx) = oneshot::channel();
         let msg = ChannelCommand::AddMessage {
             user,
             content,
+            attachments,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Adds a message posted through the incoming webhook authenticated by `token`,
+    /// the message is published like the ones of the channel members.
+    pub async fn add_webhook_message(&self, token: String, content: String) -> Result<Message> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::AddWebhookMessage {
+            token,
+            content,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Replaces the content of the message `seq` on behalf of its sender `user`, returns the edited message.
+    pub async fn edit_message(&self, user: UserId, seq: u64, content: String) -> Result<Message> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::EditMessage {
+            user,
+            seq,
+            content,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Deletes the message `seq` on behalf of its sender or of a moderator `user`.
+    pub async fn delete_message(&self, user: UserId, seq: u64) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::DeleteMessage {
+            user,
+            seq,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Sends `message` to all the connections subscribed to the channel.
+    /// Note: chat messages are published by the channel itself when added.
+    pub fn publish(&self, message: ServerMessage) -> Result {
+        publish(&self.broadcast, message)
+    }
+
+    /// Subscribes a connection of `user` to the channel publications.
+    /// Returns the subscription along with the sequence number of the last message published before it,
+    /// `None` if the user is not a member of the channel.
+    pub async fn subscribe(&self, user: UserId) -> Result<Option<(Subscription, u64)>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::Subscribe { user, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets the channel members if `user` is allowed to read the channel.
+    pub async fn get_members(&self, user: UserId) -> Result<Vec<ChannelMember>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::GetMembers { user, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets the last `limit` (at most [`MAX_HISTORY_SIZE`]) messages before the sequence number `before`
+    /// (or the latest ones) if `user` is allowed to read the channel.
+    pub async fn get_history(
+        &self,
+        user: UserId,
+        limit: usize,
+        before: Option<u64>,
+    ) -> Result<Vec<Message>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::GetHistory {
+            user,
+            limit,
+            before,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets the messages with a sequence number greater than `after` (at most the last [`MAX_HISTORY_SIZE`])
+    /// if `user` is allowed to read the channel, along with the reason of the messages missing (if any).
+    pub async fn get_messages_after(&self, user: UserId, after: u64) -> Result<MessagesAfter> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::GetMessagesAfter {
+            user,
+            after,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    pub async fn join(&self, user: UserId) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::Join { user, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    pub async fn invite(&self, by: UserId, user: UserId) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::Invite { by, user, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    pub async fn respond_to_invitation(&self, user: UserId, accept: bool) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::RespondToInvitation {
+            user,
+            accept,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Applies `moderation` to `user` on behalf of the moderator `by`.
+    pub async fn moderate(&self, by: UserId, user: UserId, moderation: Moderation) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::Moderate {
+            by,
+            user,
+            moderation,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Assigns `role` to `user` on behalf of the owner `by`.
+    pub async fn set_role(&self, by: UserId, user: UserId, role: Role) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::SetRole {
+            by,
+            user,
+            role,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Sets the retention of the messages on behalf of the owner `by`.
+    pub async fn set_retention(&self, by: UserId, retention: Retention) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::SetRetention {
+            by,
+            retention,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Drops the messages beyond the channel retention from the log, returns the number of messages dropped.
+    pub async fn compact(&self) -> Result<usize> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::Compact { reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Sets the slow mode interval (0 disables it) on behalf of the moderator `by`.
+    pub async fn set_slow_mode(&self, by: UserId, seconds: u32) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::SetSlowMode {
+            by,
+            seconds,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets the channel topic if `user` is allowed to read the channel.
+    pub async fn get_topic(&self, user: UserId) -> Result<String> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::GetTopic { user, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Sets the channel topic on behalf of `by` (see [`Channel::set_topic`]).
+    pub async fn set_topic(&self, by: UserId, topic: String) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::SetTopic {
+            by,
+            topic,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Creates an incoming webhook posting as the bot user `name` on behalf of the moderator `by`.
+    /// Returns the webhook with its token.
+    pub async fn create_incoming_webhook(
+        &self,
+        by: UserId,
+        name: UserId,
+    ) -> Result<(IncomingWebhook, String)> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::CreateIncomingWebhook { by, name, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Lists the incoming webhooks of the channel on behalf of the moderator `by`.
+    pub async fn list_incoming_webhooks(&self, by: UserId) -> Result<Vec<IncomingWebhook>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::ListIncomingWebhooks { by, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Deletes an incoming webhook on behalf of the moderator `by`.
+    pub async fn delete_incoming_webhook(&self, by: UserId, webhook_id: ID) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::DeleteIncomingWebhook {
+            by,
+            webhook_id,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Creates an outgoing webhook POSTing the channel events of the given kinds (all of them if empty)
+    /// to `url` on behalf of the moderator `by`.
+    pub async fn create_outgoing_webhook(
+        &self,
+        by: UserId,
+        url: String,
+        events: Vec<EventKind>,
+    ) -> Result<OutgoingWebhook> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::CreateOutgoingWebhook {
+            by,
+            url,
+            events,
             reply_to,
         };
 
-        let _ = self.sender.send(msg).await;
+        let _ = self.sender.send(Traced::new(msg)).await;
         rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
     }
 
-    pub async fn get_channel_users(&self) -> Result<Vec<UserId>> {
+    /// Lists the outgoing webhooks of the channel on behalf of the moderator `by`.
+    pub async fn list_outgoing_webhooks(&self, by: UserId) -> Result<Vec<OutgoingWebhook>> {
         let (reply_to, rx) = oneshot::channel();
-        let msg = ChannelCommand::GetChannelUsers(reply_to);
+        let msg = ChannelCommand::ListOutgoingWebhooks { by, reply_to };
 
-        let _ = self.sender.send(msg).await;
+        let _ = self.sender.send(Traced::new(msg)).await;
         rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
     }
+
+    /// Deletes an outgoing webhook on behalf of the moderator `by`.
+    pub async fn delete_outgoing_webhook(&self, by: UserId, webhook_id: ID) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::DeleteOutgoingWebhook {
+            by,
+            webhook_id,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Lists the webhook deliveries of the channel that exhausted their attempts
+    /// on behalf of the moderator `by`.
+    pub async fn list_dead_letters(&self, by: UserId) -> Result<Vec<Delivery>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::ListDeadLetters { by, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Marks the messages up to the sequence number `seq` as read by `user`.
+    /// Returns `false` if the message was already read.
+    pub async fn mark_read(&self, user: UserId, seq: u64) -> Result<bool> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::MarkRead {
+            user,
+            seq,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Marks the users referenced by the channel (see [`crate::user::collect_unused`]).
+    pub async fn mark_users(&self) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::MarkUsers { reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Stops the actor once the commands sent before are handled, flushing the channel to disk.
+    pub async fn stop(&self) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::Stop { reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        // An actor that already terminated has nothing left to flush
+        rx.await.unwrap_or(Ok(()))
+    }
+
+    /// Gets the unread messages of `user`, `None` if the user is not a member of the channel.
+    pub async fn get_unread_count(&self, user: UserId) -> Result<Option<UnreadCount>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::GetUnreadCount { user, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Searches the messages of the channel matching `query` (its channel aside), the latest first.
+    /// `None` if `user` is not a member of the channel.
+    pub async fn search(&self, user: UserId, query: SearchQuery) -> Result<Option<Vec<Message>>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::Search {
+            user,
+            query,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Checks whether a message of the channel has the blob `id` attached,
+    /// fails if `user` is not allowed to read the channel.
+    pub async fn has_attachment(&self, user: UserId, id: String) -> Result<bool> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ChannelCommand::HasAttachment { user, id, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+}
+
+#[cfg(test)]
+impl ChannelHandle {
+    /// Handle of a channel without a running actor, only its publications can be used.
+    pub(crate) fn detached(channel_id: ID, config: &Config) -> (Self, Subscription) {
+        let (sender, _) = mpsc::channel(1);
+        let (broadcast, subscription) = broadcast::channel(config.broadcast_capacity);
+        let handle = Self {
+            channel_id,
+            sender,
+            broadcast,
+        };
+        (handle, subscription)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channel::Visibility,
+        rate_limit::RateLimit,
+        test_util::{users, TestEnv},
+    };
+
+    #[tokio::test]
+    async fn test_refused_messages_keep_rate_limit() {
+        let env = TestEnv::with(|config| config.rate_limits.channel = RateLimit::new(2, 0.001));
+        let registry = env.registry();
+        let (alice, bob, eve) = users();
+        let ops = registry
+            .create_channel(alice, "ops".into(), Visibility::Private)
+            .await
+            .unwrap();
+        ops.invite(alice, bob).await.unwrap();
+        ops.respond_to_invitation(bob, true).await.unwrap();
+        ops.moderate(alice, bob, Moderation::Mute { seconds: 60 })
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            assert!(matches!(
+                ops.add_message(eve, "spam".into()).await,
+                Err(Error::ChannelNotFound)
+            ));
+            assert!(matches!(
+                ops.add_message(bob, "spam".into()).await,
+                Err(Error::UserMuted(_))
+            ));
+        }
+
+        ops.add_message(alice, "first".into()).await.unwrap();
+        ops.add_message(alice, "second".into()).await.unwrap();
+        assert!(matches!(
+            ops.add_message(alice, "third".into()).await,
+            Err(Error::RateLimited(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_refused_edits_keep_rate_limit() {
+        let env = TestEnv::with(|config| config.rate_limits.channel = RateLimit::new(2, 0.001));
+        let registry = env.registry();
+        let (alice, bob, _) = users();
+        let ops = registry
+            .create_channel(alice, "ops".into(), Visibility::Public)
+            .await
+            .unwrap();
+        ops.join(bob).await.unwrap();
+        ops.add_message(alice, "first".into()).await.unwrap();
+        for _ in 0..5 {
+            assert!(matches!(
+                ops.edit_message(bob, 1, "hacked".into()).await,
+                Err(Error::InsufficientRole)
+            ));
+            assert!(matches!(
+                ops.delete_message(bob, 1).await,
+                Err(Error::InsufficientRole)
+            ));
+        }
+
+        ops.edit_message(alice, 1, "edited".into()).await.unwrap();
+        assert!(matches!(
+            ops.delete_message(alice, 1).await,
+            Err(Error::RateLimited(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_messages_after_reports_gaps() {
+        let env = TestEnv::with(|config| config.rate_limits.channel = RateLimit::new(1000, 1000.0));
+        let registry = env.registry();
+        let (alice, _, _) = users();
+        let ops = registry
+            .create_channel(alice, "ops".into(), Visibility::Public)
+            .await
+            .unwrap();
+        let total = MAX_HISTORY_SIZE as u64 + 5;
+        for i in 0..total {
+            ops.add_message(alice, format!("message {}", i))
+                .await
+                .unwrap();
+        }
+
+        let replayed = ops.get_messages_after(alice, 5).await.unwrap();
+        assert_eq!(replayed.messages.len(), MAX_HISTORY_SIZE);
+        assert_eq!(replayed.gap, None);
+        let replayed = ops.get_messages_after(alice, 2).await.unwrap();
+        assert_eq!(replayed.gap, Some(Gap::Truncated));
+        assert_eq!(replayed.messages[0].seq, 6);
+        let replayed = ops.get_messages_after(alice, total).await.unwrap();
+        assert!(replayed.messages.is_empty());
+        assert_eq!(replayed.gap, None);
+        let replayed = ops.get_messages_after(alice, total + 1).await.unwrap();
+        assert_eq!(replayed.gap, Some(Gap::UnknownAnchor));
+    }
+
+    #[tokio::test]
+    async fn test_appends_queued_during_compaction_kept() {
+        let env = TestEnv::new();
+        let registry = env.registry();
+        let (alice, _, _) = users();
+        let ops = registry
+            .create_channel(alice, "ops".into(), Visibility::Public)
+            .await
+            .unwrap();
+        for i in 1..=5 {
+            ops.add_message(alice, format!("message {}", i))
+                .await
+                .unwrap();
+        }
+        let retention = Retention {
+            max_age_seconds: 0,
+            max_messages: 2,
+        };
+        ops.set_retention(alice, retention).await.unwrap();
+
+        // The appends are queued in the mailbox behind the compaction
+        let (dropped, sixth, seventh) = tokio::join!(
+            ops.compact(),
+            ops.add_message(alice, "message 6".into()),
+            ops.add_message(alice, "message 7".into()),
+        );
+        assert_eq!(dropped.unwrap(), 3);
+        assert_eq!((sixth.unwrap().seq, seventh.unwrap().seq), (6, 7));
+        let seqs = |messages: Vec<Message>| messages.iter().map(|m| m.seq).collect::<Vec<_>>();
+        let history = ops.get_history(alice, 10, None).await.unwrap();
+        assert_eq!(seqs(history), vec![4, 5, 6, 7]);
+
+        // Some of the messages following 2 are gone
+        let replayed = ops.get_messages_after(alice, 2).await.unwrap();
+        assert_eq!(replayed.gap, Some(Gap::Compacted));
+        assert_eq!(seqs(replayed.messages), vec![4, 5, 6, 7]);
+        let replayed = ops.get_messages_after(alice, 3).await.unwrap();
+        assert_eq!(replayed.gap, None);
+
+        ops.stop().await.unwrap();
+        let registry = env.registry();
+        let ops = registry.get_channel(ops.channel_id()).await.unwrap();
+        let history = ops.get_history(alice, 10, None).await.unwrap();
+        assert_eq!(seqs(history), vec![4, 5, 6, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_edited_and_deleted_messages() {
+        let env = TestEnv::new();
+        let registry = env.registry();
+        let (alice, bob, _) = users();
+        let ops = registry
+            .create_channel(alice, "ops".into(), Visibility::Public)
+            .await
+            .unwrap();
+        ops.add_message(bob, "helo".into()).await.unwrap();
+        ops.add_message(bob, "oops".into()).await.unwrap();
+        let (mut subscription, _) = ops.subscribe(alice).await.unwrap().unwrap();
+
+        assert!(matches!(
+            ops.edit_message(alice, 1, "hacked".into()).await,
+            Err(Error::InsufficientRole)
+        ));
+        let edited = ops.edit_message(bob, 1, "hello".into()).await.unwrap();
+        assert_eq!(edited.content, "hello");
+        assert!(matches!(
+            &subscription.recv().await.unwrap().message,
+            ServerMessage::MessageEdited(m) if m.seq == 1 && m.content == "hello"
+        ));
+        // The index follows the edits and deletes
+        let query = |text: &str| -> SearchQuery {
+            serde_json::from_value(serde_json::json!({ "text": text })).unwrap()
+        };
+        let found = ops.search(bob, query("hello")).await.unwrap().unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(ops
+            .search(bob, query("helo"))
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+        // Moderators delete any message
+        ops.delete_message(alice, 2).await.unwrap();
+        assert!(matches!(
+            &subscription.recv().await.unwrap().message,
+            ServerMessage::MessageDeleted { seq: 2, .. }
+        ));
+        assert!(matches!(
+            ops.delete_message(bob, 2).await,
+            Err(Error::MessageNotFound)
+        ));
+        assert!(ops
+            .search(bob, query("oops"))
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+        let history = ops.get_history(bob, 10, None).await.unwrap();
+        assert_eq!(history.len(), 1);
+
+        // The sequence number of the deleted message is not reused after a restart
+        ops.stop().await.unwrap();
+        let registry = env.registry();
+        let ops = registry.get_channel(ops.channel_id()).await.unwrap();
+        let history = ops.get_history(bob, 10, None).await.unwrap();
+        let contents: Vec<_> = history.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["hello"]);
+        let message = ops.add_message(bob, "again".into()).await.unwrap();
+        assert_eq!(message.seq, 3);
+    }
 }
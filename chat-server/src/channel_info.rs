@@ -0,0 +1,169 @@
+//! On-disk format of the channel info files.
+//!
+//! An info file starts with [`MAGIC`] and the format version (little endian `u16`), followed by the
+//! bincode encoded [`Channel`]. Files written before the header existed hold a bare baseline
+//! channel ([`Baseline`]), they are migrated when loaded and rewritten on the next save.
+//!
+//! Changing the layout of [`Channel`] (or of a type it contains) bumps [`FORMAT_VERSION`],
+//! keeps the previous layout in this module and migrates it in [`decode`].
+use std::collections::HashSet;
+
+use bincode::Options;
+use serde::Deserialize;
+
+use crate::{
+    channel::{Channel, Membership, Role},
+    errors::{Error, Result},
+    UserId, ID,
+};
+
+/// First bytes of a versioned info file.
+/// Unversioned files start with the length of the channel id (16 as a little endian `u64`).
+pub const MAGIC: &[u8; 4] = b"CHNL";
+
+/// Version of the info files written.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Bincode options of the info files, a payload must be consumed entirely.
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding()
+}
+
+/// Encodes `channel` with the header of the current format version.
+pub fn encode(channel: &Channel) -> Result<Vec<u8>> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    options().serialize_into(&mut buf, channel)?;
+    Ok(buf)
+}
+
+/// Decodes an info file, migrating the baseline layout.
+pub fn decode(buf: &[u8]) -> Result<Channel> {
+    let rest = match buf.strip_prefix(MAGIC) {
+        Some(rest) => rest,
+        None => {
+            return match options().deserialize::<Baseline>(buf) {
+                Ok(channel) => Ok(channel.into()),
+                Err(_) => Err(Error::InvalidChannelInfo("unknown layout".into())),
+            }
+        }
+    };
+    if rest.len() < 2 {
+        return Err(Error::InvalidChannelInfo("truncated header".into()));
+    }
+    let (version, payload) = rest.split_at(2);
+    match u16::from_le_bytes([version[0], version[1]]) {
+        FORMAT_VERSION => Ok(options().deserialize::<Channel>(payload)?),
+        version => Err(Error::InvalidChannelInfo(format!(
+            "unsupported format version {}",
+            version
+        ))),
+    }
+}
+
+/// Layout written before the format versions existed, the channels were public groups
+/// and their users become plain members.
+#[derive(Deserialize)]
+struct Baseline {
+    id: ID,
+    name: String,
+    description: String,
+    users: HashSet<UserId>,
+}
+
+impl From<Baseline> for Channel {
+    fn from(c: Baseline) -> Self {
+        let mut channel = Channel::new(c.id, c.name);
+        channel.description = c.description;
+        channel.members = c
+            .users
+            .into_iter()
+            .map(|user| (user, Membership::new(Role::Member)))
+            .collect();
+        channel
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channel::{ChannelKind, Visibility},
+        config::Config,
+        new_id,
+    };
+
+    #[test]
+    fn test_versioned_roundtrip() {
+        let mut channel = Channel::new(new_id(), "ops".into());
+        channel
+            .members
+            .insert("alice".into(), Membership::new(Role::Owner));
+        channel.read_cursors.insert("alice".into(), 3);
+        channel.compacted_seq = 7;
+        let buf = encode(&channel).unwrap();
+        assert!(buf.starts_with(MAGIC));
+        let decoded = decode(&buf).unwrap();
+        assert_eq!(decoded.id, channel.id);
+        assert_eq!(decoded.role(&"alice".into()), Some(Role::Owner));
+        assert_eq!(decoded.read_cursors, channel.read_cursors);
+        assert_eq!(decoded.compacted_seq, 7);
+
+        let mut unsupported = buf.clone();
+        unsupported[4] = 99;
+        assert!(matches!(
+            decode(&unsupported),
+            Err(Error::InvalidChannelInfo(_))
+        ));
+        assert!(matches!(
+            decode(&buf[..5]),
+            Err(Error::InvalidChannelInfo(_))
+        ));
+        assert!(matches!(
+            decode(b"garbage"),
+            Err(Error::InvalidChannelInfo(_))
+        ));
+    }
+
+    #[test]
+    fn test_baseline_layout_migrated() {
+        let id = new_id();
+        let users: HashSet<UserId> = vec!["alice".into(), "bob".into()].into_iter().collect();
+
+        // The bincode encoding of a tuple is the one of the struct with the same fields
+        let baseline = bincode::serialize(&(id, "ops", "topic", &users)).unwrap();
+        let channel = decode(&baseline).unwrap();
+        assert_eq!(channel.id, id);
+        assert_eq!(channel.name, "ops");
+        assert_eq!(channel.description, "topic");
+        assert_eq!(channel.kind, ChannelKind::Group);
+        assert_eq!(channel.visibility, Visibility::Public);
+        assert_eq!(channel.role(&"alice".into()), Some(Role::Member));
+        assert_eq!(channel.role(&"bob".into()), Some(Role::Member));
+        assert!(channel.read_cursors.is_empty());
+        assert!(channel.retention.is_unlimited());
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_info_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            data_dir: dir.path().into(),
+            ..Config::default()
+        };
+        let channel = Channel::new(new_id(), "ops".into());
+        channel.save(&config).await.unwrap();
+        let corrupted = Channel::new(new_id(), "corrupted".into());
+        tokio::fs::write(corrupted.get_info_path(&config), b"CHNL\x01\x00garbage")
+            .await
+            .unwrap();
+
+        let channels = Channel::load_all(&config).await.unwrap();
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].id, channel.id);
+        assert!(matches!(
+            Channel::load(&config, corrupted.id).await,
+            Err(Error::Bincode(_))
+        ));
+    }
+}
@@ -0,0 +1,135 @@
+//! Slash commands typed into the content of a `SendMessage`.
+//! A message starting with `/` is not posted, its command is run instead: either a [`Builtin`]
+//! or a command registered by a bot (see [`crate::bot_actor`]). A leading `//` posts the message
+//! with a single slash.
+
+/// Content of a `SendMessage`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    /// A regular message, posted as is.
+    Message(String),
+    /// A slash command, `name` is lowercase and `args` trimmed.
+    Command { name: String, args: String },
+}
+
+/// Parses the content of a message.
+pub fn parse(content: String) -> Input {
+    let command = match content.strip_prefix('/') {
+        Some(command) => command,
+        None => return Input::Message(content),
+    };
+    if command.starts_with('/') {
+        return Input::Message(command.to_string());
+    }
+    let (name, args) = match command.find(char::is_whitespace) {
+        Some(end) => (&command[..end], command[end..].trim()),
+        None => (command, ""),
+    };
+    // e.g. a lone slash
+    if name.is_empty() {
+        return Input::Message(content);
+    }
+    Input::Command {
+        name: name.to_lowercase(),
+        args: args.to_string(),
+    }
+}
+
+/// Checks that `name` can name a command: 1 to 32 lowercase ASCII letters, digits, `-` or `_`.
+pub fn is_valid_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+/// Commands handled by the server itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    /// Shows the channel topic, or sets it if followed by the new topic.
+    Topic,
+    /// Posts an action of the user, e.g. `/me waves` posts `* alice waves`.
+    Me,
+    /// Invites a user to the channel.
+    Invite,
+    /// Lists the available commands.
+    Help,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 4] = [Builtin::Topic, Builtin::Me, Builtin::Invite, Builtin::Help];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|builtin| builtin.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::Topic => "topic",
+            Builtin::Me => "me",
+            Builtin::Invite => "invite",
+            Builtin::Help => "help",
+        }
+    }
+
+    /// One line description of the command and its arguments.
+    pub fn usage(&self) -> &'static str {
+        match self {
+            Builtin::Topic => "/topic [new topic] - shows or sets the channel topic",
+            Builtin::Me => "/me <action> - posts an action",
+            Builtin::Invite => "/invite <user> - invites a user to the channel",
+            Builtin::Help => "/help - lists the available commands",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(name: &str, args: &str) -> Input {
+        Input::Command {
+            name: name.into(),
+            args: args.into(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("hello".into()), Input::Message("hello".into()));
+        assert_eq!(parse("/me  waves ".into()), command("me", "waves"));
+        assert_eq!(parse("/Topic".into()), command("topic", ""));
+        assert_eq!(
+            parse("/deploy\tapi now".into()),
+            command("deploy", "api now")
+        );
+        assert_eq!(parse("//me".into()), Input::Message("/me".into()));
+        assert_eq!(parse("/ hi".into()), Input::Message("/ hi".into()));
+        assert_eq!(parse("/".into()), Input::Message("/".into()));
+
+        assert!(is_valid_name("deploy-v2_x"));
+        assert!(!is_valid_name("Deploy"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(&"x".repeat(33)));
+        assert_eq!(Builtin::from_name("invite"), Some(Builtin::Invite));
+        assert_eq!(Builtin::from_name("deploy"), None);
+    }
+
+    #[test]
+    fn test_builtins() {
+        for builtin in Builtin::ALL.iter() {
+            assert_eq!(Builtin::from_name(builtin.name()), Some(*builtin));
+            assert!(is_valid_name(builtin.name()));
+            assert!(builtin.usage().starts_with(&format!("/{}", builtin.name())));
+        }
+        // Only the names are lowercased
+        assert_eq!(
+            parse("/ME Waves\nTwice".into()),
+            command("me", "Waves\nTwice")
+        );
+        assert_eq!(Builtin::from_name("Help"), None);
+    }
+}
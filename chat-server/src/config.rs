@@ -0,0 +1,536 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+
+use crate::errors::{Error, Result};
+use crate::rate_limit::{RateLimit, RateLimits};
+
+/// Runtime configuration of the server.
+/// Loaded in layers, each one overriding the previous:
+/// defaults, TOML config file, environment variables and command-line flags (see [`Args`]).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the server listens on.
+    pub bind: SocketAddr,
+    /// Folder in which all the persisted data are stored.
+    pub data_dir: PathBuf,
+    /// Capacity of the actor mailboxes.
+    pub mailbox_size: usize,
+    /// Number of publications a lagging channel subscriber can fall behind before missing some of them.
+    pub broadcast_capacity: usize,
+    /// Number of server actor shards (defaults to the available parallelism).
+    pub shards: usize,
+    pub rate_limits: RateLimits,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    /// Seconds the connections are given to close on shutdown, the channels are flushed afterwards anyway.
+    pub shutdown_timeout_seconds: u64,
+    /// Seconds the server keeps serving on shutdown after it reports not ready,
+    /// so that the load balancers stop routing new clients to it before the listener stops.
+    pub shutdown_drain_seconds: u64,
+    /// Interval between two compactions of the channel logs with a retention.
+    pub compaction_interval_seconds: u64,
+    /// Serves over TLS if set, plaintext otherwise.
+    pub tls: Option<TlsConfig>,
+    pub webhook_delivery: WebhookDelivery,
+    pub attachments: Attachments,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: ([0, 0, 0, 0], 9090).into(),
+            data_dir: "data".into(),
+            mailbox_size: 1024,
+            broadcast_capacity: 1024,
+            shards: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            rate_limits: RateLimits::default(),
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+            shutdown_timeout_seconds: 10,
+            shutdown_drain_seconds: 5,
+            compaction_interval_seconds: 60 * 60,
+            tls: None,
+            webhook_delivery: WebhookDelivery::default(),
+            attachments: Attachments::default(),
+        }
+    }
+}
+
+/// Limits and garbage collection of the attachments.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Attachments {
+    /// Maximum size of an uploaded file.
+    pub max_size_bytes: u64,
+    /// MIME types accepted on upload, `type/*` accepts all the subtypes of `type`.
+    pub mime_types: Vec<String>,
+    /// Interval between two collections of the unreferenced blobs.
+    pub gc_interval_seconds: u64,
+    /// Age below which an unreferenced blob is kept (e.g. uploaded but not posted yet).
+    pub gc_grace_seconds: u64,
+    /// Size in pixels of the box the image thumbnails fit in.
+    pub thumbnail_size: u32,
+    /// Number of workers generating the thumbnails.
+    pub thumbnail_workers: usize,
+}
+
+impl Default for Attachments {
+    fn default() -> Self {
+        Self {
+            max_size_bytes: 10 * 1024 * 1024,
+            mime_types: vec![
+                "image/*".into(),
+                "text/plain".into(),
+                "application/pdf".into(),
+                "application/zip".into(),
+            ],
+            gc_interval_seconds: 60 * 60,
+            gc_grace_seconds: 60 * 60,
+            thumbnail_size: 320,
+            thumbnail_workers: 2,
+        }
+    }
+}
+
+impl Attachments {
+    /// Checks that files of `mime_type` can be uploaded.
+    pub fn accepts(&self, mime_type: &str) -> bool {
+        let mime_type = mime_type.to_ascii_lowercase();
+        self.mime_types
+            .iter()
+            .any(|accepted| match accepted.strip_suffix("/*") {
+                Some(prefix) => mime_type
+                    .strip_prefix(prefix)
+                    .is_some_and(|subtype| subtype.starts_with('/') && subtype.len() > 1),
+                None => *accepted == mime_type,
+            })
+    }
+}
+
+/// Delivery of the outgoing webhooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookDelivery {
+    /// Attempts of a delivery before it is moved to the dead letters.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every further retry.
+    pub initial_backoff_ms: u64,
+    /// Maximum delay between two attempts.
+    pub max_backoff_seconds: u64,
+    /// Time an endpoint is given to answer.
+    pub timeout_seconds: u64,
+    /// Whether the endpoints may be reached on private, loopback and link-local addresses
+    /// (e.g. for endpoints on the same network).
+    pub allow_private_addresses: bool,
+}
+
+impl Default for WebhookDelivery {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            initial_backoff_ms: 1000,
+            max_backoff_seconds: 10 * 60,
+            timeout_seconds: 10,
+            allow_private_addresses: false,
+        }
+    }
+}
+
+/// TLS settings of the listener.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file of the certificate chain, reloaded on SIGHUP.
+    pub cert_path: PathBuf,
+    /// PEM file of the private key, reloaded on SIGHUP.
+    pub key_path: PathBuf,
+    /// What happens to plaintext HTTP clients.
+    #[serde(default)]
+    pub plaintext: Plaintext,
+    /// Address of the plaintext listener redirecting to TLS (only used by [`Plaintext::Redirect`]).
+    #[serde(default = "default_redirect_bind")]
+    pub redirect_bind: SocketAddr,
+}
+
+fn default_redirect_bind() -> SocketAddr {
+    ([0, 0, 0, 0], 8080).into()
+}
+
+/// Handling of plaintext HTTP when TLS is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Plaintext {
+    /// No plaintext listener, plaintext requests to the TLS listener fail the handshake
+    #[default]
+    Refuse,
+    /// A plaintext listener answers every request with a permanent redirect to https
+    Redirect,
+}
+
+/// Verbosity of the server logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// Output format of the server logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line (including the spans of the event)
+    Json,
+}
+
+/// Command-line flags of the server, each one can also be set by its `CHAT_*` environment variable.
+#[derive(Debug, Default, Parser)]
+#[command(about = "Chat server")]
+pub struct Args {
+    /// Path of the TOML config file
+    #[arg(long, env = "CHAT_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long, env = "CHAT_BIND")]
+    pub bind: Option<SocketAddr>,
+    /// Folder of the persisted data
+    #[arg(long, env = "CHAT_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Capacity of the actor mailboxes
+    #[arg(long, env = "CHAT_MAILBOX_SIZE")]
+    pub mailbox_size: Option<usize>,
+    /// Capacity of the channel publication buffers
+    #[arg(long, env = "CHAT_BROADCAST_CAPACITY")]
+    pub broadcast_capacity: Option<usize>,
+    /// Number of server actor shards
+    #[arg(long, env = "CHAT_SHARDS")]
+    pub shards: Option<usize>,
+    /// Rate limit of a connection as `burst/per_second`
+    #[arg(long, env = "CHAT_CONNECTION_RATE_LIMIT")]
+    pub connection_rate_limit: Option<RateLimit>,
+    /// Rate limit of the messages posted by a user as `burst/per_second`
+    #[arg(long, env = "CHAT_USER_RATE_LIMIT")]
+    pub user_rate_limit: Option<RateLimit>,
+    /// Rate limit of the messages posted into a channel as `burst/per_second`
+    #[arg(long, env = "CHAT_CHANNEL_RATE_LIMIT")]
+    pub channel_rate_limit: Option<RateLimit>,
+    /// Rate limit of the messages posted through an incoming webhook as `burst/per_second`
+    #[arg(long, env = "CHAT_WEBHOOK_RATE_LIMIT")]
+    pub webhook_rate_limit: Option<RateLimit>,
+    /// Verbosity of the logs
+    #[arg(long, env = "CHAT_LOG_LEVEL", value_enum)]
+    pub log_level: Option<LogLevel>,
+    /// Format of the logs
+    #[arg(long, env = "CHAT_LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// Seconds the connections are given to close on shutdown
+    #[arg(long, env = "CHAT_SHUTDOWN_TIMEOUT_SECONDS")]
+    pub shutdown_timeout_seconds: Option<u64>,
+    /// Seconds the server keeps serving on shutdown after it reports not ready
+    #[arg(long, env = "CHAT_SHUTDOWN_DRAIN_SECONDS")]
+    pub shutdown_drain_seconds: Option<u64>,
+    /// Attempts of an outgoing webhook delivery before it is moved to the dead letters
+    #[arg(long, env = "CHAT_WEBHOOK_MAX_ATTEMPTS")]
+    pub webhook_max_attempts: Option<u32>,
+    /// Delay in milliseconds before the first retry of an outgoing webhook delivery
+    #[arg(long, env = "CHAT_WEBHOOK_INITIAL_BACKOFF_MS")]
+    pub webhook_initial_backoff_ms: Option<u64>,
+    /// Maximum size in bytes of an uploaded attachment
+    #[arg(long, env = "CHAT_MAX_ATTACHMENT_SIZE")]
+    pub max_attachment_size: Option<u64>,
+    /// PEM file of the TLS certificate chain (enables TLS together with `--tls-key`)
+    #[arg(long, env = "CHAT_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file of the TLS private key
+    #[arg(long, env = "CHAT_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Handling of plaintext HTTP when TLS is enabled
+    #[arg(long, env = "CHAT_TLS_PLAINTEXT", value_enum)]
+    pub tls_plaintext: Option<Plaintext>,
+    /// Address of the plaintext listener redirecting to TLS
+    #[arg(long, env = "CHAT_TLS_REDIRECT_BIND")]
+    pub tls_redirect_bind: Option<SocketAddr>,
+}
+
+impl Config {
+    /// Loads the configuration from the process command-line flags and environment
+    /// (and the config file they point to).
+    pub fn load() -> Result<Self> {
+        Self::from_args(Args::parse())
+    }
+
+    /// Loads the config file of `args` (if any) and applies the overrides of `args` on top of it.
+    pub fn from_args(args: Args) -> Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        if let Some(bind) = args.bind {
+            config.bind = bind;
+        }
+        if let Some(data_dir) = args.data_dir {
+            config.data_dir = data_dir;
+        }
+        if let Some(mailbox_size) = args.mailbox_size {
+            config.mailbox_size = mailbox_size;
+        }
+        if let Some(broadcast_capacity) = args.broadcast_capacity {
+            config.broadcast_capacity = broadcast_capacity;
+        }
+        if let Some(shards) = args.shards {
+            config.shards = shards;
+        }
+        if let Some(limit) = args.connection_rate_limit {
+            config.rate_limits.connection = limit;
+        }
+        if let Some(limit) = args.user_rate_limit {
+            config.rate_limits.user = limit;
+        }
+        if let Some(limit) = args.channel_rate_limit {
+            config.rate_limits.channel = limit;
+        }
+        if let Some(limit) = args.webhook_rate_limit {
+            config.rate_limits.webhook = limit;
+        }
+        if let Some(log_level) = args.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(log_format) = args.log_format {
+            config.log_format = log_format;
+        }
+        if let Some(seconds) = args.shutdown_timeout_seconds {
+            config.shutdown_timeout_seconds = seconds;
+        }
+        if let Some(seconds) = args.shutdown_drain_seconds {
+            config.shutdown_drain_seconds = seconds;
+        }
+        if let Some(attempts) = args.webhook_max_attempts {
+            config.webhook_delivery.max_attempts = attempts;
+        }
+        if let Some(backoff) = args.webhook_initial_backoff_ms {
+            config.webhook_delivery.initial_backoff_ms = backoff;
+        }
+        if let Some(size) = args.max_attachment_size {
+            config.attachments.max_size_bytes = size;
+        }
+        if let (Some(cert_path), Some(key_path)) = (args.tls_cert, args.tls_key) {
+            match &mut config.tls {
+                Some(tls) => {
+                    tls.cert_path = cert_path;
+                    tls.key_path = key_path;
+                }
+                None => {
+                    config.tls = Some(TlsConfig {
+                        cert_path,
+                        key_path,
+                        plaintext: Plaintext::default(),
+                        redirect_bind: default_redirect_bind(),
+                    })
+                }
+            }
+        }
+        if let Some(tls) = &mut config.tls {
+            if let Some(plaintext) = args.tls_plaintext {
+                tls.plaintext = plaintext;
+            }
+            if let Some(bind) = args.tls_redirect_bind {
+                tls.redirect_bind = bind;
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses a TOML config file, missing fields get their default values.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    fn validate(&self) -> Result {
+        if self.mailbox_size == 0 || self.broadcast_capacity == 0 || self.shards == 0 {
+            return Err(Error::Generic(
+                "mailbox_size, broadcast_capacity and shards must be positive".into(),
+            ));
+        }
+        if self.webhook_delivery.max_attempts == 0 {
+            return Err(Error::Generic(
+                "webhook_delivery.max_attempts must be positive".into(),
+            ));
+        }
+        if self.compaction_interval_seconds == 0 {
+            return Err(Error::Generic(
+                "compaction_interval_seconds must be positive".into(),
+            ));
+        }
+        if self.attachments.gc_interval_seconds == 0 {
+            return Err(Error::Generic(
+                "attachments.gc_interval_seconds must be positive".into(),
+            ));
+        }
+        if self.attachments.thumbnail_size == 0 || self.attachments.thumbnail_workers == 0 {
+            return Err(Error::Generic(
+                "attachments.thumbnail_size and attachments.thumbnail_workers must be positive"
+                    .into(),
+            ));
+        }
+        if let Some(tls) = &self.tls {
+            if tls.plaintext == Plaintext::Redirect && tls.redirect_bind == self.bind {
+                return Err(Error::Generic(
+                    "The plaintext redirect cannot listen on the TLS address".into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Folder in which Channel information files (`${ID}`) are stored.
+    pub fn channel_info_dir(&self) -> PathBuf {
+        self.data_dir.join("channels").join("info")
+    }
+
+    /// Folder in which Channel data files (messages) are stored.
+    pub fn channel_data_dir(&self) -> PathBuf {
+        self.data_dir.join("channels").join("data")
+    }
+
+    /// Folder in which the attachment blobs are stored, named by the SHA-256 of their content.
+    pub fn blob_dir(&self) -> PathBuf {
+        self.data_dir.join("blobs")
+    }
+
+    /// File in which the registered bots are stored.
+    pub fn bots_path(&self) -> PathBuf {
+        self.data_dir.join("bots")
+    }
+
+    /// Folder in which the outgoing webhook deliveries waiting for a (next) attempt are stored.
+    pub fn webhook_pending_dir(&self) -> PathBuf {
+        self.data_dir.join("webhooks").join("pending")
+    }
+
+    /// Folder in which the outgoing webhook deliveries that exhausted their attempts are stored (by channel).
+    pub fn webhook_dead_dir(&self) -> PathBuf {
+        self.data_dir.join("webhooks").join("dead")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.toml");
+        std::fs::write(
+            &path,
+            r#"
+            bind = "127.0.0.1:8080"
+            data_dir = "/var/lib/chat"
+            log_level = "debug"
+            log_format = "json"
+
+            [rate_limits.user]
+            burst = 3
+            per_second = 0.5
+            "#,
+        )
+        .unwrap();
+
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(config.bind, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(
+            config.channel_data_dir(),
+            Path::new("/var/lib/chat/channels/data")
+        );
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.rate_limits.user, RateLimit::new(3, 0.5));
+        assert_eq!(config.rate_limits.channel, RateLimits::default().channel);
+        assert_eq!(config.mailbox_size, Config::default().mailbox_size);
+
+        let args = Args::try_parse_from([
+            "chat-server",
+            "--config",
+            path.to_str().unwrap(),
+            "--bind",
+            "127.0.0.1:9000",
+            "--user-rate-limit",
+            "5/2.5",
+            "--shards",
+            "3",
+        ])
+        .unwrap();
+        let config = Config::from_args(args).unwrap();
+        assert_eq!(config.bind, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.data_dir, Path::new("/var/lib/chat"));
+        assert_eq!(config.rate_limits.user, RateLimit::new(5, 2.5));
+        assert_eq!(config.shards, 3);
+
+        std::fs::write(&path, "mailbox = 10").unwrap();
+        assert!(matches!(Config::from_file(&path), Err(Error::Config(_))));
+        let args = Args {
+            shards: Some(0),
+            ..Args::default()
+        };
+        assert!(Config::from_args(args).is_err());
+
+        let args = Args::try_parse_from([
+            "chat-server",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+            "--tls-plaintext",
+            "redirect",
+        ])
+        .unwrap();
+        let tls = Config::from_args(args).unwrap().tls.unwrap();
+        assert_eq!(tls.key_path, Path::new("key.pem"));
+        assert_eq!(tls.plaintext, Plaintext::Redirect);
+        assert!(Args::try_parse_from(["chat-server", "--tls-cert", "cert.pem"]).is_err());
+
+        let attachments = Attachments::default();
+        assert!(attachments.accepts("image/PNG"));
+        assert!(attachments.accepts("text/plain"));
+        assert!(!attachments.accepts("image/"));
+        assert!(!attachments.accepts("imagex/png"));
+        assert!(!attachments.accepts("text/html"));
+    }
+
+    #[test]
+    fn test_invalid_values_refused() {
+        assert!(Config::default().validate().is_ok());
+        let invalid: Vec<fn(&mut Config)> = vec![
+            |config| config.mailbox_size = 0,
+            |config| config.broadcast_capacity = 0,
+            |config| config.webhook_delivery.max_attempts = 0,
+            |config| config.compaction_interval_seconds = 0,
+            |config| config.attachments.gc_interval_seconds = 0,
+            |config| config.attachments.thumbnail_workers = 0,
+            |config| {
+                config.tls = Some(TlsConfig {
+                    cert_path: "cert.pem".into(),
+                    key_path: "key.pem".into(),
+                    plaintext: Plaintext::Redirect,
+                    redirect_bind: config.bind,
+                })
+            },
+        ];
+        for invalidate in invalid {
+            let mut config = Config::default();
+            invalidate(&mut config);
+            assert!(matches!(config.validate(), Err(Error::Generic(_))));
+        }
+    }
+}
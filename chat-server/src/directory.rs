@@ -0,0 +1,129 @@
+//! Index of the channel members shared by the registry and the channel actors,
+//! so that the channels of a user are found without loading every channel.
+//! Built by the registry from the channel infos on start, kept up to date by the channel actors.
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use crate::{UserId, ID};
+
+#[derive(Default)]
+pub struct Directory {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Maps channel_id -> members of the channel
+    members: HashMap<ID, HashSet<UserId>>,
+    // Maps user -> channels the user is a member of
+    channels: HashMap<UserId, HashSet<ID>>,
+}
+
+impl Directory {
+    /// Replaces the members of the channel `channel_id`.
+    pub fn set_members(&self, channel_id: ID, members: impl IntoIterator<Item = UserId>) {
+        let members: HashSet<UserId> = members.into_iter().collect();
+        let mut inner = self.inner.write().unwrap();
+        let Inner {
+            members: all_members,
+            channels,
+        } = &mut *inner;
+        let previous = all_members.remove(&channel_id).unwrap_or_default();
+        for user in previous.difference(&members) {
+            if let Some(ids) = channels.get_mut(user) {
+                ids.remove(&channel_id);
+                if ids.is_empty() {
+                    channels.remove(user);
+                }
+            }
+        }
+        for user in members.difference(&previous) {
+            channels.entry(*user).or_default().insert(channel_id);
+        }
+        if !members.is_empty() {
+            all_members.insert(channel_id, members);
+        }
+    }
+
+    /// Ids of the channels `user` is a member of.
+    pub fn channels_of(&self, user: &UserId) -> Vec<ID> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .channels
+            .get(user)
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether `user` is a member of the channel `channel_id`.
+    pub fn is_member(&self, channel_id: ID, user: &UserId) -> bool {
+        let inner = self.inner.read().unwrap();
+        inner
+            .members
+            .get(&channel_id)
+            .is_some_and(|members| members.contains(user))
+    }
+
+    /// Marks the members of all the channels (see [`crate::user::collect_unused`]).
+    pub fn mark_users(&self) {
+        self.inner
+            .read()
+            .unwrap()
+            .channels
+            .keys()
+            .for_each(UserId::mark);
+    }
+
+    /// Whether `user` is a member of at least one channel.
+    pub fn is_member_anywhere(&self, user: &UserId) -> bool {
+        self.inner.read().unwrap().channels.contains_key(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_id;
+
+    #[test]
+    fn test_set_members() {
+        let directory = Directory::default();
+        let (alice, bob) = (UserId::from("alice"), UserId::from("bob"));
+        let (ops, dev) = (new_id(), new_id());
+        directory.set_members(ops, vec![alice, bob]);
+        directory.set_members(dev, vec![alice]);
+        let mut channels = directory.channels_of(&alice);
+        channels.sort();
+        let mut expected = vec![ops, dev];
+        expected.sort();
+        assert_eq!(channels, expected);
+
+        assert!(directory.is_member(ops, &bob));
+        directory.set_members(ops, vec![alice]);
+        assert!(!directory.is_member(ops, &bob));
+        assert!(directory.channels_of(&bob).is_empty());
+        assert!(!directory.is_member_anywhere(&bob));
+        directory.set_members(dev, Vec::new());
+        assert_eq!(directory.channels_of(&alice), vec![ops]);
+    }
+
+    #[test]
+    fn test_unknown_channels_and_users() {
+        let directory = Directory::default();
+        let (alice, bob) = (UserId::from("alice"), UserId::from("bob"));
+        let ops = new_id();
+        assert!(!directory.is_member(ops, &alice));
+        assert!(directory.channels_of(&alice).is_empty());
+
+        // Replacing the members with the same ones changes nothing
+        directory.set_members(ops, vec![alice, alice]);
+        directory.set_members(ops, vec![alice]);
+        assert_eq!(directory.channels_of(&alice), vec![ops]);
+        assert!(!directory.is_member(ops, &bob));
+
+        // A channel left empty is forgotten
+        directory.set_members(ops, Vec::new());
+        assert!(!directory.is_member_anywhere(&alice));
+        assert!(directory.inner.read().unwrap().members.is_empty());
+    }
+}
@@ -7,6 +7,70 @@ pub type Result<T = (), E = Error> = std::result::Result<T, E>;
 pub enum Error {
     #[error("Channel does not exist")]
     ChannelNotFound,
+    #[error("Invalid channel info: {0}")]
+    InvalidChannelInfo(String),
+    #[error("Message does not exist")]
+    MessageNotFound,
+    #[error("Session does not exist or has expired")]
+    SessionNotFound,
+    #[error("Connection does not exist or is closed")]
+    ConnectionNotFound,
+    #[error("Connection user is not known")]
+    UnknownUser,
+    #[error("Invalid user name")]
+    InvalidUserName,
+    #[error("User is not a member of the channel")]
+    NotAMember,
+    #[error("Joining the channel requires an invitation")]
+    InvitationRequired,
+    #[error("Webhook does not exist or the token is invalid")]
+    WebhookNotFound,
+    #[error("Invalid webhook URL: {0}")]
+    InvalidWebhookUrl(String),
+    #[error("Webhook delivery queue is full")]
+    WebhookQueueFull,
+    #[error("Bot does not exist or the token is invalid")]
+    BotNotFound,
+    #[error("Bot name is already taken")]
+    BotNameTaken,
+    #[error("Bot {0} is not a member of the channel")]
+    BotNotAMember(crate::UserId),
+    #[error("Connections can only act on behalf of their user")]
+    UserMismatch,
+    #[error("User is held by another session, resume it instead")]
+    UserTaken,
+    #[error("Command /{0} is already registered")]
+    CommandTaken(String),
+    #[error("Unknown command /{0}, see /help")]
+    UnknownCommand(String),
+    #[error("Invalid command: {0}")]
+    InvalidCommand(String),
+    #[error("Command invocation does not exist or has expired")]
+    InvocationNotFound,
+    #[error("Attachment does not exist")]
+    AttachmentNotFound,
+    #[error("Attachment exceeds the maximum size of {0} bytes")]
+    AttachmentTooLarge(u64),
+    #[error("Unsupported attachment type {0}")]
+    UnsupportedMediaType(String),
+    #[error("Invalid attachment: {0}")]
+    InvalidAttachment(String),
+    #[error("Invitation does not exist")]
+    InvitationNotFound,
+    #[error("User is banned from the channel")]
+    UserBanned,
+    #[error("User is muted until {0}")]
+    UserMuted(chrono::DateTime<chrono::Utc>),
+    #[error("User role does not allow this action")]
+    InsufficientRole,
+    #[error("Rate limit exceeded, retry after {0:?}")]
+    RateLimited(std::time::Duration),
+    #[error("Direct channel requires two distinct users")]
+    InvalidDirectChannel,
+    #[error("Direct channel membership cannot change")]
+    DirectChannelMembership,
+    #[error("Server is shutting down")]
+    ShuttingDown,
     #[error("Actor unexpected termination")]
     ActorUnexpectedTermination,
     #[error("IO error")]
@@ -15,6 +79,12 @@ pub enum Error {
     Bincode(#[from] bincode::Error),
     #[error("JSON error")]
     Json(#[from] serde_json::Error),
+    #[error("Config error: {0}")]
+    Config(#[from] toml::de::Error),
+    #[error("Image error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("TLS error: {0}")]
+    Tls(#[from] tokio_rustls::rustls::Error),
     #[error("{0}")]
     Generic(String),
 }
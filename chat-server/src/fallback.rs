@@ -0,0 +1,145 @@
+//! Fallback transports for the clients that cannot open a websocket (e.g. behind proxies breaking them).
+//! The client receives its [`ServerMessage`]s as Server-Sent Events and posts its [`ClientMessage`]s over HTTP,
+//! the connection is registered and handled exactly like a websocket one otherwise.
+//!
+//! [`ServerMessage`]: crate::websocket::ServerMessage
+//! [`ClientMessage`]: crate::websocket::ClientMessage
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use futures::{future, stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{error, field, info, info_span, warn};
+use uuid::Uuid;
+use warp::{http::StatusCode, hyper::body::Bytes, sse::Event, ws::Message as WsMessage, Reply};
+
+use crate::{
+    errors::Error, registry_actor::RegistryHandle, server_actor::ServerHandle,
+    websocket::run_connection,
+};
+
+/// Maximum size in bytes of a client message posted over HTTP.
+pub const MAX_POST_SIZE: u64 = 64 * 1024;
+
+/// Payload of the first event of the stream.
+#[derive(Serialize)]
+struct Connected {
+    /// The connection the client posts its messages to
+    connection_id: Uuid,
+    /// Secret the client authenticates its posts with (`Bearer <post token>`)
+    post_token: String,
+}
+
+/// Opens a connection streaming the server messages as Server-Sent Events.
+/// The first event (`connected`) carries the id of the connection the client posts its messages to
+/// along with the token authenticating the posts, the connection closes once the client stops reading the stream.
+pub async fn handle_events(
+    server: Arc<ServerHandle>,
+    registry: Arc<RegistryHandle>,
+) -> Result<warp::reply::Response, Infallible> {
+    let (connection_tx, connection_rx) = mpsc::unbounded_channel();
+    let (subscriptions_tx, subscriptions_rx) = mpsc::unbounded_channel();
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+
+    let sender = connection_tx.clone();
+    let (connection_id, post_token) = match server
+        .connect_fallback(connection_tx, subscriptions_tx, incoming_tx)
+        .await
+    {
+        Ok(connected) => connected,
+        Err(err) => return Ok(error_reply(&err)),
+    };
+    let span = info_span!(
+        "connection",
+        connection_id,
+        transport = "sse",
+        user = field::Empty
+    );
+    info!(parent: &span, "Opened");
+
+    let connected = Event::default().event("connected").json_data(Connected {
+        connection_id: Uuid::from_u128(connection_id),
+        post_token,
+    });
+    let connected = match connected {
+        Ok(connected) => connected,
+        Err(err) => {
+            error!(%err, "Serializing error");
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    tokio::spawn(async move {
+        let incoming = UnboundedReceiverStream::new(incoming_rx);
+        if let Err(err) = run_connection(
+            connection_id,
+            sender,
+            incoming,
+            subscriptions_rx,
+            server,
+            registry,
+            span.clone(),
+        )
+        .await
+        {
+            warn!(parent: &span, %err, "Connection error");
+        }
+    });
+
+    // The stream ends with the close frame sent on shutdown
+    let events = UnboundedReceiverStream::new(connection_rx)
+        .take_while(|msg| future::ready(matches!(msg, Ok(msg) if !msg.is_close())))
+        .filter_map(|msg| {
+            future::ready(
+                msg.ok()
+                    .and_then(|msg| msg.to_str().ok().map(|text| Event::default().data(text))),
+            )
+        });
+    let events = stream::once(future::ready(connected))
+        .chain(events)
+        .map(Ok::<_, Infallible>);
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response())
+}
+
+/// Hands a client message posted over HTTP to the connection of its event stream,
+/// the `authorization` header carries the post token of the connection (`Bearer <post token>`).
+/// The replies are sent as events, the request only fails if the connection is not open or the token is wrong.
+pub async fn handle_post(
+    connection_id: Uuid,
+    authorization: Option<String>,
+    body: Bytes,
+    server: Arc<ServerHandle>,
+) -> Result<warp::reply::Response, Infallible> {
+    let post_token = match authorization
+        .as_deref()
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+    {
+        Some(post_token) => post_token.trim().to_string(),
+        None => return Ok(StatusCode::UNAUTHORIZED.into_response()),
+    };
+    let text = match String::from_utf8(body.to_vec()) {
+        Ok(text) => text,
+        Err(_) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+    };
+    let reply = match server
+        .post(connection_id.as_u128(), post_token, WsMessage::text(text))
+        .await
+    {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(err) => error_reply(&err),
+    };
+    Ok(reply)
+}
+
+fn error_reply(err: &Error) -> warp::reply::Response {
+    let status = match err {
+        Error::ConnectionNotFound => StatusCode::NOT_FOUND,
+        Error::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+        _ => {
+            error!(%err, "Fallback connection error");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    warp::reply::with_status(err.to_string(), status).into_response()
+}
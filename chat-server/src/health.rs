@@ -0,0 +1,211 @@
+//! Liveness and readiness of the server.
+//! Both round-trip a probe through the actors and check that the data directory is writable,
+//! readiness additionally fails while the server is starting or shutting down.
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::{
+    config::Config,
+    errors::{Error, Result},
+    registry_actor::RegistryHandle,
+    server_actor::ServerHandle,
+};
+
+/// Time a single probe may take before its component is reported unhealthy.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Lifecycle state of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Starting,
+    Ready,
+    ShuttingDown,
+}
+
+/// Outcome of a health check.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub healthy: bool,
+    pub state: State,
+    pub components: BTreeMap<&'static str, ComponentReport>,
+}
+
+/// Outcome of the probe of a single component.
+#[derive(Debug, Serialize)]
+pub struct ComponentReport {
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<Result> for ComponentReport {
+    fn from(result: Result) -> Self {
+        Self {
+            healthy: result.is_ok(),
+            error: result.err().map(|err| err.to_string()),
+        }
+    }
+}
+
+/// Checks the health of the server components.
+pub struct Health {
+    server: Arc<ServerHandle>,
+    registry: Arc<RegistryHandle>,
+    config: Arc<Config>,
+    state: AtomicU8,
+}
+
+impl Health {
+    pub fn new(
+        server: Arc<ServerHandle>,
+        registry: Arc<RegistryHandle>,
+        config: Arc<Config>,
+    ) -> Self {
+        Self {
+            server,
+            registry,
+            config,
+            state: AtomicU8::new(State::Starting as u8),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        match self.state.load(Ordering::Acquire) {
+            s if s == State::Starting as u8 => State::Starting,
+            s if s == State::Ready as u8 => State::Ready,
+            _ => State::ShuttingDown,
+        }
+    }
+
+    pub fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Healthy if all the components answer their probes.
+    pub async fn liveness(&self) -> Report {
+        let components = self.probe().await;
+        Report {
+            healthy: components.values().all(|c| c.healthy),
+            state: self.state(),
+            components,
+        }
+    }
+
+    /// Healthy if the server is live and ready to accept connections.
+    pub async fn readiness(&self) -> Report {
+        let report = self.liveness().await;
+        Report {
+            healthy: report.healthy && report.state == State::Ready,
+            ..report
+        }
+    }
+
+    async fn probe(&self) -> BTreeMap<&'static str, ComponentReport> {
+        let (server, registry, storage) = tokio::join!(
+            with_timeout(self.server.ping()),
+            with_timeout(self.registry.ping()),
+            with_timeout(check_storage(&self.config.data_dir)),
+        );
+        let mut components = BTreeMap::new();
+        components.insert("server", server.into());
+        components.insert("registry", registry.into());
+        components.insert("storage", storage.into());
+        components
+    }
+}
+
+async fn with_timeout(probe: impl Future<Output = Result>) -> Result {
+    tokio::time::timeout(PROBE_TIMEOUT, probe)
+        .await
+        .map_err(|_| Error::Generic(format!("No answer within {:?}", PROBE_TIMEOUT)))?
+}
+
+/// Checks that a file can be written into the data directory.
+/// Each probe writes its own file, concurrent probes do not remove each other's file.
+async fn check_storage(data_dir: &Path) -> Result {
+    tokio::fs::create_dir_all(data_dir).await?;
+    let path = data_dir.join(format!(".health-{}", crate::new_id()));
+    tokio::fs::write(&path, b"ok").await?;
+    tokio::fs::remove_file(&path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_readiness_follows_state_and_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().join("data"),
+            ..Config::default()
+        });
+        let health = Health::new(
+            Arc::new(ServerHandle::with_config(config.clone())),
+            Arc::new(RegistryHandle::with_config(config.clone())),
+            config,
+        );
+
+        assert!(health.liveness().await.healthy);
+        assert!(
+            !health.readiness().await.healthy,
+            "not ready while starting"
+        );
+        health.set_state(State::Ready);
+        let (first, second) = tokio::join!(health.readiness(), health.readiness());
+        assert!(first.healthy && second.healthy, "concurrent probes");
+        let probes = std::fs::read_dir(dir.path().join("data"))
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with(".health")
+            })
+            .count();
+        assert_eq!(probes, 0);
+
+        // The data directory is replaced by a file
+        std::fs::remove_dir_all(dir.path().join("data")).unwrap();
+        std::fs::write(dir.path().join("data"), b"").unwrap();
+        let report = health.readiness().await;
+        assert!(!report.healthy);
+        assert!(!report.components["storage"].healthy);
+        assert!(report.components["server"].healthy);
+
+        health.set_state(State::ShuttingDown);
+        assert_eq!(health.readiness().await.state, State::ShuttingDown);
+    }
+
+    #[test]
+    fn test_report_serialization() {
+        let mut components = BTreeMap::new();
+        components.insert("server", ComponentReport::from(Ok(())));
+        components.insert(
+            "storage",
+            ComponentReport::from(Err(Error::Generic("read-only".into()))),
+        );
+        let report = Report {
+            healthy: false,
+            state: State::ShuttingDown,
+            components,
+        };
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "healthy": false,
+                "state": "shutting_down",
+                "components": {
+                    "server": { "healthy": true },
+                    "storage": { "healthy": false, "error": "read-only" },
+                },
+            })
+        );
+    }
+}
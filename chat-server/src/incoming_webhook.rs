@@ -0,0 +1,176 @@
+//! Incoming webhooks: external systems (e.g. CI or monitoring) post into a channel over HTTP
+//! without holding a connection open, see [`IncomingWebhook`].
+//!
+//! [`IncomingWebhook`]: crate::channel::IncomingWebhook
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tracing::{error, info, warn};
+use warp::{http::StatusCode, Reply};
+
+use crate::{
+    channel::Message,
+    errors::{Error, Result},
+    registry_actor::RegistryHandle,
+    ID,
+};
+
+/// Maximum size in bytes of a webhook request body.
+pub const MAX_PAYLOAD_SIZE: u64 = 16 * 1024;
+
+/// Body of a webhook request.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Payload {
+    pub content: String,
+}
+
+/// Posts the content of `payload` into the channel as the bot user of the webhook authenticated by `token`.
+/// Replies with the created message.
+pub async fn handle_post(
+    channel_id: ID,
+    token: String,
+    payload: Payload,
+    registry: Arc<RegistryHandle>,
+) -> std::result::Result<warp::reply::Response, Infallible> {
+    if payload.content.trim().is_empty() {
+        return Ok(
+            warp::reply::with_status("Empty content", StatusCode::BAD_REQUEST).into_response(),
+        );
+    }
+    let reply = match post(&registry, channel_id, token, payload.content).await {
+        Ok(message) => {
+            info!(%channel_id, sender = %message.sender, seq = message.seq, "Webhook message posted");
+            warp::reply::with_status(warp::reply::json(&message), StatusCode::CREATED)
+                .into_response()
+        }
+        Err(err) => error_reply(channel_id, &err),
+    };
+    Ok(reply)
+}
+
+async fn post(
+    registry: &RegistryHandle,
+    channel_id: ID,
+    token: String,
+    content: String,
+) -> Result<Message> {
+    let channel = registry.get_channel(channel_id).await?;
+    channel.add_webhook_message(token, content).await
+}
+
+fn error_reply(channel_id: ID, err: &Error) -> warp::reply::Response {
+    match err {
+        // A missing channel is not told apart from an invalid token
+        Error::ChannelNotFound | Error::WebhookNotFound => {
+            warn!(%channel_id, "Invalid webhook");
+            warp::reply::with_status(Error::WebhookNotFound.to_string(), StatusCode::NOT_FOUND)
+                .into_response()
+        }
+        Error::RateLimited(retry_after) => {
+            let seconds = retry_after.as_secs_f64().ceil() as u64;
+            let reply = warp::reply::with_status(err.to_string(), StatusCode::TOO_MANY_REQUESTS);
+            warp::reply::with_header(reply, "retry-after", seconds.to_string()).into_response()
+        }
+        err => {
+            error!(%channel_id, %err, "Webhook error");
+            warp::reply::with_status(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channel::Visibility,
+        rate_limit::RateLimit,
+        test_util::{users, TestEnv},
+    };
+
+    fn payload(content: &str) -> Payload {
+        Payload {
+            content: content.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_through_incoming_webhook() {
+        let env = TestEnv::with(|config| config.rate_limits.webhook = RateLimit::new(2, 0.0));
+        let registry = Arc::new(env.registry());
+        let (owner, _, _) = users();
+        let channel = registry
+            .create_channel(owner, "ops".into(), Visibility::InviteOnly)
+            .await
+            .unwrap();
+        let channel_id = channel.channel_id();
+        let (webhook, token) = channel
+            .create_incoming_webhook(owner, "ci".into())
+            .await
+            .unwrap();
+        let (mut subscription, _) = channel.subscribe(owner).await.unwrap().unwrap();
+
+        let reply = handle_post(
+            channel_id,
+            token.clone(),
+            payload("build passed"),
+            registry.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.status(), StatusCode::CREATED);
+        let publication = subscription.recv().await.unwrap();
+        assert!(matches!(
+            &publication.message,
+            crate::websocket::ServerMessage::ChatMessage(m)
+                if &*m.sender.name() == "ci" && m.content == "build passed" && m.webhook == Some(webhook.id)
+        ));
+        // The bot user does not join the channel
+        let members = channel.get_members(owner).await.unwrap();
+        assert_eq!(members.len(), 1);
+
+        let post = |token: &str, content: &str| {
+            handle_post(channel_id, token.into(), payload(content), registry.clone())
+        };
+        assert_eq!(
+            post("invalid", "hi").await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            post(&token, " ").await.unwrap().status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post(&token, "second").await.unwrap().status(),
+            StatusCode::CREATED
+        );
+        let limited = post(&token, "third").await.unwrap();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(limited.headers().contains_key("retry-after"));
+
+        channel
+            .delete_incoming_webhook(owner, webhook.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            post(&token, "hi").await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_channel_not_told_apart() {
+        let env = TestEnv::new();
+        let registry = Arc::new(env.registry());
+        let reply = handle_post(crate::new_id(), "token".into(), payload("hi"), registry)
+            .await
+            .unwrap();
+        assert_eq!(reply.status(), StatusCode::NOT_FOUND);
+
+        // Only the content is accepted
+        assert!(serde_json::from_str::<Payload>(r#"{"content":"hi"}"#).is_ok());
+        assert!(serde_json::from_str::<Payload>(r#"{"content":"hi","sender":"alice"}"#).is_err());
+    }
+}
@@ -1,18 +1,36 @@
+pub mod attachment;
+pub mod bot_actor;
 pub mod channel;
 pub mod channel_actor;
+pub mod channel_info;
+pub mod commands;
+pub mod config;
+pub mod directory;
 pub mod errors;
+pub mod fallback;
+pub mod health;
+pub mod incoming_webhook;
+pub mod metrics;
+pub mod outgoing_webhook;
+pub mod rate_limit;
 pub mod registry_actor;
+pub mod search;
 pub mod server_actor;
+pub mod telemetry;
+#[cfg(test)]
+mod test_util;
+pub mod thumbnail;
+pub mod tls;
+pub mod user;
 pub mod websocket;
 
-const MAX_MAILBOX_SIZE: usize = 1024;
+/// Maximum number of messages returned by a single history read.
+pub const MAX_HISTORY_SIZE: usize = 200;
 
-/// Relative path of the folder in which Channel information files (`${ID}`) files are stored.
-pub const CHANNEL_INFO_FOLDER: &str = "data/channels/info/";
-/// Relative path of the folder in which Channel data files are stored (channel directories and messages).
-pub const CHANNEL_DATA_FOLDER: &str = "data/channels/data/";
+/// Id of the default public channel every user can post into.
+pub const LOBBY_CHANNEL_ID: ID = uuid::Uuid::from_u128(0x13cdc63e_55e2_403b_9ac6_4aa7c2155bf4);
 
-type UserId = String;
+pub use user::UserId;
 
 type ID = uuid::Uuid;
 
@@ -1,29 +1,305 @@
+use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::Duration;
 
 use chat_server::{
-    registry_actor::RegistryHandle, server_actor::ServerHandle, websocket::handle_connection,
+    attachment::{self, DownloadQuery},
+    config::{Config, Plaintext},
+    fallback,
+    health::{Health, Report, State},
+    incoming_webhook,
+    metrics::metrics,
+    registry_actor::{self, RegistryHandle},
+    search::{self, SearchQuery},
+    server_actor::ServerHandle,
+    telemetry,
+    tls::{self, CertResolver},
+    user,
+    websocket::handle_connection,
 };
-use warp::Filter;
+use futures::FutureExt;
+use tokio::{net::TcpListener, sync::watch};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use warp::{http::StatusCode, Filter, Reply};
 #[tokio::main]
 async fn main() {
-    let server = Arc::new(ServerHandle::new());
-    let registry = Arc::new(RegistryHandle::new());
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+    telemetry::init(&config);
+    let server = Arc::new(ServerHandle::with_config(config.clone()));
+    let registry = Arc::new(RegistryHandle::with_config(config.clone()));
+    tokio::spawn(registry_actor::run_compaction(
+        registry.clone(),
+        config.clone(),
+    ));
+    tokio::spawn(attachment::run_garbage_collection(registry.blobs().clone()));
+    tokio::spawn(user::run_collection(server.clone(), registry.clone()));
+    let health = Arc::new(Health::new(
+        server.clone(),
+        registry.clone(),
+        config.clone(),
+    ));
 
-    let server = warp::any().map(move || server.clone());
-    let registry = warp::any().map(move || registry.clone());
+    let server_filter = server.clone();
+    let server_filter = warp::any().map(move || server_filter.clone());
+    let registry_filter = registry.clone();
+    let registry_filter = warp::any().map(move || registry_filter.clone());
 
     let chat = warp::path("chat")
         // Filter that prepares ws handshake
         .and(warp::ws())
-        .and(server)
-        .and(registry)
+        .and(server_filter.clone())
+        .and(registry_filter.clone())
         .map(move |ws: warp::ws::Ws, server, registry| {
             ws.on_upgrade(|socket| async {
-                //TODO log handle_connection errors
-                tokio::spawn(handle_connection(socket, server, registry));
+                tokio::spawn(async {
+                    if let Err(err) = handle_connection(socket, server, registry).await {
+                        warn!(%err, "Connection error");
+                    }
+                });
             })
         });
 
-    let routes = chat;
-    warp::serve(routes).run(([0, 0, 0, 0], 9090)).await;
+    let events_route = warp::path!("chat" / "events")
+        .and(warp::get())
+        .and(server_filter.clone())
+        .and(registry_filter.clone())
+        .and_then(fallback::handle_events);
+    let post_route = warp::path!("chat" / "messages" / Uuid)
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(fallback::MAX_POST_SIZE))
+        .and(warp::body::bytes())
+        .and(server_filter.clone())
+        .and_then(fallback::handle_post);
+
+    let webhook_route = warp::path!("hooks" / Uuid / String)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            incoming_webhook::MAX_PAYLOAD_SIZE,
+        ))
+        .and(warp::body::json())
+        .and(registry_filter.clone())
+        .and_then(incoming_webhook::handle_post);
+
+    let upload_route = warp::path("attachments")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(
+            config.attachments.max_size_bytes,
+        ))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::stream())
+        .and(server_filter.clone())
+        .and(registry_filter.clone())
+        .and_then(attachment::handle_upload);
+    let download_route = warp::path!("attachments" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<DownloadQuery>())
+        .and(server_filter.clone())
+        .and(registry_filter.clone())
+        .and_then(attachment::handle_download);
+    let thumbnail_route = warp::path!("attachments" / String / "thumbnail")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<DownloadQuery>())
+        .and(server_filter.clone())
+        .and(registry_filter.clone())
+        .and_then(attachment::handle_thumbnail);
+
+    let search_route = warp::path("search")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<SearchQuery>())
+        .and(server_filter.clone())
+        .and(registry_filter.clone())
+        .and_then(search::handle_search);
+
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(server_filter)
+        .and(registry_filter)
+        .and_then(render_metrics);
+
+    let health_filter = health.clone();
+    let health_filter = warp::any().map(move || health_filter.clone());
+    let liveness_route = warp::path("healthz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(health_filter.clone())
+        .and_then(|health: Arc<Health>| async move {
+            Ok::<_, Infallible>(health_reply(health.liveness().await))
+        });
+    let readiness_route = warp::path("readyz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(health_filter)
+        .and_then(|health: Arc<Health>| async move {
+            Ok::<_, Infallible>(health_reply(health.readiness().await))
+        });
+
+    let routes = events_route
+        .or(post_route)
+        .or(chat)
+        .or(webhook_route)
+        .or(upload_route)
+        .or(download_route)
+        .or(thumbnail_route)
+        .or(search_route)
+        .or(metrics_route)
+        .or(liveness_route)
+        .or(readiness_route);
+    // Both the listener and the plaintext redirect stop once drained after the shutdown signal
+    let (stop, stopped) = watch::channel(false);
+    {
+        let health = health.clone();
+        let drain = Duration::from_secs(config.shutdown_drain_seconds);
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            // Not ready anymore, new clients are routed elsewhere while the listener still serves
+            health.set_state(State::ShuttingDown);
+            info!(?drain, "Draining");
+            tokio::time::sleep(drain).await;
+            let _ = stop.send(true);
+        });
+    }
+    let shutdown = move || {
+        let mut stopped = stopped.clone();
+        async move {
+            let _ = stopped.changed().await;
+        }
+    };
+
+    let serving = match &config.tls {
+        None => {
+            let (bind, serving) = match warp::serve(routes)
+                .try_bind_with_graceful_shutdown(config.bind, shutdown())
+            {
+                Ok(bound) => bound,
+                Err(err) => {
+                    error!(bind = %config.bind, %err, "Cannot listen");
+                    std::process::exit(1);
+                }
+            };
+            info!(%bind, "Listening");
+            serving.boxed()
+        }
+        Some(tls) => {
+            let resolver = match CertResolver::new(tls.clone()) {
+                Ok(resolver) => Arc::new(resolver),
+                Err(err) => {
+                    error!(%err, "Cannot load the TLS certificate");
+                    std::process::exit(1);
+                }
+            };
+            #[cfg(unix)]
+            tokio::spawn(tls::reload_on_hangup(resolver.clone()));
+            let listener = match TcpListener::bind(config.bind).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!(bind = %config.bind, %err, "Cannot listen");
+                    std::process::exit(1);
+                }
+            };
+            let bind = listener.local_addr().unwrap_or(config.bind);
+            let incoming = match tls::incoming(listener, resolver) {
+                Ok(incoming) => incoming,
+                Err(err) => {
+                    error!(%err, "Invalid TLS configuration");
+                    std::process::exit(1);
+                }
+            };
+            if tls.plaintext == Plaintext::Redirect {
+                match warp::serve(tls::redirect_to_https(bind.port()))
+                    .try_bind_with_graceful_shutdown(tls.redirect_bind, shutdown())
+                {
+                    Ok((redirect_bind, redirecting)) => {
+                        info!(bind = %redirect_bind, "Redirecting plaintext to TLS");
+                        tokio::spawn(redirecting);
+                    }
+                    Err(err) => {
+                        error!(bind = %tls.redirect_bind, %err, "Cannot listen");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            info!(%bind, "Listening with TLS");
+            warp::serve(routes)
+                .serve_incoming_with_graceful_shutdown(incoming, shutdown())
+                .boxed()
+        }
+    };
+    health.set_state(State::Ready);
+    // Completes once a shutdown signal is received and the listener is stopped
+    serving.await;
+
+    info!("Shutting down");
+    let timeout = Duration::from_secs(config.shutdown_timeout_seconds);
+    match tokio::time::timeout(timeout, server.shutdown()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => error!(%err, "Connections shutdown error"),
+        Err(_) => warn!(
+            ?timeout,
+            "Connections still open after the shutdown timeout"
+        ),
+    }
+    // The in-flight messages are persisted before the channels stop
+    if let Err(err) = registry.shutdown().await {
+        error!(%err, "Channels shutdown error");
+    }
+    info!("Stopped");
+}
+
+/// Replies with the health `report`, the status is 503 if not healthy.
+fn health_reply(report: Report) -> warp::reply::Response {
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    warp::reply::with_status(warp::reply::json(&report), status).into_response()
+}
+
+/// Replies with the metrics in the Prometheus text format.
+async fn render_metrics(
+    server: Arc<ServerHandle>,
+    registry: Arc<RegistryHandle>,
+) -> Result<warp::reply::Response, Infallible> {
+    let reply = match metrics().render(&server, &registry).await {
+        Ok(text) => {
+            warp::reply::with_header(text, "content-type", prometheus::TEXT_FORMAT).into_response()
+        }
+        Err(err) => {
+            error!(%err, "Metrics error");
+            warp::reply::with_status(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response()
+        }
+    };
+    Ok(reply)
+}
+
+/// Completes on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("Failed to install the SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
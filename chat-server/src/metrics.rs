@@ -0,0 +1,244 @@
+//! Prometheus metrics of the server.
+//! The counters are updated at the actor boundaries as the events happen,
+//! the gauges of the actor state are collected from the actors on every scrape.
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::errors::Result;
+use crate::registry_actor::RegistryHandle;
+use crate::server_actor::ServerHandle;
+
+/// The metrics of the server, registered under the `chat_` prefix.
+pub struct Metrics {
+    registry: Registry,
+    /// Open client connections (websocket and fallback transports)
+    pub connections: IntGauge,
+    /// Distinct users with at least one open connection
+    pub users: IntGauge,
+    /// Running channel actors
+    pub channels: IntGauge,
+    /// Commands waiting in the actor mailboxes, by actor kind
+    pub mailbox_depth: IntGaugeVec,
+    /// Client messages received by the connections
+    pub messages_received: IntCounter,
+    /// Chat messages appended to the channel logs
+    pub messages_persisted: IntCounter,
+    /// Chat messages dropped from the channel logs by the compaction
+    pub messages_compacted: IntCounter,
+    /// Publications forwarded to the clients
+    pub messages_delivered: IntCounter,
+    /// Messages that never reached a client, by reason (`closed` connection or `lagged` subscription)
+    pub dropped_sends: IntCounterVec,
+    /// Time from sending a command to an actor until the actor handled it, by actor kind and command.
+    /// It covers the wait in the actor mailbox and the handling, not the delivery of the reply to the caller
+    /// (nor the time spent by the caller before sending the command).
+    pub request_duration: HistogramVec,
+    /// Outgoing webhook delivery attempts, by outcome (`delivered`, `retried` or `dead`)
+    pub webhook_deliveries: IntCounterVec,
+    /// Thumbnails of the uploaded images, by outcome (`generated` or `failed`)
+    pub thumbnails: IntCounterVec,
+}
+
+/// The metrics of the process.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let metrics = Self {
+            registry: Registry::new_custom(Some("chat".into()), None).unwrap(),
+            connections: IntGauge::new("connections", "Open client connections").unwrap(),
+            users: IntGauge::new("users", "Distinct users with an open connection").unwrap(),
+            channels: IntGauge::new("channels", "Running channel actors").unwrap(),
+            mailbox_depth: IntGaugeVec::new(
+                Opts::new("mailbox_depth", "Commands waiting in the actor mailboxes"),
+                &["actor"],
+            )
+            .unwrap(),
+            messages_received: IntCounter::new(
+                "messages_received_total",
+                "Client messages received",
+            )
+            .unwrap(),
+            messages_persisted: IntCounter::new(
+                "messages_persisted_total",
+                "Chat messages appended to the channel logs",
+            )
+            .unwrap(),
+            messages_compacted: IntCounter::new(
+                "messages_compacted_total",
+                "Chat messages dropped from the channel logs by the compaction",
+            )
+            .unwrap(),
+            messages_delivered: IntCounter::new(
+                "messages_delivered_total",
+                "Publications forwarded to the clients",
+            )
+            .unwrap(),
+            dropped_sends: IntCounterVec::new(
+                Opts::new(
+                    "dropped_sends_total",
+                    "Messages that never reached a client",
+                ),
+                &["reason"],
+            )
+            .unwrap(),
+            request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "request_duration_seconds",
+                    "Time from sending a command to an actor until it is handled (mailbox wait included, reply excluded)",
+                )
+                .buckets(exponential_buckets(0.00005, 4.0, 10).unwrap()),
+                &["actor", "command"],
+            )
+            .unwrap(),
+            webhook_deliveries: IntCounterVec::new(
+                Opts::new(
+                    "webhook_deliveries_total",
+                    "Outgoing webhook delivery attempts",
+                ),
+                &["outcome"],
+            )
+            .unwrap(),
+            thumbnails: IntCounterVec::new(
+                Opts::new("thumbnails_total", "Thumbnails of the uploaded images"),
+                &["outcome"],
+            )
+            .unwrap(),
+        };
+        let registry = &metrics.registry;
+        registry
+            .register(Box::new(metrics.connections.clone()))
+            .unwrap();
+        registry.register(Box::new(metrics.users.clone())).unwrap();
+        registry
+            .register(Box::new(metrics.channels.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.mailbox_depth.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.messages_received.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.messages_persisted.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.messages_compacted.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.messages_delivered.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.dropped_sends.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.webhook_deliveries.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.thumbnails.clone()))
+            .unwrap();
+        metrics
+    }
+
+    /// Counts a message dropped because the connection is closed.
+    pub fn dropped_closed(&self) {
+        self.dropped_sends.with_label_values(&["closed"]).inc();
+    }
+
+    /// Records the time `actor` took to handle `command` since it was sent,
+    /// measured by the actor once the command is handled (see [`Metrics::request_duration`]).
+    pub fn observe_request(&self, actor: &str, command: &str, duration: Duration) {
+        self.request_duration
+            .with_label_values(&[actor, command])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Collects the state of the actors and encodes all the metrics in the Prometheus text format.
+    pub async fn render(&self, server: &ServerHandle, registry: &RegistryHandle) -> Result<String> {
+        let server_stats = server.stats().await?;
+        self.connections.set(server_stats.connections as i64);
+        self.users.set(server_stats.users as i64);
+        let registry_stats = registry.stats().await?;
+        self.channels.set(registry_stats.channels as i64);
+        for (actor, depth) in [
+            ("server", server_stats.mailbox_depth),
+            ("registry", registry_stats.mailbox_depth),
+            ("channel", registry_stats.channel_mailbox_depth),
+        ] {
+            self.mailbox_depth
+                .with_label_values(&[actor])
+                .set(depth as i64);
+        }
+
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .map_err(|err| err.to_string())?;
+        Ok(String::from_utf8(buf).map_err(|err| err.to_string())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_render_collects_actor_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().into(),
+            shards: 2,
+            ..Config::default()
+        });
+        let server = ServerHandle::with_config(config.clone());
+        let registry = RegistryHandle::with_config(config);
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let (subscriptions, _subscriptions) = mpsc::unbounded_channel();
+        let connection_id = server.connect(sender, subscriptions).await.unwrap();
+        server
+            .register_user(connection_id, "alice".into())
+            .await
+            .unwrap();
+
+        let text = metrics().render(&server, &registry).await.unwrap();
+        assert!(text.contains("chat_connections 1\n"), "{}", text);
+        assert!(text.contains("chat_users 1\n"));
+        // The lobby is always running
+        assert!(text.contains("chat_channels 1\n"));
+        assert!(text.contains("chat_mailbox_depth{actor=\"server\"} 0\n"));
+        assert!(text.contains(
+            "chat_request_duration_seconds_count{actor=\"server\",command=\"register_user\"}"
+        ));
+    }
+
+    #[test]
+    fn test_counters_recorded() {
+        let dropped = metrics().dropped_sends.with_label_values(&["closed"]);
+        let before = dropped.get();
+        metrics().dropped_closed();
+        // The other tests may drop messages meanwhile
+        assert!(dropped.get() > before);
+
+        // Labels of no actor, only recorded here
+        metrics().observe_request("test", "probe", Duration::from_millis(3));
+        let histogram = metrics()
+            .request_duration
+            .with_label_values(&["test", "probe"]);
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert!((histogram.get_sample_sum() - 0.003).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,930 @@
+//! Outgoing webhooks: the events of a channel are POSTed as JSON to the URLs subscribed to them,
+//! see [`OutgoingWebhook`].
+//!
+//! Every request carries the headers
+//! - `X-Chat-Event`: the kind of the event (e.g. `MessageCreated`),
+//! - `X-Chat-Delivery`: the id of the delivery, the same on every attempt,
+//! - `X-Chat-Signature`: `sha256=` followed by the hex encoded HMAC-SHA256 of the body keyed with the webhook secret.
+//!
+//! A delivery is stored until its endpoint answers with a 2xx status, the failed attempts are retried
+//! with an exponential backoff (also after a restart). The deliveries still failing after the configured
+//! attempts are moved to the dead letters of their channel, the ones of a deleted webhook are dropped.
+//!
+//! The endpoints are only reached on public addresses, the host is resolved on every attempt and
+//! the private, loopback and link-local addresses are refused (unless allowed by the config).
+//!
+//! [`OutgoingWebhook`]: crate::channel::OutgoingWebhook
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use hyper::{
+    client::conn,
+    header::{CONTENT_TYPE, HOST},
+    Body, Request, StatusCode, Uri,
+};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::{
+    crypto::ring as provider, pki_types::ServerName, ClientConfig, RootCertStore,
+};
+use tokio_rustls::TlsConnector;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{
+    channel::{Message, Moderation, OutgoingWebhook, Role},
+    config::{Config, WebhookDelivery},
+    errors::{Error, Result},
+    metrics::metrics,
+    new_id,
+    telemetry::Traced,
+    UserId, ID,
+};
+
+/// Kinds of the channel events a webhook subscribes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum EventKind {
+    MessageCreated,
+    MemberJoined,
+    MemberRemoved,
+    RoleChanged,
+    // Added last, the kinds are stored in the channel info by index
+    MessageEdited,
+    MessageDeleted,
+}
+
+impl EventKind {
+    /// All the kinds, the ones a webhook subscribes to by default.
+    pub const ALL: [EventKind; 6] = [
+        EventKind::MessageCreated,
+        EventKind::MemberJoined,
+        EventKind::MemberRemoved,
+        EventKind::RoleChanged,
+        EventKind::MessageEdited,
+        EventKind::MessageDeleted,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::MessageCreated => "MessageCreated",
+            EventKind::MessageEdited => "MessageEdited",
+            EventKind::MessageDeleted => "MessageDeleted",
+            EventKind::MemberJoined => "MemberJoined",
+            EventKind::MemberRemoved => "MemberRemoved",
+            EventKind::RoleChanged => "RoleChanged",
+        }
+    }
+}
+
+/// A channel event delivered to the outgoing webhooks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum WebhookEvent {
+    MessageCreated {
+        message: Message,
+    },
+    /// The message with its new content.
+    MessageEdited {
+        message: Message,
+    },
+    /// The message `seq` was deleted by `by`.
+    MessageDeleted {
+        seq: u64,
+        sender: UserId,
+        by: UserId,
+    },
+    MemberJoined {
+        user: UserId,
+    },
+    /// The member was kicked or banned by `by`.
+    MemberRemoved {
+        user: UserId,
+        by: UserId,
+        moderation: Moderation,
+    },
+    RoleChanged {
+        user: UserId,
+        by: UserId,
+        role: Role,
+    },
+}
+
+impl WebhookEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            WebhookEvent::MessageCreated { .. } => EventKind::MessageCreated,
+            WebhookEvent::MessageEdited { .. } => EventKind::MessageEdited,
+            WebhookEvent::MessageDeleted { .. } => EventKind::MessageDeleted,
+            WebhookEvent::MemberJoined { .. } => EventKind::MemberJoined,
+            WebhookEvent::MemberRemoved { .. } => EventKind::MemberRemoved,
+            WebhookEvent::RoleChanged { .. } => EventKind::RoleChanged,
+        }
+    }
+}
+
+/// Body of a request.
+#[derive(Serialize)]
+struct Payload<'a> {
+    delivery_id: ID,
+    channel_id: ID,
+    #[serde(with = "ts_milliseconds")]
+    created: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+}
+
+/// The POST of an event to an outgoing webhook.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: ID,
+    pub channel_id: ID,
+    pub webhook_id: ID,
+    pub url: String,
+    pub event: EventKind,
+    /// The JSON body of the request
+    pub body: String,
+    /// Failed attempts so far
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    #[serde(with = "ts_milliseconds")]
+    pub created: DateTime<Utc>,
+}
+
+impl Delivery {
+    fn new(channel_id: ID, webhook: &OutgoingWebhook, event: &WebhookEvent) -> Result<Self> {
+        let id = new_id();
+        let created = Utc::now();
+        let body = serde_json::to_string(&Payload {
+            delivery_id: id,
+            channel_id,
+            created,
+            event,
+        })?;
+        Ok(Self {
+            id,
+            channel_id,
+            webhook_id: webhook.id,
+            url: webhook.url.clone(),
+            event: event.kind(),
+            body,
+            attempts: 0,
+            last_error: None,
+            created,
+        })
+    }
+}
+
+/// A delivery waiting for its next attempt, stored along with its signature
+/// so that it can be retried without the webhook secret.
+#[derive(Debug, Serialize, Deserialize)]
+struct Pending {
+    delivery: Delivery,
+    signature: String,
+}
+
+/// The `X-Chat-Signature` header of a request with `body` to a webhook with `secret`.
+pub fn sign(secret: &str, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, body.as_bytes());
+    let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+/// Whether `ip` is reachable from the internet: not a private, loopback, link-local (e.g. cloud metadata),
+/// shared, unspecified, broadcast, documentation or multicast address.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network" and the shared address space (carrier-grade NAT)
+        || a == 0
+        || (a == 100 && (64..128).contains(&b)))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local (fc00::/7) and link-local (fe80::/10)
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80)
+}
+
+/// Delay before the retry following the `attempts`-th failed attempt.
+fn backoff(delivery: &WebhookDelivery, attempts: u32) -> Duration {
+    let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+    Duration::from_millis(delivery.initial_backoff_ms.saturating_mul(factor))
+        .min(Duration::from_secs(delivery.max_backoff_seconds))
+}
+
+/// Dispatcher of the outgoing webhook deliveries as an actor resource
+/// inspired by https://ryhl.io/blog/actors-with-tokio/
+enum WebhookCommand {
+    Enqueue {
+        channel_id: ID,
+        webhooks: Vec<OutgoingWebhook>,
+        event: WebhookEvent,
+    },
+    Cancel {
+        webhook_id: ID,
+        reply_to: oneshot::Sender<Result>,
+    },
+    DeadLetters {
+        channel_id: ID,
+        reply_to: oneshot::Sender<Result<Vec<Delivery>>>,
+    },
+}
+
+impl WebhookCommand {
+    /// Name of the command in the metrics.
+    fn name(&self) -> &'static str {
+        match self {
+            WebhookCommand::Enqueue { .. } => "enqueue",
+            WebhookCommand::Cancel { .. } => "cancel",
+            WebhookCommand::DeadLetters { .. } => "dead_letters",
+        }
+    }
+}
+
+struct WebhookActor {
+    receiver: mpsc::Receiver<Traced<WebhookCommand>>,
+    // Internal state
+    config: Arc<Config>,
+    tls: TlsConnector,
+    // Maps delivery id -> delivery waiting for its next attempt
+    pending: HashMap<ID, Pending>,
+    // Maps delivery id -> task of its next attempt
+    scheduled: HashMap<ID, JoinHandle<()>>,
+    // Outcomes of the attempts, sent by their tasks
+    outcomes_tx: mpsc::UnboundedSender<(ID, std::result::Result<(), String>)>,
+    outcomes_rx: mpsc::UnboundedReceiver<(ID, std::result::Result<(), String>)>,
+}
+
+impl WebhookActor {
+    fn new(config: Arc<Config>, receiver: mpsc::Receiver<Traced<WebhookCommand>>) -> Self {
+        let (outcomes_tx, outcomes_rx) = mpsc::unbounded_channel();
+        WebhookActor {
+            receiver,
+            config,
+            tls: tls_connector(),
+            pending: HashMap::new(),
+            scheduled: HashMap::new(),
+            outcomes_tx,
+            outcomes_rx,
+        }
+    }
+
+    /// Schedules the deliveries left pending by the previous run.
+    async fn on_start(&mut self) -> Result {
+        let dir = self.config.webhook_pending_dir();
+        tokio::fs::create_dir_all(&dir).await?;
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_some() {
+                // Interrupted store, the delivery was not queued
+                tokio::fs::remove_file(entry.path()).await?;
+                continue;
+            }
+            let pending: Pending = match tokio::fs::read(entry.path())
+                .await
+                .map_err(Error::from)
+                .and_then(|bytes| Ok(serde_json::from_slice(&bytes)?))
+            {
+                Ok(pending) => pending,
+                Err(err) => {
+                    error!(path = %entry.path().display(), %err, "Invalid webhook delivery");
+                    continue;
+                }
+            };
+            let delay = match pending.delivery.attempts {
+                0 => Duration::ZERO,
+                attempts => backoff(&self.config.webhook_delivery, attempts),
+            };
+            self.schedule(&pending, delay);
+            self.pending.insert(pending.delivery.id, pending);
+        }
+        if !self.pending.is_empty() {
+            info!(
+                deliveries = self.pending.len(),
+                "Resuming webhook deliveries"
+            );
+        }
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, msg: WebhookCommand) {
+        match msg {
+            WebhookCommand::Enqueue {
+                channel_id,
+                webhooks,
+                event,
+            } => {
+                if let Err(err) = self.enqueue(channel_id, webhooks, &event).await {
+                    error!(%channel_id, %err, "Queuing the webhook deliveries failed");
+                }
+            }
+            WebhookCommand::Cancel {
+                webhook_id,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.cancel(webhook_id).await);
+            }
+            WebhookCommand::DeadLetters {
+                channel_id,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.dead_letters(channel_id).await);
+            }
+        }
+    }
+
+    /// Stores a delivery of `event` to each of `webhooks` and attempts it right away.
+    async fn enqueue(
+        &mut self,
+        channel_id: ID,
+        webhooks: Vec<OutgoingWebhook>,
+        event: &WebhookEvent,
+    ) -> Result {
+        for webhook in webhooks {
+            let delivery = Delivery::new(channel_id, &webhook, event)?;
+            let signature = sign(&webhook.secret, &delivery.body);
+            let pending = Pending {
+                delivery,
+                signature,
+            };
+            self.store(&pending).await?;
+            self.schedule(&pending, Duration::ZERO);
+            self.pending.insert(pending.delivery.id, pending);
+        }
+        Ok(())
+    }
+
+    /// Drops the deliveries to the webhook with `webhook_id`, the webhook is deleted.
+    async fn cancel(&mut self, webhook_id: ID) -> Result {
+        let cancelled: Vec<ID> = self
+            .pending
+            .values()
+            .filter(|pending| pending.delivery.webhook_id == webhook_id)
+            .map(|pending| pending.delivery.id)
+            .collect();
+        for delivery_id in &cancelled {
+            self.pending.remove(delivery_id);
+            if let Some(task) = self.scheduled.remove(delivery_id) {
+                task.abort();
+            }
+            tokio::fs::remove_file(self.pending_path(*delivery_id)).await?;
+        }
+        if !cancelled.is_empty() {
+            info!(%webhook_id, deliveries = cancelled.len(), "Webhook deliveries cancelled");
+        }
+        Ok(())
+    }
+
+    /// Records the outcome of an attempt: the delivery is either done, retried later or dead.
+    async fn attempted(&mut self, delivery_id: ID, outcome: std::result::Result<(), String>) {
+        self.scheduled.remove(&delivery_id);
+        let mut pending = match self.pending.remove(&delivery_id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let delivery = &mut pending.delivery;
+        let span = info_span!("webhook", %delivery_id, webhook_id = %delivery.webhook_id);
+        let _entered = span.enter();
+        match outcome {
+            Ok(()) => {
+                info!(attempts = delivery.attempts + 1, "Webhook delivered");
+                metrics()
+                    .webhook_deliveries
+                    .with_label_values(&["delivered"])
+                    .inc();
+                if let Err(err) = tokio::fs::remove_file(self.pending_path(delivery_id)).await {
+                    error!(%err, "Removing the webhook delivery failed");
+                }
+            }
+            Err(err) => {
+                delivery.attempts += 1;
+                delivery.last_error = Some(err);
+                if delivery.attempts >= self.config.webhook_delivery.max_attempts {
+                    warn!(attempts = delivery.attempts, err = ?delivery.last_error, "Webhook delivery dead");
+                    metrics()
+                        .webhook_deliveries
+                        .with_label_values(&["dead"])
+                        .inc();
+                    if let Err(err) = self.bury(delivery).await {
+                        error!(%err, "Storing the dead webhook delivery failed");
+                    }
+                } else {
+                    let delay = backoff(&self.config.webhook_delivery, delivery.attempts);
+                    warn!(attempts = delivery.attempts, err = ?delivery.last_error, ?delay, "Webhook delivery failed, retrying");
+                    metrics()
+                        .webhook_deliveries
+                        .with_label_values(&["retried"])
+                        .inc();
+                    if let Err(err) = self.store(&pending).await {
+                        error!(%err, "Storing the webhook delivery failed");
+                    }
+                    self.schedule(&pending, delay);
+                    self.pending.insert(delivery_id, pending);
+                }
+            }
+        }
+    }
+
+    /// Attempts `pending` after `delay` in its own task, the outcome is sent back to the actor.
+    fn schedule(&mut self, pending: &Pending, delay: Duration) {
+        let delivery = &pending.delivery;
+        let id = delivery.id;
+        let request = match request(delivery, &pending.signature) {
+            Ok(request) => request,
+            Err(err) => {
+                let _ = self.outcomes_tx.send((id, Err(err)));
+                return;
+            }
+        };
+        let tls = self.tls.clone();
+        let timeout = Duration::from_secs(self.config.webhook_delivery.timeout_seconds);
+        let allow_private = self.config.webhook_delivery.allow_private_addresses;
+        let outcomes = self.outcomes_tx.clone();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let attempt = post(&tls, request, allow_private);
+            let outcome = match tokio::time::timeout(timeout, attempt).await {
+                Ok(outcome) => outcome,
+                Err(_) => Err(format!("No answer within {:?}", timeout)),
+            };
+            let _ = outcomes.send((id, outcome));
+        });
+        self.scheduled.insert(id, task);
+    }
+
+    fn pending_path(&self, delivery_id: ID) -> PathBuf {
+        self.config
+            .webhook_pending_dir()
+            .join(format!("{:x}", delivery_id.as_u128()))
+    }
+
+    fn dead_dir(&self, channel_id: ID) -> PathBuf {
+        self.config
+            .webhook_dead_dir()
+            .join(format!("{:x}", channel_id.as_u128()))
+    }
+
+    /// Stores `pending` until its next attempt.
+    async fn store(&self, pending: &Pending) -> Result {
+        let bytes = serde_json::to_vec(pending)?;
+        write_atomically(self.pending_path(pending.delivery.id), &bytes).await
+    }
+
+    /// Moves `delivery` to the dead letters of its channel.
+    async fn bury(&self, delivery: &Delivery) -> Result {
+        let dir = self.dead_dir(delivery.channel_id);
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join(format!("{:x}", delivery.id.as_u128()));
+        write_atomically(path, &serde_json::to_vec(delivery)?).await?;
+        tokio::fs::remove_file(self.pending_path(delivery.id)).await?;
+        Ok(())
+    }
+
+    /// The dead letters of the channel, oldest first.
+    async fn dead_letters(&self, channel_id: ID) -> Result<Vec<Delivery>> {
+        let mut entries = match tokio::fs::read_dir(self.dead_dir(channel_id)).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut deliveries = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_some() {
+                // Being written
+                continue;
+            }
+            let bytes = tokio::fs::read(entry.path()).await?;
+            deliveries.push(serde_json::from_slice::<Delivery>(&bytes)?);
+        }
+        deliveries.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.id.cmp(&b.id)));
+        Ok(deliveries)
+    }
+}
+
+/// Writes `bytes` to a temporary file renamed to `path`,
+/// so that an interrupted write leaves the previous content of `path` intact.
+async fn write_atomically(path: PathBuf, bytes: &[u8]) -> Result {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(bytes).await?;
+    file.sync_data().await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+/// Client configuration verifying the endpoints against the web PKI roots.
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder_with_provider(Arc::new(provider::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("The ring provider supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// The request of an attempt of `delivery`.
+struct Attempt {
+    uri: Uri,
+    request: Request<Body>,
+}
+
+fn request(delivery: &Delivery, signature: &str) -> std::result::Result<Attempt, String> {
+    let uri: Uri = delivery.url.parse().map_err(|err| format!("{}", err))?;
+    let authority = uri.authority().ok_or("Missing host")?.as_str();
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let request = Request::post(path)
+        .header(HOST, authority)
+        .header(CONTENT_TYPE, "application/json")
+        .header("x-chat-event", delivery.event.as_str())
+        .header("x-chat-delivery", delivery.id.to_string())
+        .header("x-chat-signature", signature)
+        .body(Body::from(delivery.body.clone()))
+        .map_err(|err| err.to_string())?;
+    Ok(Attempt { uri, request })
+}
+
+/// Sends the request of `attempt` over a new connection, succeeds if the endpoint answers with a 2xx status.
+/// The connection is made to a public address of the host only, unless `allow_private`.
+async fn post(
+    tls: &TlsConnector,
+    attempt: Attempt,
+    allow_private: bool,
+) -> std::result::Result<(), String> {
+    let https = attempt.uri.scheme_str() == Some("https");
+    // IPv6 literals are bracketed in URLs
+    let host = attempt
+        .uri
+        .host()
+        .ok_or("Missing host")?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = attempt
+        .uri
+        .port_u16()
+        .unwrap_or(if https { 443 } else { 80 });
+    // Connected to the address checked, the host cannot resolve to another one in between
+    let address = tokio::net::lookup_host((host.as_str(), port))
+        .await
+        .map_err(|err| err.to_string())?
+        .find(|address| allow_private || is_public(address.ip()))
+        .ok_or_else(|| format!("{} has no public address", host))?;
+    let stream = TcpStream::connect(address)
+        .await
+        .map_err(|err| err.to_string())?;
+    let status = if https {
+        let name = ServerName::try_from(host).map_err(|err| err.to_string())?;
+        let stream = tls
+            .connect(name, stream)
+            .await
+            .map_err(|err| err.to_string())?;
+        send(stream, attempt.request).await
+    } else {
+        send(stream, attempt.request).await
+    }
+    .map_err(|err| err.to_string())?;
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(format!("Endpoint answered {}", status))
+    }
+}
+
+async fn send<T>(io: T, request: Request<Body>) -> hyper::Result<StatusCode>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = conn::handshake(io).await?;
+    // Drives the connection, it closes once the response is received and the sender dropped
+    tokio::spawn(connection);
+    Ok(sender.send_request(request).await?.status())
+}
+
+async fn run(mut actor: WebhookActor) {
+    let span = info_span!("webhooks");
+    if let Err(err) = actor.on_start().instrument(span.clone()).await {
+        error!(parent: &span, %err, "Webhook actor initialization error");
+        return; // Note here the actor terminates
+    }
+    loop {
+        tokio::select! {
+            msg = actor.receiver.recv() => match msg {
+                Some(Traced { command, span, sent }) => {
+                    let name = command.name();
+                    // Handled within the span of the sender
+                    let span = info_span!(parent: &span, "webhooks");
+                    actor.handle_message(command).instrument(span).await;
+                    metrics().observe_request("webhooks", name, sent.elapsed());
+                }
+                None => break,
+            },
+            Some((delivery_id, outcome)) = actor.outcomes_rx.recv() => {
+                actor.attempted(delivery_id, outcome).await;
+            }
+        }
+    }
+}
+
+/// Handle of the [`WebhookActor`]
+/// Provides the public interface of the actor
+#[derive(Clone)]
+pub struct WebhookHandle {
+    sender: mpsc::Sender<Traced<WebhookCommand>>,
+}
+
+impl WebhookHandle {
+    /// Spawns the dispatcher, resuming the deliveries stored by the previous run.
+    pub fn new(config: Arc<Config>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.mailbox_size);
+        tokio::spawn(run(WebhookActor::new(config, receiver)));
+        Self { sender }
+    }
+
+    /// Queues a delivery of `event` of the channel to each of `webhooks` without waiting,
+    /// the deliveries are stored by the dispatcher. Fails if the dispatcher mailbox is full.
+    pub fn enqueue(
+        &self,
+        channel_id: ID,
+        webhooks: Vec<OutgoingWebhook>,
+        event: WebhookEvent,
+    ) -> Result {
+        let msg = WebhookCommand::Enqueue {
+            channel_id,
+            webhooks,
+            event,
+        };
+
+        self.sender
+            .try_send(Traced::new(msg))
+            .map_err(|err| match err {
+                mpsc::error::TrySendError::Full(_) => Error::WebhookQueueFull,
+                mpsc::error::TrySendError::Closed(_) => Error::ActorUnexpectedTermination,
+            })
+    }
+
+    /// Drops the deliveries to the webhook with `webhook_id` not delivered yet.
+    pub async fn cancel(&self, webhook_id: ID) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = WebhookCommand::Cancel {
+            webhook_id,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets the deliveries of the channel that exhausted their attempts, oldest first.
+    pub async fn dead_letters(&self, channel_id: ID) -> Result<Vec<Delivery>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = WebhookCommand::DeadLetters {
+            channel_id,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use warp::{http::HeaderMap, hyper::body::Bytes, Filter};
+
+    /// A request received by the stand-in endpoint.
+    struct Received {
+        headers: HeaderMap,
+        body: String,
+    }
+
+    /// Serves a local endpoint answering 500 to the first `failures` requests and 200 to the next ones.
+    fn stand_in(failures: usize) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let recorded = received.clone();
+        let route = warp::post()
+            .and(warp::path("hook"))
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .map(move |headers: HeaderMap, body: Bytes| {
+                let mut received = recorded.lock().unwrap();
+                received.push(Received {
+                    headers,
+                    body: String::from_utf8(body.to_vec()).unwrap(),
+                });
+                if received.len() <= failures {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    StatusCode::OK
+                }
+            });
+        let (addr, serving) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(serving);
+        (format!("http://{}/hook", addr), received)
+    }
+
+    fn webhook(url: String) -> OutgoingWebhook {
+        OutgoingWebhook {
+            id: new_id(),
+            url,
+            secret: "secret".into(),
+            events: EventKind::ALL.to_vec(),
+            created_by: "alice".into(),
+        }
+    }
+
+    /// Waits until `condition` holds, for at most 5 seconds.
+    async fn eventually(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Condition not met in time");
+    }
+
+    fn pending_count(config: &Config) -> usize {
+        std::fs::read_dir(config.webhook_pending_dir())
+            .unwrap()
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_delivery_retries_and_dead_letters() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().into(),
+            webhook_delivery: WebhookDelivery {
+                max_attempts: 3,
+                initial_backoff_ms: 10,
+                allow_private_addresses: true,
+                ..WebhookDelivery::default()
+            },
+            ..Config::default()
+        });
+        let webhooks = WebhookHandle::new(config.clone());
+        let channel_id = new_id();
+        let event = WebhookEvent::MemberJoined { user: "bob".into() };
+
+        // Delivered on the third attempt, signed the same way every time
+        let (url, received) = stand_in(2);
+        webhooks
+            .enqueue(channel_id, vec![webhook(url)], event.clone())
+            .unwrap();
+        eventually(|| received.lock().unwrap().len() == 3 && pending_count(&config) == 0).await;
+        {
+            let received = received.lock().unwrap();
+            let first = &received[0];
+            assert_eq!(first.headers["x-chat-event"], "MemberJoined");
+            assert_eq!(
+                first.headers["x-chat-signature"],
+                sign("secret", &first.body).as_str()
+            );
+            assert_ne!(
+                first.headers["x-chat-signature"],
+                sign("other", &first.body).as_str()
+            );
+            let body: serde_json::Value = serde_json::from_str(&first.body).unwrap();
+            assert_eq!(body["type"], "MemberJoined");
+            assert_eq!(body["user"], "bob");
+            assert_eq!(body["channel_id"], channel_id.to_string());
+            assert!(received.iter().all(|r| r.body == first.body
+                && r.headers["x-chat-delivery"] == first.headers["x-chat-delivery"]));
+        }
+        assert!(webhooks.dead_letters(channel_id).await.unwrap().is_empty());
+
+        // Never delivered
+        let (url, received) = stand_in(usize::MAX);
+        webhooks
+            .enqueue(channel_id, vec![webhook(url.clone())], event.clone())
+            .unwrap();
+        eventually(|| received.lock().unwrap().len() == 3 && pending_count(&config) == 0).await;
+        let dead = webhooks.dead_letters(channel_id).await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].url, url);
+        assert_eq!(dead[0].attempts, 3);
+        assert!(dead[0].last_error.as_deref().unwrap().contains("500"));
+
+        // The deliveries stored by a previous run are resumed
+        let (url, received) = stand_in(0);
+        let delivery = Delivery::new(channel_id, &webhook(url), &event).unwrap();
+        let pending = Pending {
+            signature: sign("secret", &delivery.body),
+            delivery,
+        };
+        std::fs::write(
+            config
+                .webhook_pending_dir()
+                .join(format!("{:x}", pending.delivery.id.as_u128())),
+            serde_json::to_vec(&pending).unwrap(),
+        )
+        .unwrap();
+        let _restarted = WebhookHandle::new(config.clone());
+        eventually(|| received.lock().unwrap().len() == 1 && pending_count(&config) == 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_of_deleted_webhook_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().into(),
+            webhook_delivery: WebhookDelivery {
+                initial_backoff_ms: 60_000,
+                allow_private_addresses: true,
+                ..WebhookDelivery::default()
+            },
+            ..Config::default()
+        });
+        let webhooks = WebhookHandle::new(config.clone());
+        let (url, received) = stand_in(usize::MAX);
+        let deleted = webhook(url);
+        let event = WebhookEvent::MemberJoined { user: "bob".into() };
+        webhooks
+            .enqueue(new_id(), vec![deleted.clone()], event)
+            .unwrap();
+        // Waiting for its retry
+        eventually(|| received.lock().unwrap().len() == 1).await;
+
+        webhooks.cancel(deleted.id).await.unwrap();
+        assert_eq!(pending_count(&config), 0);
+    }
+
+    #[tokio::test]
+    async fn test_private_addresses_refused() {
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(is_public("2606:2800:220:1::1".parse().unwrap()));
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(private.parse().unwrap()), "{}", private);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().into(),
+            webhook_delivery: WebhookDelivery {
+                max_attempts: 1,
+                ..WebhookDelivery::default()
+            },
+            ..Config::default()
+        });
+        let webhooks = WebhookHandle::new(config.clone());
+        let (url, received) = stand_in(0);
+        let channel_id = new_id();
+        let event = WebhookEvent::MemberJoined { user: "bob".into() };
+        webhooks
+            .enqueue(channel_id, vec![webhook(url)], event)
+            .unwrap();
+        eventually(|| config.webhook_dead_dir().exists() && pending_count(&config) == 0).await;
+        let dead = webhooks.dead_letters(channel_id).await.unwrap();
+        assert!(dead[0]
+            .last_error
+            .as_deref()
+            .unwrap()
+            .contains("no public address"));
+        assert!(received.lock().unwrap().is_empty());
+    }
+}
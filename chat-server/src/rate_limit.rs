@@ -0,0 +1,141 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// Rate limit of a token bucket: at most `burst` requests at once, refilled at `per_second` requests per second.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    pub const fn new(burst: u32, per_second: f64) -> Self {
+        Self { burst, per_second }
+    }
+}
+
+/// Parses a rate limit written as `burst/per_second` (e.g. `20/10`).
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (burst, per_second) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected `burst/per_second`, found `{}`", s))?;
+        let burst = burst.trim().parse().map_err(|e| format!("burst: {}", e))?;
+        let per_second: f64 = per_second
+            .trim()
+            .parse()
+            .map_err(|e| format!("per_second: {}", e))?;
+        if per_second.is_nan() || per_second < 0.0 {
+            return Err("per_second must not be negative".into());
+        }
+        Ok(Self::new(burst, per_second))
+    }
+}
+
+/// The rate limits applied to incoming client messages.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// Applies to every message received by a single connection.
+    pub connection: RateLimit,
+    /// Applies to the chat messages posted by a user (across all the user connections).
+    pub user: RateLimit,
+    /// Applies to the chat messages posted into a single channel (by all the users).
+    pub channel: RateLimit,
+    /// Applies to the messages posted through a single incoming webhook.
+    pub webhook: RateLimit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            connection: RateLimit::new(20, 10.0),
+            user: RateLimit::new(10, 5.0),
+            channel: RateLimit::new(100, 50.0),
+            webhook: RateLimit::new(10, 1.0),
+        }
+    }
+}
+
+/// Token bucket rate limiter
+/// see https://en.wikipedia.org/wiki/Token_bucket
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token from the bucket.
+    /// If the bucket is empty returns the time after which a token will be available.
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if self.limit.per_second > 0.0 {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.per_second,
+            ))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+
+    /// Returns `true` if the bucket would be full at `now` (that is it can be safely dropped).
+    pub fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens + elapsed * self.limit.per_second >= self.limit.burst as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(RateLimit::new(2, 1.0));
+        let now = Instant::now();
+        assert!(bucket.try_acquire(now).is_ok());
+        assert!(bucket.try_acquire(now).is_ok());
+        let retry_after = bucket.try_acquire(now).unwrap_err();
+        assert!(retry_after > Duration::from_millis(900) && retry_after <= Duration::from_secs(1));
+
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.try_acquire(later).is_err());
+        let later = now + Duration::from_millis(1000);
+        assert!(bucket.try_acquire(later).is_ok());
+        assert!(!bucket.is_full(later));
+        assert!(bucket.is_full(later + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!("20/10".parse(), Ok(RateLimit::new(20, 10.0)));
+        assert_eq!(" 3 / 0.5 ".parse(), Ok(RateLimit::new(3, 0.5)));
+        assert!("20".parse::<RateLimit>().is_err());
+        assert!("20/-1".parse::<RateLimit>().is_err());
+        assert!("x/1".parse::<RateLimit>().is_err());
+    }
+}
@@ -1,12 +1,26 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::sync::{mpsc, oneshot};
+use tracing::{error, warn, Instrument};
 
 use crate::{
+    attachment::BlobStore,
+    bot_actor::BotHandle,
+    channel::{
+        direct_channel_id, Channel, ChannelKind, DirectChannel, Membership, Role, UnreadCount,
+        Visibility,
+    },
     channel_actor::ChannelHandle,
+    config::Config,
+    directory::Directory,
     errors::{Error, Result},
+    metrics::metrics,
+    outgoing_webhook::WebhookHandle,
+    telemetry::Traced,
 };
-use crate::{ID, MAX_MAILBOX_SIZE};
+use crate::{new_id, UserId, ID, LOBBY_CHANNEL_ID};
 
 /// Registry of channels implementation as an actor resource
 /// inspired by https://ryhl.io/blog/actors-with-tokio/
@@ -15,67 +29,406 @@ enum RegistryCommand {
         channel_id: ID,
         reply_to: oneshot::Sender<Result<ChannelHandle>>,
     },
+    CreateChannel {
+        owner: UserId,
+        name: String,
+        visibility: Visibility,
+        reply_to: oneshot::Sender<Result<ChannelHandle>>,
+    },
+    GetDirectChannel {
+        user: UserId,
+        peer: UserId,
+        reply_to: oneshot::Sender<Result<ChannelHandle>>,
+    },
+    ListDirectChannels {
+        user: UserId,
+        reply_to: oneshot::Sender<Result<Vec<DirectChannel>>>,
+    },
+    GetChannels {
+        reply_to: oneshot::Sender<Result<Vec<ChannelHandle>>>,
+    },
+    GetUserChannels {
+        user: UserId,
+        reply_to: oneshot::Sender<Result<Vec<ChannelHandle>>>,
+    },
+    BorrowChannel {
+        channel_id: ID,
+        reply_to: oneshot::Sender<Result<ChannelHandle>>,
+    },
+    ReleaseChannel {
+        channel_id: ID,
+        reply_to: oneshot::Sender<Result>,
+    },
+    Stats {
+        reply_to: oneshot::Sender<Result<RegistryStats>>,
+    },
+    MarkUsers {
+        reply_to: oneshot::Sender<Result<Vec<ChannelHandle>>>,
+    },
+    Ping {
+        reply_to: oneshot::Sender<Result>,
+    },
+    Shutdown {
+        reply_to: oneshot::Sender<Result<Vec<ChannelHandle>>>,
+    },
+}
+
+/// Summary of the state of the registry.
+pub struct RegistryStats {
+    /// Running channel actors
+    pub channels: usize,
+    /// Commands waiting in the registry mailbox
+    pub mailbox_depth: usize,
+    /// Commands waiting in the mailboxes of all the channel actors
+    pub channel_mailbox_depth: usize,
+}
+
+impl RegistryCommand {
+    /// Name of the command in the metrics.
+    fn name(&self) -> &'static str {
+        match self {
+            RegistryCommand::GetChannel { .. } => "get_channel",
+            RegistryCommand::CreateChannel { .. } => "create_channel",
+            RegistryCommand::GetDirectChannel { .. } => "get_direct_channel",
+            RegistryCommand::ListDirectChannels { .. } => "list_direct_channels",
+            RegistryCommand::GetChannels { .. } => "get_channels",
+            RegistryCommand::GetUserChannels { .. } => "get_user_channels",
+            RegistryCommand::BorrowChannel { .. } => "borrow_channel",
+            RegistryCommand::ReleaseChannel { .. } => "release_channel",
+            RegistryCommand::Stats { .. } => "stats",
+            RegistryCommand::MarkUsers { .. } => "mark_users",
+            RegistryCommand::Ping { .. } => "ping",
+            RegistryCommand::Shutdown { .. } => "shutdown",
+        }
+    }
 }
 
 struct RegistryActor {
-    receiver: mpsc::Receiver<RegistryCommand>,
+    receiver: mpsc::Receiver<Traced<RegistryCommand>>,
     // Internal state
+
+    // Channels stored on disk (with or without a running actor)
+    known: HashSet<ID>,
+
+    // Maps channel_id -> running channel actor
     channels: HashMap<ID, ChannelHandle>,
+
+    // Channels spawned only to be borrowed (e.g. compacted), stopped once released
+    // unless handed out in the meantime
+    borrowed: HashSet<ID>,
+
+    // Maps user -> direct channels the user participates in
+    directs: HashMap<UserId, HashSet<DirectChannel>>,
+
+    // Config of every spawned channel
+    config: Arc<Config>,
+
+    // Dispatcher of the outgoing webhook deliveries shared by the channels
+    webhooks: WebhookHandle,
+
+    // Index of the channel members shared by the channels
+    directory: Arc<Directory>,
 }
 
 impl RegistryActor {
-    fn new(receiver: mpsc::Receiver<RegistryCommand>) -> Self {
+    fn new(
+        receiver: mpsc::Receiver<Traced<RegistryCommand>>,
+        config: Arc<Config>,
+        webhooks: WebhookHandle,
+        directory: Arc<Directory>,
+    ) -> Self {
         Self {
             receiver,
+            config,
+            webhooks,
+            directory,
+            known: HashSet::new(),
             channels: HashMap::new(),
+            borrowed: HashSet::new(),
+            directs: HashMap::new(),
         }
     }
 
     async fn on_start(&mut self) -> Result {
+        for channel in Channel::load_all(&self.config).await? {
+            self.known.insert(channel.id);
+            self.directory
+                .set_members(channel.id, channel.members.keys().cloned());
+            if let ChannelKind::Direct(first, second) = channel.kind {
+                self.index_direct(channel.id, first, second);
+            }
+        }
+        if !self.known.contains(&LOBBY_CHANNEL_ID) {
+            let lobby = Channel::new(LOBBY_CHANNEL_ID, "Lobby".into());
+            self.register(
+                ChannelHandle::create(
+                    lobby,
+                    self.config.clone(),
+                    self.webhooks.clone(),
+                    self.directory.clone(),
+                )
+                .await?,
+            );
+        }
         Ok(())
     }
 
-    fn handle_message(&mut self, msg: RegistryCommand) {
+    /// Gets the handle of the known channel `channel_id`, spawning its actor if not running.
+    fn spawn(&mut self, channel_id: ID) -> ChannelHandle {
+        self.borrowed.remove(&channel_id);
+        match self.channels.get(&channel_id) {
+            Some(c) => c.clone(),
+            None => self.register(ChannelHandle::new(
+                channel_id,
+                self.config.clone(),
+                self.webhooks.clone(),
+                self.directory.clone(),
+            )),
+        }
+    }
+
+    fn register(&mut self, channel: ChannelHandle) -> ChannelHandle {
+        self.known.insert(channel.channel_id());
+        self.channels.insert(channel.channel_id(), channel.clone());
+        channel
+    }
+
+    fn index_direct(&mut self, channel_id: ID, first: UserId, second: UserId) {
+        self.directs
+            .entry(first)
+            .or_default()
+            .insert(DirectChannel {
+                channel_id,
+                peer: second,
+            });
+        self.directs
+            .entry(second)
+            .or_default()
+            .insert(DirectChannel {
+                channel_id,
+                peer: first,
+            });
+    }
+
+    async fn handle_message(&mut self, msg: RegistryCommand) {
         match msg {
             RegistryCommand::GetChannel {
                 channel_id,
                 reply_to,
+            } => {
+                if self.known.contains(&channel_id) {
+                    let _ = reply_to.send(Ok(self.spawn(channel_id)));
+                } else {
+                    let _ = reply_to.send(Err(Error::ChannelNotFound));
+                }
+            }
+            RegistryCommand::CreateChannel {
+                owner,
+                name,
+                visibility,
+                reply_to,
+            } => {
+                let mut channel = Channel::new(new_id(), name);
+                channel.visibility = visibility;
+                channel.members.insert(owner, Membership::new(Role::Owner));
+                // Registered only once saved, a failed creation leaves no dead actor behind
+                let created = ChannelHandle::create(
+                    channel,
+                    self.config.clone(),
+                    self.webhooks.clone(),
+                    self.directory.clone(),
+                )
+                .await
+                .map(|c| self.register(c));
+                let _ = reply_to.send(created);
+            }
+            RegistryCommand::GetDirectChannel {
+                user,
+                peer,
+                reply_to,
+            } => {
+                if user == peer {
+                    let _ = reply_to.send(Err(Error::InvalidDirectChannel));
+                    return;
+                }
+                let channel_id = direct_channel_id(&user, &peer);
+                if self.channels.contains_key(&channel_id) {
+                    let _ = reply_to.send(Ok(self.spawn(channel_id)));
+                } else {
+                    let channel = Channel::new_direct(user, peer);
+                    let created = ChannelHandle::create(
+                        channel,
+                        self.config.clone(),
+                        self.webhooks.clone(),
+                        self.directory.clone(),
+                    )
+                    .await
+                    .map(|c| self.register(c));
+                    if created.is_ok() {
+                        self.index_direct(channel_id, user, peer);
+                    }
+                    let _ = reply_to.send(created);
+                }
+            }
+            RegistryCommand::ListDirectChannels { user, reply_to } => {
+                let mut channels: Vec<_> = self
+                    .directs
+                    .get(&user)
+                    .map(|directs| directs.iter().cloned().collect())
+                    .unwrap_or_default();
+                channels.sort_by_key(|a| a.peer);
+                let _ = reply_to.send(Ok(channels));
+            }
+            RegistryCommand::GetChannels { reply_to } => {
+                let missing: Vec<_> = self
+                    .known
+                    .iter()
+                    .filter(|id| !self.channels.contains_key(id))
+                    .copied()
+                    .collect();
+                for channel_id in missing {
+                    self.register(ChannelHandle::new(
+                        channel_id,
+                        self.config.clone(),
+                        self.webhooks.clone(),
+                        self.directory.clone(),
+                    ));
+                }
+                self.borrowed.clear();
+                let _ = reply_to.send(Ok(self.channels.values().cloned().collect()));
+            }
+            RegistryCommand::GetUserChannels { user, reply_to } => {
+                let mut ids = self.directory.channels_of(&user);
+                ids.retain(|id| self.known.contains(id));
+                let channels = ids.into_iter().map(|id| self.spawn(id)).collect();
+                let _ = reply_to.send(Ok(channels));
+            }
+            RegistryCommand::BorrowChannel {
+                channel_id,
+                reply_to,
             } => {
                 if let Some(c) = self.channels.get(&channel_id) {
                     let _ = reply_to.send(Ok(c.clone()));
-                } else {
-                    let c = ChannelHandle::new(channel_id);
-                    self.channels.insert(channel_id, c.clone());
+                } else if self.known.contains(&channel_id) {
+                    let c = self.register(ChannelHandle::new(
+                        channel_id,
+                        self.config.clone(),
+                        self.webhooks.clone(),
+                        self.directory.clone(),
+                    ));
+                    self.borrowed.insert(channel_id);
                     let _ = reply_to.send(Ok(c));
+                } else {
+                    let _ = reply_to.send(Err(Error::ChannelNotFound));
                 }
             }
+            RegistryCommand::ReleaseChannel {
+                channel_id,
+                reply_to,
+            } => {
+                let released = if self.borrowed.remove(&channel_id) {
+                    self.channels.remove(&channel_id)
+                } else {
+                    None
+                };
+                let stopped = match released {
+                    // Stopped before another actor of the channel can be spawned
+                    Some(c) => c.stop().await,
+                    None => Ok(()),
+                };
+                let _ = reply_to.send(stopped);
+            }
+            RegistryCommand::Stats { reply_to } => {
+                let mailbox_size = self.config.mailbox_size;
+                let _ = reply_to.send(Ok(RegistryStats {
+                    channels: self.channels.len(),
+                    mailbox_depth: 0,
+                    channel_mailbox_depth: self
+                        .channels
+                        .values()
+                        .map(|c| mailbox_size - c.mailbox_capacity())
+                        .sum(),
+                }));
+            }
+            RegistryCommand::MarkUsers { reply_to } => {
+                self.directory.mark_users();
+                for (user, directs) in &self.directs {
+                    user.mark();
+                    directs.iter().for_each(|direct| direct.peer.mark());
+                }
+                let _ = reply_to.send(Ok(self.channels.values().cloned().collect()));
+            }
+            RegistryCommand::Ping { reply_to } => {
+                let _ = reply_to.send(Ok(()));
+            }
+            RegistryCommand::Shutdown { reply_to } => {
+                // Refuses the new commands, the running channels are handed over to be stopped
+                self.receiver.close();
+                let _ = reply_to.send(Ok(self.channels.drain().map(|(_, c)| c).collect()));
+            }
         }
     }
 }
 
 async fn run(mut actor: RegistryActor) {
-    if let Err(_err) = actor.on_start().await {
+    if let Err(err) = actor.on_start().await {
+        error!(%err, "Registry actor initialization error");
         return; // Note here the actor terminates
     }
-    while let Some(msg) = actor.receiver.recv().await {
-        actor.handle_message(msg);
+    while let Some(Traced {
+        command,
+        span,
+        sent,
+    }) = actor.receiver.recv().await
+    {
+        let name = command.name();
+        actor.handle_message(command).instrument(span).await;
+        metrics().observe_request("registry", name, sent.elapsed());
     }
 }
 /// Handle of the [`RegistryActor`]
 /// Provides the public interface of the actor
 #[derive(Clone)]
 pub struct RegistryHandle {
-    sender: mpsc::Sender<RegistryCommand>,
+    sender: mpsc::Sender<Traced<RegistryCommand>>,
+    mailbox_size: usize,
+    bots: BotHandle,
+    blobs: Arc<BlobStore>,
 }
 
 impl RegistryHandle {
     pub fn new() -> Self {
-        let (sender, receiver) = mpsc::channel(MAX_MAILBOX_SIZE);
-        let actor = RegistryActor::new(receiver);
+        Self::with_config(Arc::new(Config::default()))
+    }
+
+    pub fn with_config(config: Arc<Config>) -> Self {
+        let mailbox_size = config.mailbox_size;
+        let (sender, receiver) = mpsc::channel(mailbox_size);
+        let webhooks = WebhookHandle::new(config.clone());
+        let directory = Arc::new(Directory::default());
+        let bots = BotHandle::new(config.clone(), directory.clone());
+        let blobs = Arc::new(BlobStore::new(config.clone()));
+        let actor = RegistryActor::new(receiver, config, webhooks, directory);
         tokio::spawn(run(actor));
-        Self { sender }
+        Self {
+            sender,
+            mailbox_size,
+            bots,
+            blobs,
+        }
+    }
+
+    /// The bots handling the slash commands that are not built in.
+    pub fn bots(&self) -> &BotHandle {
+        &self.bots
     }
 
+    /// The store of the attachment files.
+    pub fn blobs(&self) -> &Arc<BlobStore> {
+        &self.blobs
+    }
+
+    /// Gets an existing channel, fails with [`Error::ChannelNotFound`] if it does not exist.
     pub async fn get_channel(&self, channel_id: ID) -> Result<ChannelHandle> {
         let (reply_to, rx) = oneshot::channel();
         let msg = RegistryCommand::GetChannel {
@@ -83,7 +436,337 @@ impl RegistryHandle {
             reply_to,
         };
 
-        let _ = self.sender.send(msg).await;
+        let _ = self.sender.send(Traced::new(msg)).await;
         rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
     }
+
+    /// Creates a new channel with `owner` as its first member.
+    pub async fn create_channel(
+        &self,
+        owner: UserId,
+        name: String,
+        visibility: Visibility,
+    ) -> Result<ChannelHandle> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = RegistryCommand::CreateChannel {
+            owner,
+            name,
+            visibility,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets the direct channel between `user` and `peer`, creating it on first use.
+    pub async fn get_direct_channel(&self, user: UserId, peer: UserId) -> Result<ChannelHandle> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = RegistryCommand::GetDirectChannel {
+            user,
+            peer,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Lists the direct channels `user` participates in.
+    pub async fn list_direct_channels(&self, user: UserId) -> Result<Vec<DirectChannel>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = RegistryCommand::ListDirectChannels { user, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets all the existing channels (spawning the actors of the channels not running yet).
+    pub async fn get_channels(&self) -> Result<Vec<ChannelHandle>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = RegistryCommand::GetChannels { reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets the channels `user` is a member of (spawning the actors of the channels not running yet).
+    pub async fn get_user_channels(&self, user: UserId) -> Result<Vec<ChannelHandle>> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = RegistryCommand::GetUserChannels { user, reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets the channel `channel_id` like [`RegistryHandle::get_channel`],
+    /// its actor is stopped by [`RegistryHandle::release_channel`] if it was spawned by the borrow
+    /// and the channel was not handed out since.
+    pub async fn borrow_channel(&self, channel_id: ID) -> Result<ChannelHandle> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = RegistryCommand::BorrowChannel {
+            channel_id,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Releases the channel `channel_id` borrowed by [`RegistryHandle::borrow_channel`].
+    pub async fn release_channel(&self, channel_id: ID) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = RegistryCommand::ReleaseChannel {
+            channel_id,
+            reply_to,
+        };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Collects the unread messages of `user` in all the channels the user is member of,
+    /// the most unread first. The channels failing to count are logged and skipped.
+    pub async fn get_unread_counts(&self, user: UserId) -> Result<Vec<UnreadCount>> {
+        let channels = self.get_user_channels(user).await?;
+        let counts = futures::future::join_all(
+            channels
+                .iter()
+                .map(|channel| channel.get_unread_count(user)),
+        )
+        .await;
+        let mut unread = Vec::new();
+        for (channel, count) in channels.iter().zip(counts) {
+            match count {
+                Ok(Some(count)) => unread.push(count),
+                Ok(None) => {}
+                Err(err) => {
+                    warn!(channel_id = %channel.channel_id(), %err, "Unread count failed");
+                }
+            }
+        }
+        unread.sort_by_key(|count| std::cmp::Reverse(count.unread));
+        Ok(unread)
+    }
+
+    /// Collects the state of the registry and of the running channel actors.
+    pub async fn stats(&self) -> Result<RegistryStats> {
+        let mailbox_depth = self.mailbox_size - self.sender.capacity();
+        let (reply_to, rx) = oneshot::channel();
+        let msg = RegistryCommand::Stats { reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        let stats = rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        Ok(RegistryStats {
+            mailbox_depth,
+            ..stats
+        })
+    }
+
+    /// Marks the users referenced by the registry, the running channels and the bots
+    /// (see [`crate::user::collect_unused`]).
+    pub async fn mark_users(&self) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = RegistryCommand::MarkUsers { reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        let channels = rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        for channel in channels {
+            match channel.mark_users().await {
+                // Stopped meanwhile, its users are not referenced anymore
+                Ok(()) | Err(Error::ActorUnexpectedTermination) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.bots().mark_users().await
+    }
+
+    /// Round-trips a probe through the registry, fails if it is not running
+    /// (answered only after the channels are loaded on start).
+    pub async fn ping(&self) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = RegistryCommand::Ping { reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Stops the registry and all the running channel actors, flushing the channels to disk.
+    pub async fn shutdown(&self) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = RegistryCommand::Shutdown { reply_to };
+
+        let _ = self.sender.send(Traced::new(msg)).await;
+        let channels = rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        let stopped = futures::future::join_all(channels.iter().map(|c| c.stop())).await;
+        stopped.into_iter().collect()
+    }
+}
+
+impl Default for RegistryHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compacts the logs of the channels with a retention every `config.compaction_interval_seconds`,
+/// never returns. The channels are compacted one after the other by their own actor,
+/// the actors spawned only for the compaction are stopped once done.
+pub async fn run_compaction(registry: Arc<RegistryHandle>, config: Arc<Config>) {
+    let period = Duration::from_secs(config.compaction_interval_seconds);
+    let mut interval = tokio::time::interval(period);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(err) = compact_retained(&registry, &config).await {
+            error!(%err, "Compaction error");
+        }
+    }
+}
+
+/// Compacts the logs of the channels with a retention, see [`run_compaction`].
+/// Returns the number of dropped messages.
+async fn compact_retained(registry: &RegistryHandle, config: &Config) -> Result<usize> {
+    let mut dropped = 0;
+    // Read from the infos so that the channels without retention are not spawned
+    for channel in Channel::load_all(config).await? {
+        if channel.retention.is_unlimited() {
+            continue;
+        }
+        let handle = registry.borrow_channel(channel.id).await?;
+        match handle.compact().await {
+            Ok(compacted) => dropped += compacted,
+            Err(err) => error!(channel_id = %channel.id, %err, "Compaction error"),
+        }
+        registry.release_channel(channel.id).await?;
+    }
+    Ok(dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channel::Retention,
+        test_util::{users, TestEnv},
+    };
+
+    #[tokio::test]
+    async fn test_failed_creation_not_registered() {
+        let env = TestEnv::new();
+        let registry = env.registry();
+        let (alice, _, _) = users();
+        let created = registry
+            .create_channel(alice, "ops".into(), Visibility::Public)
+            .await
+            .unwrap();
+        assert_eq!(registry.stats().await.unwrap().channels, 2);
+
+        // The channel infos cannot be written anymore
+        std::fs::remove_dir_all(env.config.channel_info_dir()).unwrap();
+        std::fs::write(env.config.channel_info_dir(), b"").unwrap();
+        assert!(matches!(
+            registry
+                .create_channel(alice, "dev".into(), Visibility::Public)
+                .await,
+            Err(Error::Io(_))
+        ));
+        assert_eq!(registry.stats().await.unwrap().channels, 2);
+        assert!(registry.get_channel(created.channel_id()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unread_counts_of_member_channels() {
+        let env = TestEnv::new();
+        let registry = env.registry();
+        let (alice, bob, eve) = users();
+        let ops = registry
+            .create_channel(alice, "ops".into(), Visibility::Public)
+            .await
+            .unwrap();
+        let dev = registry
+            .create_channel(alice, "dev".into(), Visibility::Public)
+            .await
+            .unwrap();
+        let other = registry
+            .create_channel(eve, "other".into(), Visibility::Public)
+            .await
+            .unwrap();
+        ops.join(bob).await.unwrap();
+        dev.join(bob).await.unwrap();
+        ops.add_message(alice, "one".into()).await.unwrap();
+        ops.add_message(alice, "two".into()).await.unwrap();
+        dev.add_message(alice, "three".into()).await.unwrap();
+        other.add_message(eve, "four".into()).await.unwrap();
+
+        let unread: Vec<_> = registry
+            .get_unread_counts(bob)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|count| (count.channel_id, count.unread))
+            .collect();
+        assert_eq!(unread, vec![(ops.channel_id(), 2), (dev.channel_id(), 1)]);
+
+        for channel in [&ops, &dev, &other].iter() {
+            channel.stop().await.unwrap();
+        }
+        // The log of dev cannot be read anymore
+        let data_path = env
+            .config
+            .channel_data_dir()
+            .join(format!("{:x}", dev.channel_id().as_u128()));
+        let mut log = std::fs::read(&data_path).unwrap();
+        log.extend_from_slice(&3u32.to_le_bytes());
+        log.extend_from_slice(b"bad");
+        std::fs::write(&data_path, log).unwrap();
+
+        let registry = env.registry();
+        let unread = registry.get_unread_counts(bob).await.unwrap();
+        assert_eq!(unread.len(), 1);
+        assert_eq!(unread[0].channel_id, ops.channel_id());
+        // Only the channels of bob are spawned
+        assert_eq!(registry.stats().await.unwrap().channels, 2);
+    }
+
+    #[tokio::test]
+    async fn test_compaction_releases_channels() {
+        let env = TestEnv::new();
+        let registry = env.registry();
+        let (alice, _, _) = users();
+        let ops = registry
+            .create_channel(alice, "ops".into(), Visibility::Public)
+            .await
+            .unwrap();
+        let dev = registry
+            .create_channel(alice, "dev".into(), Visibility::Public)
+            .await
+            .unwrap();
+        for channel in [&ops, &dev].iter() {
+            for content in ["one", "two", "three"] {
+                channel.add_message(alice, content.into()).await.unwrap();
+            }
+        }
+        let retention = Retention {
+            max_age_seconds: 0,
+            max_messages: 1,
+        };
+        ops.set_retention(alice, retention).await.unwrap();
+        ops.stop().await.unwrap();
+        dev.stop().await.unwrap();
+
+        let registry = env.registry();
+        assert_eq!(registry.stats().await.unwrap().channels, 0);
+        assert_eq!(compact_retained(&registry, &env.config).await.unwrap(), 2);
+        // Neither the channels without retention nor the compacted one are left running
+        assert_eq!(registry.stats().await.unwrap().channels, 0);
+
+        // Handed out while borrowed, kept running
+        registry.borrow_channel(ops.channel_id()).await.unwrap();
+        let ops = registry.get_channel(ops.channel_id()).await.unwrap();
+        registry.release_channel(ops.channel_id()).await.unwrap();
+        assert_eq!(registry.stats().await.unwrap().channels, 1);
+        assert_eq!(ops.get_history(alice, 10, None).await.unwrap().len(), 1);
+    }
 }
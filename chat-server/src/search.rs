@@ -0,0 +1,451 @@
+//! Full-text search of the channel messages.
+//! Every channel actor keeps a [`SearchIndex`] of the sequence numbers of its messages, built from
+//! the channel log on start and updated on every append, edit and delete.
+//! A search only reads the channels the requester is a member of.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use chrono::{serde::ts_milliseconds_option, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+use warp::{http::StatusCode, Reply};
+
+use crate::{
+    channel::Message,
+    errors::{Error, Result},
+    registry_actor::RegistryHandle,
+    server_actor::ServerHandle,
+    UserId, ID,
+};
+
+/// Maximum number of messages returned by a search.
+pub const MAX_SEARCH_RESULTS: usize = 100;
+
+fn default_limit() -> usize {
+    20
+}
+
+/// Splits `text` into its lowercase alphanumeric terms.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Terms of `text` sorted, without duplicates.
+fn terms(text: &str) -> Vec<String> {
+    let mut terms: Vec<_> = tokenize(text).collect();
+    terms.sort();
+    terms.dedup();
+    terms
+}
+
+/// Filters of a search, the messages found contain all the terms of `text`.
+/// An empty `text` matches all the messages if another filter is set, none otherwise.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub text: String,
+    /// Searches a single channel, all the channels of the requester if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<ID>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<UserId>,
+    /// Messages created at or after this time
+    #[serde(
+        default,
+        with = "ts_milliseconds_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub after: Option<DateTime<Utc>>,
+    /// Messages created before this time
+    #[serde(
+        default,
+        with = "ts_milliseconds_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub before: Option<DateTime<Utc>>,
+    /// At most [`MAX_SEARCH_RESULTS`]
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+impl SearchQuery {
+    fn limit(&self) -> usize {
+        self.limit.min(MAX_SEARCH_RESULTS)
+    }
+
+    /// Whether a filter other than `text` is set.
+    fn is_filtered(&self) -> bool {
+        self.channel_id.is_some()
+            || self.sender.is_some()
+            || self.after.is_some()
+            || self.before.is_some()
+    }
+
+    fn matches(&self, message: &Message) -> bool {
+        self.sender
+            .as_ref()
+            .is_none_or(|sender| *sender == message.sender)
+            && self.after.is_none_or(|after| message.created >= after)
+            && self.before.is_none_or(|before| message.created < before)
+    }
+}
+
+/// Inverted index of the messages of a channel.
+/// It holds only sequence numbers, the hits are resolved through the messages the channel actor keeps.
+#[derive(Default)]
+pub struct SearchIndex {
+    // Maps term -> sequence numbers of the messages containing the term, in ascending order
+    postings: HashMap<String, Vec<u64>>,
+}
+
+impl SearchIndex {
+    /// Indexes `message`.
+    pub fn add(&mut self, message: &Message) {
+        for term in terms(&message.content) {
+            let seqs = self.postings.entry(term).or_default();
+            // Appended messages go last
+            let position = seqs.partition_point(|seq| *seq < message.seq);
+            if seqs.get(position) != Some(&message.seq) {
+                seqs.insert(position, message.seq);
+            }
+        }
+    }
+
+    /// Removes `message` (as indexed, i.e. before an edit) from the index.
+    pub fn remove(&mut self, message: &Message) {
+        for term in terms(&message.content) {
+            if let Some(seqs) = self.postings.get_mut(&term) {
+                if let Ok(position) = seqs.binary_search(&message.seq) {
+                    seqs.remove(position);
+                }
+                if seqs.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// Gets the messages matching `query` (its channel aside), the latest first.
+    /// `messages` are the indexed messages in sequence order.
+    pub fn search(&self, query: &SearchQuery, messages: &[Message]) -> Vec<Message> {
+        let mut postings = Vec::new();
+        for term in terms(&query.text) {
+            match self.postings.get(&term) {
+                Some(seqs) => postings.push(seqs),
+                None => return Vec::new(),
+            }
+        }
+        // The rarest term drives the intersection
+        postings.sort_by_key(|seqs| seqs.len());
+        let (rarest, others) = match postings.split_first() {
+            Some(split) => split,
+            None if query.is_filtered() => {
+                return messages
+                    .iter()
+                    .rev()
+                    .filter(|message| query.matches(message))
+                    .take(query.limit())
+                    .cloned()
+                    .collect();
+            }
+            None => return Vec::new(),
+        };
+        rarest
+            .iter()
+            .rev()
+            .filter(|seq| others.iter().all(|seqs| seqs.binary_search(seq).is_ok()))
+            .filter_map(|seq| {
+                let position = messages.binary_search_by_key(seq, |m| m.seq).ok()?;
+                Some(&messages[position])
+            })
+            .filter(|message| query.matches(message))
+            .take(query.limit())
+            .cloned()
+            .collect()
+    }
+}
+
+/// Searches the messages on behalf of `user`, the latest first.
+/// Fails if `user` is not a member of the channel of `query` (if any),
+/// otherwise the channels failing to search are logged and skipped.
+pub async fn search(
+    registry: &RegistryHandle,
+    user: UserId,
+    query: &SearchQuery,
+) -> Result<Vec<Message>> {
+    let mut messages = match query.channel_id {
+        Some(channel_id) => {
+            let channel = registry.get_channel(channel_id).await?;
+            match channel.search(user, query.clone()).await? {
+                Some(messages) => messages,
+                None => {
+                    // Fails like a read would (e.g. a private channel is not found)
+                    channel.get_members(user).await?;
+                    return Err(Error::NotAMember);
+                }
+            }
+        }
+        None => {
+            let channels = registry.get_user_channels(user).await?;
+            let found = futures::future::join_all(
+                channels
+                    .iter()
+                    .map(|channel| channel.search(user, query.clone())),
+            )
+            .await;
+            let mut messages = Vec::new();
+            for (channel, found) in channels.iter().zip(found) {
+                match found {
+                    Ok(found) => messages.extend(found.into_iter().flatten()),
+                    Err(err) => {
+                        warn!(channel_id = %channel.channel_id(), %err, "Search failed");
+                    }
+                }
+            }
+            messages
+        }
+    };
+    messages.sort_by_key(|m| std::cmp::Reverse(m.created));
+    messages.truncate(query.limit());
+    Ok(messages)
+}
+
+/// Searches the messages on behalf of the user of the session in the `authorization` header
+/// (`Bearer <session id>`), replies with the messages found.
+pub async fn handle_search(
+    authorization: Option<String>,
+    query: SearchQuery,
+    server: Arc<ServerHandle>,
+    registry: Arc<RegistryHandle>,
+) -> std::result::Result<warp::reply::Response, Infallible> {
+    let found = match server.authenticate(authorization.as_deref()).await {
+        Ok(user) => search(&registry, user, &query).await,
+        Err(err) => Err(err),
+    };
+    let reply = match found {
+        Ok(messages) => warp::reply::json(&messages).into_response(),
+        Err(err) => {
+            let status = match err {
+                Error::SessionNotFound => StatusCode::UNAUTHORIZED,
+                Error::NotAMember | Error::UserBanned => StatusCode::FORBIDDEN,
+                Error::ChannelNotFound => StatusCode::NOT_FOUND,
+                _ => {
+                    error!(%err, "Search error");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            };
+            if status != StatusCode::INTERNAL_SERVER_ERROR {
+                warn!(%err, "Search refused");
+            }
+            warp::reply::with_status(err.to_string(), status).into_response()
+        }
+    };
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channel::Visibility,
+        test_util::{users, TestEnv},
+    };
+
+    fn query(text: &str) -> SearchQuery {
+        serde_json::from_value(serde_json::json!({ "text": text })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let env = TestEnv::new();
+        let registry = env.registry();
+        let (alice, bob, eve) = users();
+        let ops = registry
+            .create_channel(alice, "ops".into(), Visibility::Private)
+            .await
+            .unwrap();
+        ops.invite(alice, bob).await.unwrap();
+        ops.respond_to_invitation(bob, true).await.unwrap();
+        let first = ops
+            .add_message(alice, "Deploy the API today".into())
+            .await
+            .unwrap();
+        ops.add_message(bob, "deploy done, api is up".into())
+            .await
+            .unwrap();
+        ops.add_message(bob, "lunch?".into()).await.unwrap();
+        let random = registry
+            .create_channel(eve, "random".into(), Visibility::Public)
+            .await
+            .unwrap();
+        random
+            .add_message(eve, "deploy on friday".into())
+            .await
+            .unwrap();
+
+        let found = search(&registry, bob, &query("api DEPLOY")).await.unwrap();
+        let contents: Vec<_> = found.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec!["deploy done, api is up", "Deploy the API today"]
+        );
+        let by_alice = SearchQuery {
+            sender: Some(alice),
+            ..query("deploy")
+        };
+        assert_eq!(search(&registry, bob, &by_alice).await.unwrap().len(), 1);
+        let earlier = SearchQuery {
+            before: Some(first.created),
+            ..query("deploy")
+        };
+        assert!(search(&registry, bob, &earlier).await.unwrap().is_empty());
+        assert!(search(&registry, bob, &query("friday"))
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(search(&registry, bob, &query("dep"))
+            .await
+            .unwrap()
+            .is_empty());
+        // Only filters
+        assert!(search(&registry, bob, &query("")).await.unwrap().is_empty());
+        let from_bob = SearchQuery {
+            sender: Some(bob),
+            ..query(" ")
+        };
+        let contents: Vec<_> = search(&registry, bob, &from_bob)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(contents, vec!["lunch?", "deploy done, api is up"]);
+
+        let in_ops = SearchQuery {
+            channel_id: Some(ops.channel_id()),
+            ..query("deploy")
+        };
+        assert!(matches!(
+            search(&registry, eve, &in_ops).await,
+            Err(Error::ChannelNotFound)
+        ));
+        assert_eq!(
+            search(&registry, eve, &query("deploy"))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Rebuilt from the log
+        ops.stop().await.unwrap();
+        let registry = env.registry();
+        assert_eq!(
+            search(&registry, bob, &query("api")).await.unwrap().len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_route() {
+        use warp::Filter;
+
+        let env = TestEnv::new();
+        let registry = Arc::new(env.registry());
+        let server = Arc::new(env.server());
+        let (sender, _) = tokio::sync::mpsc::unbounded_channel();
+        let (subscriptions, _) = tokio::sync::mpsc::unbounded_channel();
+        let connection_id = server.connect(sender, subscriptions).await.unwrap();
+        let (alice, _, eve) = users();
+        let session_id = server.register_user(connection_id, alice).await.unwrap();
+        let channel = registry
+            .create_channel(alice, "notes".into(), Visibility::Public)
+            .await
+            .unwrap();
+        let mut messages = Vec::new();
+        for content in ["note one", "note two", "note three"] {
+            messages.push(channel.add_message(alice, content.into()).await.unwrap());
+            // Distinct creation times
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        let (route_server, route_registry) = (server.clone(), registry.clone());
+        let route = warp::path("search")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::query::<SearchQuery>())
+            .and(warp::any().map(move || route_server.clone()))
+            .and(warp::any().map(move || route_registry.clone()))
+            .and_then(handle_search);
+
+        let path = format!(
+            "/search?text=note&after={}&before={}",
+            messages[1].created.timestamp_millis(),
+            messages[2].created.timestamp_millis()
+        );
+        let reply = warp::test::request()
+            .path(&path)
+            .header("authorization", format!("Bearer {}", session_id))
+            .reply(&route)
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        let found: Vec<Message> = serde_json::from_slice(reply.body()).unwrap();
+        let contents: Vec<_> = found.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["note two"]);
+
+        let path = format!(
+            "/search?sender=alice&limit=2&channel_id={}",
+            channel.channel_id()
+        );
+        let reply = warp::test::request()
+            .path(&path)
+            .header("authorization", format!("Bearer {}", session_id))
+            .reply(&route)
+            .await;
+        let found: Vec<Message> = serde_json::from_slice(reply.body()).unwrap();
+        let contents: Vec<_> = found.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["note three", "note two"]);
+
+        let reply = warp::test::request().path(&path).reply(&route).await;
+        assert_eq!(reply.status(), StatusCode::UNAUTHORIZED);
+
+        // The session of another user only searches the channels of that user
+        let (sender, _) = tokio::sync::mpsc::unbounded_channel();
+        let (subscriptions, _) = tokio::sync::mpsc::unbounded_channel();
+        let connection_id = server.connect(sender, subscriptions).await.unwrap();
+        let eve_session = server.register_user(connection_id, eve).await.unwrap();
+        let private = registry
+            .create_channel(alice, "private".into(), Visibility::Private)
+            .await
+            .unwrap();
+        private
+            .add_message(alice, "note four".into())
+            .await
+            .unwrap();
+        let search_as_eve = |path: String| {
+            warp::test::request()
+                .path(&path)
+                .header("authorization", format!("Bearer {}", eve_session))
+                .reply(&route)
+        };
+        let reply = search_as_eve("/search?text=note".into()).await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        let found: Vec<Message> = serde_json::from_slice(reply.body()).unwrap();
+        assert!(found.is_empty());
+        let reply = search_as_eve(format!(
+            "/search?text=note&channel_id={}",
+            channel.channel_id()
+        ))
+        .await;
+        assert_eq!(reply.status(), StatusCode::FORBIDDEN);
+        let reply = search_as_eve(format!(
+            "/search?text=note&channel_id={}",
+            private.channel_id()
+        ))
+        .await;
+        assert_eq!(reply.status(), StatusCode::NOT_FOUND);
+    }
+}
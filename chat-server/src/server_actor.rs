@@ -1,4 +1,7 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::{
     mpsc::{self, UnboundedSender},
     oneshot,
@@ -7,66 +10,507 @@ use uuid::Uuid;
 use warp::{ws::Message as WsMessage, Error as WsError};
 
 use crate::{
+    channel::{new_token, secrets_eq},
     channel_actor::ChannelHandle,
+    config::Config,
     errors::{Error, Result},
+    metrics::metrics,
+    rate_limit::{RateLimit, RateLimits, TokenBucket},
+    telemetry::Traced,
     websocket::ServerMessage,
-    UserId, MAX_MAILBOX_SIZE,
+    UserId, ID,
 };
 
+/// Time after which a typing indicator expires if not renewed.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Minimum interval between two typing broadcasts of the same user in the same channel.
+const TYPING_THROTTLE: Duration = Duration::from_secs(2);
+/// Time a session outlives its connection, a client reconnecting meanwhile can resume the session.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Interval of expiring typing indicators, sessions and rate limiters.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Server implementation as an actor like resource
 /// inspired by https://ryhl.io/blog/actors-with-tokio/
+///
+/// The server state is sharded across several actors, each command is routed by its key:
+/// connection commands by connection id, session commands by session id and user commands by user.
 enum ServerCommand {
     Connect {
+        connection_id: u128,
         sender: UnboundedSender<Result<WsMessage, WsError>>,
-        reply_to: oneshot::Sender<Result<u128>>,
+        subscriptions: UnboundedSender<(ChannelHandle, u64)>,
+        incoming: Option<Incoming>,
+        reply_to: oneshot::Sender<Result>,
     },
-    Disconnect {
+    Post {
         connection_id: u128,
+        post_token: String,
+        message: WsMessage,
         reply_to: oneshot::Sender<Result>,
     },
+    Disconnect {
+        connection_id: u128,
+        reply_to: oneshot::Sender<Result<Option<ID>>>,
+    },
     RegisterUser {
         connection_id: u128,
         user: UserId,
+        // Id of the session to create, owned by the shard of the connection
+        session_id: ID,
+        reply_to: oneshot::Sender<Result<Registration>>,
+    },
+    ClaimUser {
+        user: UserId,
+        session_id: ID,
+        // The claim to replace, the user is claimed only if unclaimed or still claimed by it
+        replacing: Option<ID>,
+        reply_to: oneshot::Sender<Result<Option<ID>>>,
+    },
+    AttachConnection {
+        connection_id: u128,
+        session_id: ID,
+        user: UserId,
+        reply_to: oneshot::Sender<Result<Option<ID>>>,
+    },
+    AttachSession {
+        session_id: ID,
+        connection_id: u128,
+        reply_to: oneshot::Sender<Result<UserId>>,
+    },
+    DetachSession {
+        session_id: ID,
+        connection_id: u128,
         reply_to: oneshot::Sender<Result>,
     },
-    PublishToChannel {
+    GetSessionUser {
+        session_id: ID,
+        reply_to: oneshot::Sender<Result<UserId>>,
+    },
+    PublishToUsers {
         users: Vec<UserId>,
-        message: ServerMessage,
+        message: WsMessage,
+        reply_to: oneshot::Sender<Result>,
+    },
+    SubscribeUsers {
+        users: Vec<UserId>,
+        channel: ChannelHandle,
+        since: u64,
+        reply_to: oneshot::Sender<Result>,
+    },
+    AcquirePostToken {
+        user: UserId,
         reply_to: oneshot::Sender<Result>,
     },
+    RenewTyping {
+        user: UserId,
+        channel_id: ID,
+        reply_to: oneshot::Sender<Result<bool>>,
+    },
+    Typing {
+        user: UserId,
+        channel: ChannelHandle,
+        reply_to: oneshot::Sender<Result>,
+    },
+    Stats {
+        reply_to: oneshot::Sender<Result<ShardStats>>,
+    },
+    MarkUsers {
+        reply_to: oneshot::Sender<Result>,
+    },
+    Ping {
+        reply_to: oneshot::Sender<Result>,
+    },
+    Shutdown {
+        message: WsMessage,
+        reply_to: oneshot::Sender<Result>,
+    },
+}
+
+impl ServerCommand {
+    /// Name of the command in the metrics.
+    fn name(&self) -> &'static str {
+        match self {
+            ServerCommand::Connect { .. } => "connect",
+            ServerCommand::Post { .. } => "post",
+            ServerCommand::Disconnect { .. } => "disconnect",
+            ServerCommand::RegisterUser { .. } => "register_user",
+            ServerCommand::ClaimUser { .. } => "claim_user",
+            ServerCommand::AttachConnection { .. } => "attach_connection",
+            ServerCommand::AttachSession { .. } => "attach_session",
+            ServerCommand::DetachSession { .. } => "detach_session",
+            ServerCommand::GetSessionUser { .. } => "get_session_user",
+            ServerCommand::PublishToUsers { .. } => "publish_to_users",
+            ServerCommand::SubscribeUsers { .. } => "subscribe_users",
+            ServerCommand::AcquirePostToken { .. } => "acquire_post_token",
+            ServerCommand::RenewTyping { .. } => "renew_typing",
+            ServerCommand::Typing { .. } => "typing",
+            ServerCommand::Stats { .. } => "stats",
+            ServerCommand::MarkUsers { .. } => "mark_users",
+            ServerCommand::Ping { .. } => "ping",
+            ServerCommand::Shutdown { .. } => "shutdown",
+        }
+    }
+}
+
+/// Receiver of the messages a fallback client posts over HTTP.
+struct Incoming {
+    sender: UnboundedSender<Result<WsMessage, WsError>>,
+    // Secret authenticating the posts, the connection id alone is not a credential (it is logged)
+    post_token: String,
+}
+
+/// State of a single shard.
+struct ShardStats {
+    connections: usize,
+    users: Vec<UserId>,
+}
+
+/// Summary of the state of all the server shards.
+pub struct ServerStats {
+    /// Open connections
+    pub connections: usize,
+    /// Distinct users with an open connection
+    pub users: usize,
+    /// Commands waiting in the mailboxes of the shards
+    pub mailbox_depth: usize,
+}
+
+/// Outcome of registering a user to a connection.
+struct Registration {
+    session_id: ID,
+    // The session of the previous user of the connection
+    replaced: Option<ID>,
+}
+
+/// A user currently typing in a channel.
+struct Typing {
+    expires: Instant,
+    last_broadcast: Instant,
+    // The channel to notify when the indicator expires
+    channel: ChannelHandle,
+}
+
+/// Session of a user, it survives reconnections for [`SESSION_TIMEOUT`].
+struct Session {
+    user: UserId,
+    connection_id: Option<u128>,
+    // Set when the connection closes
+    expires: Option<Instant>,
 }
 
 struct ServerActor {
-    receiver: mpsc::Receiver<ServerCommand>,
+    receiver: mpsc::Receiver<Traced<ServerCommand>>,
     // Internal state
 
     // Maps connection_id -> websocket sender
     connections: HashMap<u128, mpsc::UnboundedSender<Result<WsMessage, WsError>>>,
 
+    // Maps connection_id -> sender of the channels the connection should subscribe to
+    subscriptions: HashMap<u128, mpsc::UnboundedSender<(ChannelHandle, u64)>>,
+
+    // Maps connection_id -> receiver of the messages the client posts over HTTP (fallback transports only)
+    incoming: HashMap<u128, Incoming>,
+
     // Maps connection -> user
     users_inverse: HashMap<u128, UserId>,
 
-    // Maps user  -> many  connection_id
+    // Maps user  -> many  connection_id (only the connections of this shard)
     users: HashMap<UserId, HashSet<u128>>,
+
+    // Maps user -> rate limiter of the messages posted by the user
+    user_rate_limiters: HashMap<UserId, TokenBucket>,
+    user_rate_limit: RateLimit,
+
+    // Maps (channel, user) -> typing indicator (not persisted)
+    typing: HashMap<(ID, UserId), Typing>,
+
+    // Maps session_id -> session
+    sessions: HashMap<ID, Session>,
+
+    // Maps connection_id -> session_id
+    connection_sessions: HashMap<u128, ID>,
+
+    // Maps user -> session holding the user (owned by the shard of the user)
+    claims: HashMap<UserId, ID>,
+
+    // Set on shutdown, new connections are refused
+    shutting_down: bool,
+    // Replied once all the connections of the shard are closed
+    drained: Option<oneshot::Sender<Result>>,
 }
 
 impl ServerActor {
-    fn new(receiver: mpsc::Receiver<ServerCommand>) -> Self {
+    fn new(receiver: mpsc::Receiver<Traced<ServerCommand>>, user_rate_limit: RateLimit) -> Self {
         ServerActor {
             receiver,
             connections: HashMap::default(),
+            subscriptions: HashMap::default(),
+            incoming: HashMap::default(),
             users_inverse: HashMap::default(),
             users: HashMap::default(),
+            user_rate_limiters: HashMap::default(),
+            user_rate_limit,
+            typing: HashMap::default(),
+            sessions: HashMap::default(),
+            connection_sessions: HashMap::default(),
+            claims: HashMap::default(),
+            shutting_down: false,
+            drained: None,
+        }
+    }
+
+    /// Sends `message` to all the connections of `users` (in this shard).
+    /// Note: channel wide messages are published by the channels to their subscribers instead.
+    fn publish(&mut self, users: &[UserId], message: &WsMessage) {
+        for u in users {
+            if let Some(connections) = self.users.get(u) {
+                for connection_id in connections {
+                    if let Some(c) = self.connections.get(connection_id) {
+                        // Fails if the connection is closed meanwhile
+                        if c.send(Ok(message.clone())).is_err() {
+                            metrics().dropped_closed();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the shutdown notice `message` to all the connections of the shard and closes them.
+    /// New connections are refused from now on.
+    fn shutdown(&mut self, message: &WsMessage) {
+        self.shutting_down = true;
+        for c in self.connections.values() {
+            let _ = c.send(Ok(message.clone()));
+            let _ = c.send(Ok(WsMessage::close_with(1001u16, "Server shutdown")));
+        }
+    }
+
+    /// Replies to the pending shutdown once all the connections are closed.
+    fn check_drained(&mut self) {
+        if self.connections.is_empty() {
+            if let Some(reply_to) = self.drained.take() {
+                let _ = reply_to.send(Ok(()));
+            }
+        }
+    }
+
+    /// Asks all the connections of `users` to subscribe to `channel` catching up from the sequence number `since`.
+    fn subscribe_users(&mut self, users: &[UserId], channel: &ChannelHandle, since: u64) {
+        for u in users {
+            if let Some(connections) = self.users.get(u) {
+                for connection_id in connections {
+                    if let Some(c) = self.subscriptions.get(connection_id) {
+                        let _ = c.send((channel.clone(), since));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Associates the connection with `user` (replacing any previous user of the connection).
+    fn register(&mut self, connection_id: u128, user: UserId) {
+        self.unregister(connection_id);
+        self.users_inverse.insert(connection_id, user);
+        self.users.entry(user).or_default().insert(connection_id);
+    }
+
+    fn unregister(&mut self, connection_id: u128) {
+        if let Some(user) = self.users_inverse.remove(&connection_id) {
+            if let Some(connections) = self.users.get_mut(&user) {
+                connections.remove(&connection_id);
+                if connections.is_empty() {
+                    self.users.remove(&user);
+                }
+            }
+        }
+    }
+
+    /// Registers `user` to the connection along with a new session `session_id`.
+    fn register_user(&mut self, connection_id: u128, user: UserId, session_id: ID) -> Registration {
+        self.sessions.insert(
+            session_id,
+            Session {
+                user,
+                connection_id: Some(connection_id),
+                expires: None,
+            },
+        );
+        let replaced = self.attach_connection(connection_id, session_id, user);
+        Registration {
+            session_id,
+            replaced,
+        }
+    }
+
+    /// Claims `user` for the session `session_id` unless the user is claimed by another session
+    /// than `replacing`, returns the session holding the claim in that case.
+    fn claim_user(&mut self, user: UserId, session_id: ID, replacing: Option<ID>) -> Option<ID> {
+        match self.claims.get(&user) {
+            Some(current) if Some(*current) != replacing => Some(*current),
+            _ => {
+                self.claims.insert(user, session_id);
+                None
+            }
+        }
+    }
+
+    /// Associates the connection with the session of `user`, returns the session it replaces (if any).
+    fn attach_connection(
+        &mut self,
+        connection_id: u128,
+        session_id: ID,
+        user: UserId,
+    ) -> Option<ID> {
+        self.register(connection_id, user);
+        self.connection_sessions
+            .insert(connection_id, session_id)
+            .filter(|replaced| *replaced != session_id)
+    }
+
+    /// Attaches the session to the connection, the session is taken over from any previous connection.
+    fn attach_session(&mut self, session_id: ID, connection_id: u128) -> Result<UserId> {
+        let session = self
+            .sessions
+            .get_mut(&session_id)
+            .ok_or(Error::SessionNotFound)?;
+        session.connection_id = Some(connection_id);
+        session.expires = None;
+        Ok(session.user)
+    }
+
+    /// Detaches the session from the connection, the session expires unless resumed in time.
+    /// Note: sessions already taken over by another connection are left intact.
+    fn detach_session(&mut self, session_id: ID, connection_id: u128, now: Instant) {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            if session.connection_id == Some(connection_id) {
+                session.connection_id = None;
+                session.expires = Some(now + SESSION_TIMEOUT);
+            }
+        }
+    }
+
+    /// Marks the users held by the shard so that their names stay interned (see [`crate::user::collect_unused`]).
+    fn mark_users(&self) {
+        let sessions = self.sessions.values().map(|session| &session.user);
+        let typing = self.typing.keys().map(|(_, user)| user);
+        self.users
+            .keys()
+            .chain(self.claims.keys())
+            .chain(self.user_rate_limiters.keys())
+            .chain(sessions)
+            .chain(typing)
+            .for_each(UserId::mark);
+    }
+
+    fn expire_sessions(&mut self, now: Instant) {
+        self.sessions
+            .retain(|_, session| !matches!(session.expires, Some(expires) if expires <= now));
+    }
+
+    /// Renews the typing indicator of `user` in the channel `channel_id`.
+    /// Returns `false` if the indicator is published and throttled (nothing more to do),
+    /// `true` if it must be published (see [`ServerActor::typing`]).
+    fn renew_typing(&mut self, user: UserId, channel_id: ID) -> bool {
+        let now = Instant::now();
+        match self.typing.get_mut(&(channel_id, user)) {
+            Some(typing) => {
+                typing.expires = now + TYPING_TIMEOUT;
+                now.saturating_duration_since(typing.last_broadcast) >= TYPING_THROTTLE
+            }
+            None => true,
+        }
+    }
+
+    /// Starts (or renews) the typing indicator of `user`.
+    /// The indicator is published to the channel at most once per [`TYPING_THROTTLE`].
+    fn typing(&mut self, user: UserId, channel: ChannelHandle) -> Result {
+        let channel_id = channel.channel_id();
+
+        let now = Instant::now();
+        let key = (channel_id, user);
+        if let Some(typing) = self.typing.get_mut(&key) {
+            typing.expires = now + TYPING_TIMEOUT;
+            if now.saturating_duration_since(typing.last_broadcast) < TYPING_THROTTLE {
+                return Ok(());
+            }
+        }
+
+        let message = ServerMessage::UserTyping {
+            channel_id,
+            user,
+            typing: true,
+        };
+        channel.publish(message)?;
+        self.typing.insert(
+            key,
+            Typing {
+                expires: now + TYPING_TIMEOUT,
+                last_broadcast: now,
+                channel,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes the expired typing indicators notifying the channels.
+    fn expire_typing(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .typing
+            .iter()
+            .filter(|(_, typing)| typing.expires <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            if let Some(typing) = self.typing.remove(&key) {
+                let (channel_id, user) = key;
+                let message = ServerMessage::UserTyping {
+                    channel_id,
+                    user,
+                    typing: false,
+                };
+                let _ = typing.channel.publish(message);
+            }
         }
     }
 
     fn handle_message(&mut self, msg: ServerCommand) {
         match msg {
-            ServerCommand::Connect { reply_to, sender } => {
-                let connection_id = Uuid::new_v4().as_u128();
+            ServerCommand::Connect {
+                connection_id,
+                sender,
+                subscriptions,
+                incoming,
+                reply_to,
+            } => {
+                if self.shutting_down {
+                    let _ = reply_to.send(Err(Error::ShuttingDown));
+                    return;
+                }
                 self.connections.insert(connection_id, sender);
+                self.subscriptions.insert(connection_id, subscriptions);
+                if let Some(incoming) = incoming {
+                    self.incoming.insert(connection_id, incoming);
+                }
 
-                let _ = reply_to.send(Ok(connection_id));
+                let _ = reply_to.send(Ok(()));
+            }
+            ServerCommand::Post {
+                connection_id,
+                post_token,
+                message,
+                reply_to,
+            } => {
+                // A wrong token is not told apart from an unknown connection
+                let res = match self.incoming.get(&connection_id) {
+                    Some(incoming) if secrets_eq(&incoming.post_token, &post_token) => incoming
+                        .sender
+                        .send(Ok(message))
+                        .map_err(|_| Error::ConnectionNotFound),
+                    _ => Err(Error::ConnectionNotFound),
+                };
+                let _ = reply_to.send(res);
             }
 
             ServerCommand::Disconnect {
@@ -74,85 +518,276 @@ impl ServerActor {
                 reply_to,
             } => {
                 self.connections.remove(&connection_id);
-                if let Some(user_id) = self.users_inverse.remove(&connection_id) {
-                    self.users.remove(&user_id);
-                }
+                self.subscriptions.remove(&connection_id);
+                self.incoming.remove(&connection_id);
+                self.unregister(connection_id);
+                let session_id = self.connection_sessions.remove(&connection_id);
+                self.check_drained();
 
-                let _ = reply_to.send(Ok(()));
+                let _ = reply_to.send(Ok(session_id));
             }
             ServerCommand::RegisterUser {
                 connection_id,
                 user,
+                session_id,
                 reply_to,
             } => {
-                self.users_inverse.insert(connection_id, user.clone());
-                if let Some(connections) = self.users.get_mut(&user) {
-                    connections.insert(connection_id);
-                } else {
-                    let mut connections = HashSet::new();
-                    connections.insert(connection_id);
-                    self.users.insert(user.clone(), connections);
-                }
-
+                let registration = self.register_user(connection_id, user, session_id);
+                let _ = reply_to.send(Ok(registration));
+            }
+            ServerCommand::ClaimUser {
+                user,
+                session_id,
+                replacing,
+                reply_to,
+            } => {
+                let claimed = self.claim_user(user, session_id, replacing);
+                let _ = reply_to.send(Ok(claimed));
+            }
+            ServerCommand::AttachConnection {
+                connection_id,
+                session_id,
+                user,
+                reply_to,
+            } => {
+                let replaced = self.attach_connection(connection_id, session_id, user);
+                let _ = reply_to.send(Ok(replaced));
+            }
+            ServerCommand::AttachSession {
+                session_id,
+                connection_id,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.attach_session(session_id, connection_id));
+            }
+            ServerCommand::DetachSession {
+                session_id,
+                connection_id,
+                reply_to,
+            } => {
+                self.detach_session(session_id, connection_id, Instant::now());
                 let _ = reply_to.send(Ok(()));
             }
-            ServerCommand::PublishToChannel {
+            ServerCommand::GetSessionUser {
+                session_id,
+                reply_to,
+            } => {
+                let user = self
+                    .sessions
+                    .get(&session_id)
+                    .map(|session| session.user)
+                    .ok_or(Error::SessionNotFound);
+                let _ = reply_to.send(user);
+            }
+            ServerCommand::PublishToUsers {
                 users,
                 message,
                 reply_to,
             } => {
-                let message = match serde_json::to_string(&message) {
-                    Err(err) => {
-                        let _ = reply_to.send(Err(Error::Json(err)));
-                        return;
-                    }
-                    Ok(m) => m,
-                };
-
-                let message = WsMessage::text(message);
-
-                for u in users {
-                    if let Some(connections) = self.users.get(&u) {
-                        for connection_id in connections {
-                            if let Some(c) = self.connections.get(connection_id) {
-                                // TODO handle send error this happens if the receiver of the websocket connection is closed
-                                // That is the connection is closed (we should at list log this error)
-                                let _ = c.send(Ok(message.clone()));
-                            }
-                        }
-                    }
-                }
+                self.publish(&users, &message);
+                let _ = reply_to.send(Ok(()));
+            }
+            ServerCommand::SubscribeUsers {
+                users,
+                channel,
+                since,
+                reply_to,
+            } => {
+                self.subscribe_users(&users, &channel, since);
                 let _ = reply_to.send(Ok(()));
             }
+            ServerCommand::AcquirePostToken { user, reply_to } => {
+                let user_rate_limit = self.user_rate_limit;
+                let res = self
+                    .user_rate_limiters
+                    .entry(user)
+                    .or_insert_with(|| TokenBucket::new(user_rate_limit))
+                    .try_acquire(Instant::now())
+                    .map_err(Error::RateLimited);
+                let _ = reply_to.send(res);
+            }
+            ServerCommand::RenewTyping {
+                user,
+                channel_id,
+                reply_to,
+            } => {
+                let _ = reply_to.send(Ok(self.renew_typing(user, channel_id)));
+            }
+            ServerCommand::Typing {
+                user,
+                channel,
+                reply_to,
+            } => {
+                let _ = reply_to.send(self.typing(user, channel));
+            }
+            ServerCommand::Stats { reply_to } => {
+                let _ = reply_to.send(Ok(ShardStats {
+                    connections: self.connections.len(),
+                    users: self.users.keys().cloned().collect(),
+                }));
+            }
+            ServerCommand::MarkUsers { reply_to } => {
+                self.mark_users();
+                let _ = reply_to.send(Ok(()));
+            }
+            ServerCommand::Ping { reply_to } => {
+                let _ = reply_to.send(Ok(()));
+            }
+            ServerCommand::Shutdown { message, reply_to } => {
+                self.shutdown(&message);
+                self.drained = Some(reply_to);
+                self.check_drained();
+            }
         }
     }
 }
 
 async fn run(mut actor: ServerActor) {
-    while let Some(msg) = actor.receiver.recv().await {
-        actor.handle_message(msg);
+    let mut housekeeping = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+    loop {
+        tokio::select! {
+            msg = actor.receiver.recv() => match msg {
+                Some(Traced { command, span, sent }) => {
+                    let name = command.name();
+                    span.in_scope(|| actor.handle_message(command));
+                    metrics().observe_request("server", name, sent.elapsed());
+                }
+                None => break,
+            },
+            _ = housekeeping.tick() => {
+                let now = Instant::now();
+                actor.expire_typing(now);
+                actor.expire_sessions(now);
+                // Forget the limiters that have recovered (a reconnection would not gain anything)
+                actor
+                    .user_rate_limiters
+                    .retain(|_, limiter| !limiter.is_full(now));
+            }
+        }
     }
 }
+
+/// Handle of the sharded [`ServerActor`]
+/// Provides the public interface of the actors, routing each call to the shard owning its state.
 #[derive(Clone)]
 pub struct ServerHandle {
-    sender: mpsc::Sender<ServerCommand>,
+    shards: Vec<mpsc::Sender<Traced<ServerCommand>>>,
+    config: Arc<Config>,
 }
 impl ServerHandle {
     pub fn new() -> Self {
-        let (sender, receiver) = mpsc::channel(MAX_MAILBOX_SIZE);
-        let actor = ServerActor::new(receiver);
-        tokio::spawn(run(actor));
-        Self { sender }
+        Self::with_config(Arc::new(Config::default()))
+    }
+
+    /// Spawns `config.shards` server actors (at least one).
+    pub fn with_config(config: Arc<Config>) -> Self {
+        let shards = (0..config.shards.max(1))
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel(config.mailbox_size);
+                let actor = ServerActor::new(receiver, config.rate_limits.user);
+                tokio::spawn(run(actor));
+                sender
+            })
+            .collect();
+        Self { shards, config }
+    }
+
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.config.rate_limits
     }
 
+    /// The shard owning the state of `key`.
+    fn shard(&self, key: impl Hash) -> &mpsc::Sender<Traced<ServerCommand>> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn shard_index(&self, key: impl Hash) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    /// A new session id owned by the same shard as the connection,
+    /// so that the shard registers the user and creates the session in a single step.
+    fn new_session_id(&self, connection_id: u128) -> ID {
+        let shard = self.shard_index(connection_id);
+        loop {
+            let session_id = crate::new_id();
+            if self.shard_index(session_id) == shard {
+                return session_id;
+            }
+        }
+    }
+
+    /// Registers a new connection, `subscriptions` receives the channels the connection should subscribe to
+    /// along with the sequence number of the last message the connection does not need.
     pub async fn connect(
         &self,
         sender: mpsc::UnboundedSender<Result<WsMessage, WsError>>,
+        subscriptions: mpsc::UnboundedSender<(ChannelHandle, u64)>,
+    ) -> Result<u128> {
+        self.connect_with(sender, subscriptions, None).await
+    }
+
+    /// Registers a new connection of a client that posts its messages over HTTP (see [`ServerHandle::post`]),
+    /// `incoming` receives the posted messages.
+    /// Returns the connection id along with the secret token the client authenticates its posts with.
+    pub async fn connect_fallback(
+        &self,
+        sender: mpsc::UnboundedSender<Result<WsMessage, WsError>>,
+        subscriptions: mpsc::UnboundedSender<(ChannelHandle, u64)>,
+        incoming: mpsc::UnboundedSender<Result<WsMessage, WsError>>,
+    ) -> Result<(u128, String)> {
+        let post_token = new_token();
+        let incoming = Incoming {
+            sender: incoming,
+            post_token: post_token.clone(),
+        };
+        let connection_id = self
+            .connect_with(sender, subscriptions, Some(incoming))
+            .await?;
+        Ok((connection_id, post_token))
+    }
+
+    async fn connect_with(
+        &self,
+        sender: mpsc::UnboundedSender<Result<WsMessage, WsError>>,
+        subscriptions: mpsc::UnboundedSender<(ChannelHandle, u64)>,
+        incoming: Option<Incoming>,
     ) -> Result<u128> {
+        let connection_id = Uuid::new_v4().as_u128();
         let (reply_to, rx) = oneshot::channel();
-        let msg = ServerCommand::Connect { sender, reply_to };
+        let msg = ServerCommand::Connect {
+            connection_id,
+            sender,
+            subscriptions,
+            incoming,
+            reply_to,
+        };
 
-        let _ = self.sender.send(msg).await;
+        let _ = self.shard(connection_id).send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        Ok(connection_id)
+    }
+
+    /// Hands a message posted by the client over HTTP to its fallback connection.
+    /// Fails with [`Error::ConnectionNotFound`] unless the connection is open, was opened by [`ServerHandle::connect_fallback`]
+    /// and `post_token` is the one returned by it.
+    pub async fn post(
+        &self,
+        connection_id: u128,
+        post_token: String,
+        message: WsMessage,
+    ) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ServerCommand::Post {
+            connection_id,
+            post_token,
+            message,
+            reply_to,
+        };
+
+        let _ = self.shard(connection_id).send(Traced::new(msg)).await;
         rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
     }
 
@@ -163,37 +798,484 @@ impl ServerHandle {
             reply_to,
         };
 
-        let _ = self.sender.send(msg).await;
-        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+        let _ = self.shard(connection_id).send(Traced::new(msg)).await;
+        let session_id = rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        if let Some(session_id) = session_id {
+            self.detach_session(session_id, connection_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Registers `user` to the connection and returns the id of the session the client can resume after reconnecting.
+    /// Fails with [`Error::UserTaken`] if the session of another connection holds `user`
+    /// (the client resumes that session instead, see [`ServerHandle::resume_session`]).
+    pub async fn register_user(&self, connection_id: u128, user: UserId) -> Result<ID> {
+        let session_id = self.new_session_id(connection_id);
+        self.claim_user(user, session_id, false).await?;
+        self.attach_user(connection_id, user, session_id).await
+    }
+
+    /// Registers the authenticated `user` (a bot) to the connection and returns the id of its session,
+    /// the user is taken over from the session holding it (if any).
+    pub async fn register_authenticated_user(
+        &self,
+        connection_id: u128,
+        user: UserId,
+    ) -> Result<ID> {
+        let session_id = self.new_session_id(connection_id);
+        self.claim_user(user, session_id, true).await?;
+        self.attach_user(connection_id, user, session_id).await
+    }
+
+    /// Claims `user` for the session `session_id`, the claim of an expired session is taken over
+    /// as well as any claim if `force` is set.
+    async fn claim_user(&self, user: UserId, session_id: ID, force: bool) -> Result {
+        let mut replacing = None;
+        loop {
+            let (reply_to, rx) = oneshot::channel();
+            let msg = ServerCommand::ClaimUser {
+                user,
+                session_id,
+                replacing,
+                reply_to,
+            };
+
+            let _ = self.shard(user).send(Traced::new(msg)).await;
+            let current = match rx.await.map_err(|_| Error::ActorUnexpectedTermination)?? {
+                Some(current) => current,
+                None => return Ok(()),
+            };
+            // Claimed meanwhile by another session than the expired one
+            if replacing.is_some() && !force {
+                return Err(Error::UserTaken);
+            }
+            if !force && self.session_user(current).await.is_ok() {
+                return Err(Error::UserTaken);
+            }
+            replacing = Some(current);
+        }
     }
 
-    // TODO remove this for now we registrer UserId manually in the future pass the UseId to the connection
-    pub async fn register_user(&self, connection_id: u128, user: UserId) -> Result {
+    /// Creates the session `session_id` of `user` along with the registration of the connection.
+    async fn attach_user(&self, connection_id: u128, user: UserId, session_id: ID) -> Result<ID> {
         let (reply_to, rx) = oneshot::channel();
         let msg = ServerCommand::RegisterUser {
             connection_id,
             user,
+            session_id,
+            reply_to,
+        };
+
+        let _ = self.shard(connection_id).send(Traced::new(msg)).await;
+        let registration = rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        if let Some(replaced) = registration.replaced {
+            self.detach_session(replaced, connection_id).await?;
+        }
+        Ok(registration.session_id)
+    }
+
+    /// Attaches the connection to the session with `session_id` and returns the session user.
+    pub async fn resume_session(&self, connection_id: u128, session_id: ID) -> Result<UserId> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ServerCommand::AttachSession {
+            session_id,
+            connection_id,
+            reply_to,
+        };
+
+        let _ = self.shard(session_id).send(Traced::new(msg)).await;
+        let user = rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ServerCommand::AttachConnection {
+            connection_id,
+            session_id,
+            user,
+            reply_to,
+        };
+
+        let _ = self.shard(connection_id).send(Traced::new(msg)).await;
+        let replaced = rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        if let Some(replaced) = replaced {
+            self.detach_session(replaced, connection_id).await?;
+        }
+        Ok(user)
+    }
+
+    /// Gets the user of the session with `session_id`, authenticates the HTTP requests of the clients.
+    pub async fn session_user(&self, session_id: ID) -> Result<UserId> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ServerCommand::GetSessionUser {
+            session_id,
+            reply_to,
+        };
+
+        let _ = self.shard(session_id).send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Gets the user of the session in the `authorization` header of an HTTP request (`Bearer <session id>`).
+    pub async fn authenticate(&self, authorization: Option<&str>) -> Result<UserId> {
+        let session_id = authorization
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .and_then(|session_id| session_id.trim().parse::<ID>().ok())
+            .ok_or(Error::SessionNotFound)?;
+        self.session_user(session_id).await
+    }
+
+    async fn detach_session(&self, session_id: ID, connection_id: u128) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ServerCommand::DetachSession {
+            session_id,
+            connection_id,
+            reply_to,
+        };
+
+        let _ = self.shard(session_id).send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Takes a token from the rate limiter of the messages posted by `user`.
+    /// Fails with [`Error::RateLimited`] if the user posts too fast.
+    pub async fn acquire_post_token(&self, user: UserId) -> Result {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ServerCommand::AcquirePostToken { user, reply_to };
+
+        let _ = self.shard(user).send(Traced::new(msg)).await;
+        rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
+    }
+
+    /// Renews the typing indicator of `user` in the channel `channel_id`,
+    /// `true` if it must be published with [`ServerHandle::typing`] (not published or not throttled anymore).
+    pub async fn renew_typing(&self, user: UserId, channel_id: ID) -> Result<bool> {
+        let (reply_to, rx) = oneshot::channel();
+        let msg = ServerCommand::RenewTyping {
+            user,
+            channel_id,
             reply_to,
         };
 
-        let _ = self.sender.send(msg).await;
+        let _ = self.shard(user).send(Traced::new(msg)).await;
         rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
     }
 
-    pub async fn publish_to_channel(
-        &self,
-        channel: &ChannelHandle,
-        message: ServerMessage,
-    ) -> Result {
+    /// Notifies the subscribers of `channel` that `user` is typing.
+    /// The indicator expires automatically unless renewed by another call.
+    pub async fn typing(&self, user: UserId, channel: ChannelHandle) -> Result {
         let (reply_to, rx) = oneshot::channel();
-        let users: Vec<UserId> = channel.get_channel_users().await?;
-        let msg = ServerCommand::PublishToChannel {
-            users,
-            message,
+        let msg = ServerCommand::Typing {
+            user,
+            channel,
             reply_to,
         };
 
-        let _ = self.sender.send(msg).await;
+        let _ = self.shard(user).send(Traced::new(msg)).await;
         rx.await.map_err(|_| Error::ActorUnexpectedTermination)?
     }
+
+    /// Collects the state of all the shards.
+    pub async fn stats(&self) -> Result<ServerStats> {
+        let mailbox_depth = self
+            .shards
+            .iter()
+            .map(|shard| self.config.mailbox_size - shard.capacity())
+            .sum();
+        let mut replies = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            let (reply_to, rx) = oneshot::channel();
+            let msg = ServerCommand::Stats { reply_to };
+
+            let _ = shard.send(Traced::new(msg)).await;
+            replies.push(rx);
+        }
+        let mut connections = 0;
+        // The connections of a user may live in any shard
+        let mut users = HashSet::new();
+        for rx in replies {
+            let stats = rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+            connections += stats.connections;
+            users.extend(stats.users);
+        }
+        Ok(ServerStats {
+            connections,
+            users: users.len(),
+            mailbox_depth,
+        })
+    }
+
+    /// Marks the users held by all the shards (see [`crate::user::collect_unused`]).
+    pub async fn mark_users(&self) -> Result {
+        let mut replies = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            let (reply_to, rx) = oneshot::channel();
+            let msg = ServerCommand::MarkUsers { reply_to };
+
+            let _ = shard.send(Traced::new(msg)).await;
+            replies.push(rx);
+        }
+        for rx in replies {
+            rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        }
+        Ok(())
+    }
+
+    /// Round-trips a probe through every shard, fails if any of them is not running.
+    pub async fn ping(&self) -> Result {
+        let mut replies = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            let (reply_to, rx) = oneshot::channel();
+            let msg = ServerCommand::Ping { reply_to };
+
+            let _ = shard.send(Traced::new(msg)).await;
+            replies.push(rx);
+        }
+        for rx in replies {
+            rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        }
+        Ok(())
+    }
+
+    /// Notifies all the connections with [`ServerMessage::ServerShutdown`] and closes them.
+    /// Completes once every connection has disconnected, new connections are refused meanwhile.
+    pub async fn shutdown(&self) -> Result {
+        let message = WsMessage::text(serde_json::to_string(&ServerMessage::ServerShutdown)?);
+        let mut replies = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            let (reply_to, rx) = oneshot::channel();
+            let msg = ServerCommand::Shutdown {
+                message: message.clone(),
+                reply_to,
+            };
+
+            let _ = shard.send(Traced::new(msg)).await;
+            replies.push(rx);
+        }
+        for rx in replies {
+            rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        }
+        Ok(())
+    }
+
+    /// Sends `message` to all the connections of the given `users`.
+    pub async fn publish_to_users(&self, users: Vec<UserId>, message: ServerMessage) -> Result {
+        // The connections of a user may live in any shard
+        let message = WsMessage::text(serde_json::to_string(&message)?);
+        let mut replies = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            let (reply_to, rx) = oneshot::channel();
+            let msg = ServerCommand::PublishToUsers {
+                users: users.clone(),
+                message: message.clone(),
+                reply_to,
+            };
+
+            let _ = shard.send(Traced::new(msg)).await;
+            replies.push(rx);
+        }
+        for rx in replies {
+            rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        }
+        Ok(())
+    }
+
+    /// Asks all the connections of `users` to subscribe to `channel` (e.g. after the users joined it).
+    /// The connections catch up with the chat messages after the sequence number `since`.
+    pub async fn subscribe_users(
+        &self,
+        users: Vec<UserId>,
+        channel: ChannelHandle,
+        since: u64,
+    ) -> Result {
+        // The connections of a user may live in any shard
+        let mut replies = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            let (reply_to, rx) = oneshot::channel();
+            let msg = ServerCommand::SubscribeUsers {
+                users: users.clone(),
+                channel: channel.clone(),
+                since,
+                reply_to,
+            };
+
+            let _ = shard.send(Traced::new(msg)).await;
+            replies.push(rx);
+        }
+        for rx in replies {
+            rx.await.map_err(|_| Error::ActorUnexpectedTermination)??;
+        }
+        Ok(())
+    }
+}
+
+impl Default for ServerHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn test_typing_throttle_and_expiration() {
+        let config = Config::default();
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut actor = ServerActor::new(receiver, config.rate_limits.user);
+        let (channel, mut subscription) = ChannelHandle::detached(crate::new_id(), &config);
+        let channel_id = channel.channel_id();
+        let mut next = move || {
+            subscription
+                .recv()
+                .now_or_never()
+                .and_then(|publication| publication.ok())
+        };
+
+        assert!(actor.renew_typing("alice".into(), channel_id));
+        actor.typing("alice".into(), channel.clone()).unwrap();
+        assert!(!actor.renew_typing("alice".into(), channel_id));
+        actor.typing("alice".into(), channel).unwrap();
+        assert!(next().is_some());
+        assert!(next().is_none(), "second indicator is throttled");
+        actor
+            .typing
+            .get_mut(&(channel_id, "alice".into()))
+            .unwrap()
+            .last_broadcast -= TYPING_THROTTLE;
+        assert!(actor.renew_typing("alice".into(), channel_id));
+
+        actor.expire_typing(Instant::now());
+        assert!(next().is_none());
+        actor.expire_typing(Instant::now() + TYPING_TIMEOUT);
+        let stopped = next().unwrap();
+        assert!(stopped.text.contains("\"typing\":false"));
+        assert!(actor.typing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sessions_across_shards() {
+        let server = ServerHandle::with_config(Arc::new(Config {
+            shards: 4,
+            ..Config::default()
+        }));
+        let connect = || {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let (subscriptions, _) = mpsc::unbounded_channel();
+            let server = server.clone();
+            async move {
+                let connection_id = server.connect(sender, subscriptions).await.unwrap();
+                (connection_id, receiver)
+            }
+        };
+        let (first, _first_rx) = connect().await;
+        let (second, mut second_rx) = connect().await;
+
+        let session_id = server.register_user(first, "alice".into()).await.unwrap();
+        // Created by the shard of the connection along with the registration
+        assert_eq!(server.shard_index(session_id), server.shard_index(first));
+        // Held by the session of the first connection, even once disconnected
+        assert!(matches!(
+            server.register_user(second, "alice".into()).await,
+            Err(Error::UserTaken)
+        ));
+        server.disconnect(first).await.unwrap();
+        assert!(matches!(
+            server.register_user(second, "alice".into()).await,
+            Err(Error::UserTaken)
+        ));
+        assert!(matches!(
+            server.resume_session(second, crate::new_id()).await,
+            Err(Error::SessionNotFound)
+        ));
+        let user = server.resume_session(second, session_id).await.unwrap();
+        assert_eq!(&*user.name(), "alice");
+
+        let invitation = ServerMessage::Invitation {
+            channel_id: crate::new_id(),
+            from: "bob".into(),
+        };
+        server
+            .publish_to_users(vec![user], invitation)
+            .await
+            .unwrap();
+        assert!(second_rx.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_connections() {
+        let server = ServerHandle::with_config(Arc::new(Config {
+            shards: 1,
+            ..Config::default()
+        }));
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let (subscriptions, _subscriptions) = mpsc::unbounded_channel();
+        let connection_id = server.connect(sender, subscriptions).await.unwrap();
+
+        let mut shutdown = tokio::spawn({
+            let server = server.clone();
+            async move { server.shutdown().await }
+        });
+        let notice = receiver.recv().await.unwrap().unwrap();
+        assert!(notice.to_str().unwrap().contains("ServerShutdown"));
+        assert!(receiver.recv().await.unwrap().unwrap().is_close());
+
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let (subscriptions, _subscriptions) = mpsc::unbounded_channel();
+        assert!(matches!(
+            server.connect(sender, subscriptions).await,
+            Err(Error::ShuttingDown)
+        ));
+        assert!(
+            (&mut shutdown).now_or_never().is_none(),
+            "waits for the open connection"
+        );
+
+        server.disconnect(connection_id).await.unwrap();
+        shutdown.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_post_to_fallback_connection() {
+        let server = ServerHandle::with_config(Arc::new(Config {
+            shards: 2,
+            ..Config::default()
+        }));
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let (subscriptions, _subscriptions) = mpsc::unbounded_channel();
+        let websocket = server.connect(sender, subscriptions).await.unwrap();
+        assert!(matches!(
+            server
+                .post(websocket, String::new(), WsMessage::text("{}"))
+                .await,
+            Err(Error::ConnectionNotFound)
+        ));
+
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let (subscriptions, _subscriptions) = mpsc::unbounded_channel();
+        let (incoming, mut incoming_rx) = mpsc::unbounded_channel();
+        let (fallback, post_token) = server
+            .connect_fallback(sender, subscriptions, incoming)
+            .await
+            .unwrap();
+        assert!(matches!(
+            server
+                .post(fallback, "guess".into(), WsMessage::text("{}"))
+                .await,
+            Err(Error::ConnectionNotFound)
+        ));
+        server
+            .post(fallback, post_token.clone(), WsMessage::text("{}"))
+            .await
+            .unwrap();
+        let posted = incoming_rx.recv().await.unwrap().unwrap();
+        assert_eq!(posted.to_str(), Ok("{}"));
+
+        server.disconnect(fallback).await.unwrap();
+        assert!(server
+            .post(fallback, post_token, WsMessage::text("{}"))
+            .await
+            .is_err());
+        assert!(incoming_rx.recv().await.is_none(), "closed on disconnect");
+    }
 }
@@ -0,0 +1,73 @@
+//! Logging and tracing of the server.
+//! The commands sent to the actors carry the span of their sender, the actors handle them within that span.
+//! That way a client message can be followed from the websocket to the persistence and the fan-out.
+use std::time::Instant;
+
+use tracing::{level_filters::LevelFilter, Span};
+
+use crate::config::{Config, LogFormat, LogLevel};
+
+/// An actor command along with the span it was sent from.
+pub(crate) struct Traced<T> {
+    pub command: T,
+    pub span: Span,
+    // When the command was sent (for the request latency metrics)
+    pub sent: Instant,
+}
+
+impl<T> Traced<T> {
+    /// Wraps `command` with the current span.
+    pub fn new(command: T) -> Self {
+        Self {
+            command,
+            span: Span::current(),
+            sent: Instant::now(),
+        }
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Installs the global subscriber writing the logs to stdout in the configured level and format.
+pub fn init(config: &Config) {
+    let subscriber = tracing_subscriber::fmt().with_max_level(LevelFilter::from(config.log_level));
+    match config.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traced_command_keeps_sender_span() {
+        tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+            let span = tracing::info_span!("connection", connection_id = 1);
+            let traced = span.in_scope(|| Traced::new(()));
+            assert!(span.id().is_some());
+            assert_eq!(traced.span.id(), span.id());
+            assert!(Traced::new(()).span.is_none());
+        });
+    }
+
+    #[test]
+    fn test_log_levels() {
+        assert_eq!(LevelFilter::from(LogLevel::Error), LevelFilter::ERROR);
+        assert_eq!(LevelFilter::from(LogLevel::Warn), LevelFilter::WARN);
+        assert_eq!(LevelFilter::from(LogLevel::Info), LevelFilter::INFO);
+        assert_eq!(LevelFilter::from(LogLevel::Debug), LevelFilter::DEBUG);
+        assert_eq!(LevelFilter::from(LogLevel::Trace), LevelFilter::TRACE);
+    }
+}
@@ -0,0 +1,51 @@
+//! Fixtures shared by the tests of the modules.
+use std::sync::Arc;
+
+use crate::{
+    config::Config, registry_actor::RegistryHandle, server_actor::ServerHandle, user::UserId,
+};
+
+/// Configuration of a test storing its data into a temporary directory, removed on drop.
+pub(crate) struct TestEnv {
+    _dir: tempfile::TempDir,
+    pub config: Arc<Config>,
+}
+
+impl TestEnv {
+    pub fn new() -> Self {
+        Self::with(|_| {})
+    }
+
+    /// Test configuration adjusted by `configure`, the data directory aside.
+    pub fn with(configure: impl FnOnce(&mut Config)) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            data_dir: dir.path().into(),
+            ..Config::default()
+        };
+        configure(&mut config);
+        TestEnv {
+            _dir: dir,
+            config: Arc::new(config),
+        }
+    }
+
+    /// Spawns a registry of the channels stored in the test directory (a new one simulates a restart).
+    pub fn registry(&self) -> RegistryHandle {
+        RegistryHandle::with_config(self.config.clone())
+    }
+
+    /// Spawns the server actors.
+    pub fn server(&self) -> ServerHandle {
+        ServerHandle::with_config(self.config.clone())
+    }
+}
+
+/// The users of the tests: alice, bob and eve.
+pub(crate) fn users() -> (UserId, UserId, UserId) {
+    (
+        UserId::from("alice"),
+        UserId::from("bob"),
+        UserId::from("eve"),
+    )
+}
@@ -0,0 +1,265 @@
+//! Thumbnails of the uploaded images, generated in the background by a pool of workers
+//! so that the clients can preview the images without downloading them.
+//! The queue is not persisted, the images left without a thumbnail (e.g. by a restart) are queued
+//! again by [`crate::attachment::BlobStore::recover_thumbnails`].
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Cursor, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use image::{ImageFormat, ImageReader, Limits};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, warn};
+
+use crate::{
+    config::Config,
+    errors::{Error, Result},
+    metrics::metrics,
+};
+
+/// Maximum width and height in pixels of a decoded image, larger images get no thumbnail.
+pub const MAX_IMAGE_DIMENSION: u32 = 16 * 1024;
+
+/// Maximum number of pixels of a decoded image (16 megapixels), larger images get no thumbnail.
+pub const MAX_IMAGE_PIXELS: u64 = 16 * 1024 * 1024;
+
+/// Maximum memory in bytes allocated to decode an image.
+const MAX_ALLOC: u64 = 64 * 1024 * 1024;
+
+/// Generation of the thumbnail of a stored image.
+#[derive(Debug)]
+pub struct Job {
+    /// The image.
+    pub source: PathBuf,
+    /// Written first, then renamed to `target`.
+    pub tmp: PathBuf,
+    pub target: PathBuf,
+}
+
+/// Handle of the workers generating the thumbnails.
+pub struct ThumbnailPool {
+    sender: mpsc::Sender<Job>,
+    // Targets of the jobs queued or running, and of the failed ones (the same content fails again)
+    attempted: Arc<std::sync::Mutex<HashSet<PathBuf>>>,
+}
+
+impl ThumbnailPool {
+    /// Starts `config.attachments.thumbnail_workers` workers.
+    pub fn new(config: &Config) -> Self {
+        let (sender, receiver) = mpsc::channel(config.mailbox_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let attempted = Arc::new(std::sync::Mutex::new(HashSet::new()));
+        for _ in 0..config.attachments.thumbnail_workers {
+            tokio::spawn(run_worker(
+                receiver.clone(),
+                attempted.clone(),
+                config.attachments.thumbnail_size,
+            ));
+        }
+        Self { sender, attempted }
+    }
+
+    /// Queues `job`, waits while the queue is full.
+    /// Returns false if a job with the same target is queued, running or failed.
+    pub async fn enqueue(&self, job: Job) -> bool {
+        let target = job.target.clone();
+        if !self.attempted.lock().unwrap().insert(target.clone()) {
+            return false;
+        }
+        if let Err(err) = self.sender.send(job).await {
+            warn!(job = ?err.0, "Thumbnail workers stopped");
+            self.attempted.lock().unwrap().remove(&target);
+            return false;
+        }
+        true
+    }
+}
+
+async fn run_worker(
+    receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+    attempted: Arc<std::sync::Mutex<HashSet<PathBuf>>>,
+    size: u32,
+) {
+    loop {
+        // The lock is only held while waiting for the next job
+        let job = match receiver.lock().await.recv().await {
+            Some(job) => job,
+            None => return,
+        };
+        let generated = tokio::task::spawn_blocking(move || {
+            let content = std::fs::read(&job.source)?;
+            let thumbnail = generate(&content, size)?;
+            std::fs::write(&job.tmp, thumbnail)?;
+            std::fs::rename(&job.tmp, &job.target)?;
+            Ok::<_, Error>(job.target)
+        })
+        .await;
+        match generated {
+            Ok(Ok(target)) => {
+                debug!(?target, "Thumbnail generated");
+                metrics().thumbnails.with_label_values(&["generated"]).inc();
+                attempted.lock().unwrap().remove(&target);
+            }
+            Ok(Err(err)) => {
+                warn!(%err, "Thumbnail generation failed");
+                metrics().thumbnails.with_label_values(&["failed"]).inc();
+            }
+            Err(err) => {
+                warn!(%err, "Thumbnail worker panicked");
+                metrics().thumbnails.with_label_values(&["failed"]).inc();
+            }
+        }
+    }
+}
+
+fn reader<R: BufRead + Seek>(image: R) -> Result<ImageReader<R>> {
+    let mut reader = ImageReader::new(image).with_guessed_format()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_ALLOC);
+    reader.limits(limits);
+    Ok(reader)
+}
+
+/// Reads the width and height of an image from its header, `None` if the format is not supported.
+pub fn dimensions(content: &[u8]) -> Option<(u32, u32)> {
+    reader(Cursor::new(content)).ok()?.into_dimensions().ok()
+}
+
+/// Reads the width and height of the image in the file at `path`, see [`dimensions`].
+pub fn file_dimensions(path: &Path) -> Option<(u32, u32)> {
+    let file = std::fs::File::open(path).ok()?;
+    reader(BufReader::new(file)).ok()?.into_dimensions().ok()
+}
+
+/// Generates the PNG thumbnail of an image, fitting in a `size` pixels box.
+/// Smaller images are only converted, images of more than [`MAX_IMAGE_PIXELS`] are refused.
+pub fn generate(content: &[u8], size: u32) -> Result<Vec<u8>> {
+    let (width, height) = reader(Cursor::new(content))?.into_dimensions()?;
+    if u64::from(width) * u64::from(height) > MAX_IMAGE_PIXELS {
+        return Err(Error::InvalidAttachment(format!(
+            "image of {}x{} pixels is too large",
+            width, height
+        )));
+    }
+    let image = reader(Cursor::new(content))?.decode()?;
+    let thumbnail = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+    let mut png = Vec::new();
+    thumbnail.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Attachments, registry_actor::RegistryHandle};
+    use image::{DynamicImage, GrayImage, RgbImage};
+    use std::time::Duration;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    #[tokio::test]
+    async fn test_thumbnail_generated_on_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().into(),
+            attachments: Attachments {
+                thumbnail_size: 100,
+                ..Attachments::default()
+            },
+            ..Config::default()
+        });
+        let registry = RegistryHandle::with_config(config);
+        let blobs = registry.blobs();
+
+        let (blob, _) = blobs.store(&png(400, 200), "image/png").await.unwrap();
+        assert_eq!((blob.width, blob.height), (Some(400), Some(200)));
+        let mut thumbnail = None;
+        for _ in 0..100 {
+            if let Ok(content) = blobs.read_thumbnail(&blob.id).await {
+                thumbnail = Some(content);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(dimensions(&thumbnail.unwrap()), Some((100, 50)));
+
+        // Not an image after all
+        let (text, _) = blobs.store(b"not a png", "image/png").await.unwrap();
+        assert_eq!(text.width, None);
+        assert!(matches!(
+            blobs.read_thumbnail(&text.id).await,
+            Err(Error::AttachmentNotFound)
+        ));
+        assert_eq!(
+            dimensions(&generate(&png(30, 60), 100).unwrap()),
+            Some((30, 60))
+        );
+        let mut huge = Vec::new();
+        DynamicImage::ImageLuma8(GrayImage::new(4097, 4097))
+            .write_to(&mut Cursor::new(&mut huge), ImageFormat::Png)
+            .unwrap();
+        assert!(matches!(
+            generate(&huge, 100),
+            Err(Error::InvalidAttachment(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_missing_thumbnails_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().into(),
+            attachments: Attachments {
+                gc_grace_seconds: 0,
+                ..Attachments::default()
+            },
+            ..Config::default()
+        });
+        let wait_for = |path: PathBuf| async move {
+            for _ in 0..100 {
+                if path.exists() {
+                    return true;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            false
+        };
+        let image = png(40, 40);
+        let id = {
+            let registry = RegistryHandle::with_config(config.clone());
+            let (blob, _) = registry.blobs().store(&image, "image/png").await.unwrap();
+            blob.id
+        };
+        let shard = config.blob_dir().join(&id[..2]);
+        let thumbnail = shard.join(format!("{}.thumb", id));
+        // As if the job was lost by a restart
+        assert!(wait_for(thumbnail.clone()).await);
+        std::fs::remove_file(&thumbnail).unwrap();
+
+        let registry = RegistryHandle::with_config(config.clone());
+        let blobs = registry.blobs();
+        assert_eq!(blobs.recover_thumbnails().await.unwrap(), 1);
+        // Already queued or generated
+        assert_eq!(blobs.recover_thumbnails().await.unwrap(), 0);
+        assert!(wait_for(thumbnail.clone()).await);
+
+        // Generated while its image was collected
+        assert_eq!(blobs.collect_garbage().await.unwrap(), 1);
+        let orphan = shard.join(format!("{}.thumb", id));
+        std::fs::write(&orphan, b"png").unwrap();
+        assert_eq!(blobs.collect_garbage().await.unwrap(), 0);
+        assert!(!orphan.exists());
+    }
+}
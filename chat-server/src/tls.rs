@@ -0,0 +1,295 @@
+//! TLS termination of the listener.
+//! The certificate is resolved on every handshake through a [`CertResolver`],
+//! so reloading it (on SIGHUP) only affects the new connections and never drops the established ones.
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::Stream;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio_rustls::rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{debug, error, info, warn};
+use warp::{
+    http::{uri::Authority, StatusCode},
+    path::FullPath,
+    Filter, Rejection, Reply,
+};
+
+use crate::config::TlsConfig;
+use crate::errors::{Error, Result};
+
+/// Time a client is given to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of handshakes in progress, no more connections are accepted meanwhile.
+const MAX_CONCURRENT_HANDSHAKES: usize = 512;
+
+/// Serves the certificate currently loaded from the files of a [`TlsConfig`].
+#[derive(Debug)]
+pub struct CertResolver {
+    tls: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    /// Loads the certificate and the private key of `tls`.
+    pub fn new(tls: TlsConfig) -> Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let current = RwLock::new(load_certified_key(&tls, &provider)?);
+        Ok(Self {
+            tls,
+            provider,
+            current,
+        })
+    }
+
+    /// Reloads the certificate and the private key from their files.
+    /// On error the previous certificate is still served.
+    pub fn reload(&self) -> Result {
+        let key = load_certified_key(&self.tls, &self.provider)?;
+        *self.current.write().unwrap() = key;
+        Ok(())
+    }
+
+    /// The certificate served to the new connections.
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// The rustls configuration of a server using this resolver.
+    pub fn server_config(self: Arc<Self>) -> Result<Arc<ServerConfig>> {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self);
+        // Websockets are only upgraded from HTTP/1.1
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+fn load_certified_key(tls: &TlsConfig, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>> {
+    let certs = rustls_pemfile::certs(&mut open(&tls.cert_path)?)
+        .collect::<io::Result<Vec<CertificateDer<'static>>>>()?;
+    if certs.is_empty() {
+        return Err(Error::Generic(format!(
+            "No certificate in {}",
+            tls.cert_path.display()
+        )));
+    }
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut open(&tls.key_path)?)?
+        .ok_or_else(|| Error::Generic(format!("No private key in {}", tls.key_path.display())))?;
+    Ok(Arc::new(CertifiedKey::from_der(certs, key, provider)?))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    Ok(BufReader::new(File::open(path)?))
+}
+
+/// Reloads the certificate of `resolver` on every SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_hangup(resolver: Arc<CertResolver>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!(%err, "Cannot install the SIGHUP handler, the TLS certificate will not be reloaded");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match resolver.reload() {
+            Ok(()) => info!("TLS certificate reloaded"),
+            Err(err) => error!(%err, "TLS certificate reload failed, keeping the previous one"),
+        }
+    }
+}
+
+/// The TLS connections accepted by `listener`.
+/// Every handshake runs in its own task so that a slow client does not hold back the others,
+/// the connections failing their handshake are dropped.
+/// At most [`MAX_CONCURRENT_HANDSHAKES`] run at once, the pending connections wait in the listener backlog.
+/// The listener is closed once the stream is dropped.
+pub fn incoming(
+    listener: TcpListener,
+    resolver: Arc<CertResolver>,
+) -> Result<impl Stream<Item = io::Result<TlsStream<TcpStream>>>> {
+    let acceptor = TlsAcceptor::from(resolver.server_config()?);
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let handshakes = Arc::new(Semaphore::new(MAX_CONCURRENT_HANDSHAKES));
+    tokio::spawn(async move {
+        loop {
+            let permit = tokio::select! {
+                permit = handshakes.clone().acquire_owned() => match permit {
+                    Ok(permit) => permit,
+                    Err(_) => break, // Never closed
+                },
+                _ = sender.closed() => break,
+            };
+            let (stream, remote) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        // Usually out of file descriptors, give the open connections some time to close
+                        warn!(%err, "Accept error");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+                _ = sender.closed() => break,
+            };
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let handshake =
+                    tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await;
+                drop(permit);
+                match handshake {
+                    Ok(Ok(stream)) => {
+                        let _ = sender.send(Ok(stream));
+                    }
+                    Ok(Err(err)) => debug!(%remote, %err, "TLS handshake failed"),
+                    Err(_) => debug!(%remote, "TLS handshake timed out"),
+                }
+            });
+        }
+    });
+    Ok(UnboundedReceiverStream::new(receiver))
+}
+
+/// Answers every plaintext request with a permanent redirect to the same URL over https on `port`.
+pub fn redirect_to_https(
+    port: u16,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("host")
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(move |host: Option<String>, path: FullPath, query: String| {
+            match https_location(host.as_deref(), port, path.as_str(), &query) {
+                Some(location) => {
+                    warp::reply::with_header(StatusCode::PERMANENT_REDIRECT, "location", location)
+                        .into_response()
+                }
+                None => StatusCode::BAD_REQUEST.into_response(),
+            }
+        })
+}
+
+/// The https URL of a request to `host` (the `Host` header, whose port is replaced by `port`).
+fn https_location(host: Option<&str>, port: u16, path: &str, query: &str) -> Option<String> {
+    let authority: Authority = host?.parse().ok()?;
+    let mut location = match port {
+        443 => format!("https://{}{}", authority.host(), path),
+        _ => format!("https://{}:{}{}", authority.host(), port, path),
+    };
+    if !query.is_empty() {
+        location.push('?');
+        location.push_str(query);
+    }
+    Some(location)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::convert::TryFrom;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    /// Writes a new self-signed certificate for localhost into the files of `tls`.
+    fn write_certificate(tls: &TlsConfig) -> CertificateDer<'static> {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        std::fs::write(&tls.cert_path, generated.cert.pem()).unwrap();
+        std::fs::write(&tls.key_path, generated.key_pair.serialize_pem()).unwrap();
+        generated.cert.der().clone()
+    }
+
+    async fn connect(
+        addr: std::net::SocketAddr,
+        trusted: CertificateDer<'static>,
+    ) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await?;
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_established_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let tls = TlsConfig {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+            plaintext: Default::default(),
+            redirect_bind: ([127, 0, 0, 1], 0).into(),
+        };
+        let first = write_certificate(&tls);
+        let resolver = Arc::new(CertResolver::new(tls.clone()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = Box::pin(incoming(listener, resolver.clone()).unwrap());
+
+        let mut client = connect(addr, first.clone()).await.unwrap();
+        let mut server = incoming.next().await.unwrap().unwrap();
+
+        let second = write_certificate(&tls);
+        resolver.reload().unwrap();
+        assert_eq!(resolver.current().cert[0], second);
+
+        // The established connection still works
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // The new connections get the new certificate
+        assert!(connect(addr, first).await.is_err());
+        connect(addr, second.clone()).await.unwrap();
+        assert!(incoming.next().await.unwrap().is_ok());
+
+        // A broken key file keeps the current certificate
+        std::fs::write(&tls.key_path, b"").unwrap();
+        assert!(resolver.reload().is_err());
+        assert_eq!(resolver.current().cert[0], second);
+    }
+
+    #[test]
+    fn test_https_location() {
+        assert_eq!(
+            https_location(Some("chat.example.com:8080"), 9090, "/chat", "").as_deref(),
+            Some("https://chat.example.com:9090/chat")
+        );
+        assert_eq!(
+            https_location(Some("[::1]"), 443, "/metrics", "a=1").as_deref(),
+            Some("https://[::1]/metrics?a=1")
+        );
+        assert_eq!(https_location(None, 443, "/", ""), None);
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{debug, error};
+
+use crate::{
+    errors::{Error, Result},
+    registry_actor::RegistryHandle,
+    server_actor::ServerHandle,
+};
+
+/// Maximum length of a user name in bytes.
+pub const MAX_USER_NAME_LEN: usize = 64;
+
+/// Interval of freeing the user names no longer used (see [`run_collection`]).
+const COLLECTION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Name resolved by the ids of freed names.
+const STALE_NAME: &str = "<stale>";
+
+/// Identifier of a user, a compact `Copy` handle of the interned user name.
+/// It is serialized as the user name both on the wire and on disk.
+/// Only valid names are interned. The names no longer used are freed by [`collect_unused`]
+/// and their slots reused under a new generation, so that a stale id never resolves to another name.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UserId {
+    index: u32,
+    generation: u32,
+}
+
+/// Interned name of a slot.
+struct Slot {
+    name: Option<Arc<str>>,
+    generation: u32,
+    // Last collection epoch the name was interned, resolved or marked in
+    epoch: AtomicU64,
+}
+
+impl Slot {
+    fn touch(&self, epoch: u64) {
+        self.epoch.store(epoch, Ordering::Relaxed);
+    }
+}
+
+/// Maps user names to slots and back.
+#[derive(Default)]
+struct Interner {
+    slots: Vec<Slot>,
+    ids: HashMap<Arc<str>, u32>,
+    // Slots of the freed names, reused by the next interned names
+    free: Vec<u32>,
+    // Current collection epoch
+    epoch: u64,
+}
+
+impl Interner {
+    /// The slot of `id` unless its name was freed.
+    fn slot(&self, id: UserId) -> Option<&Slot> {
+        self.slots
+            .get(id.index as usize)
+            .filter(|slot| slot.generation == id.generation && slot.name.is_some())
+    }
+
+    fn lookup(&self, name: &str) -> Option<UserId> {
+        let index = *self.ids.get(name)?;
+        let slot = &self.slots[index as usize];
+        slot.touch(self.epoch);
+        Some(UserId {
+            index,
+            generation: slot.generation,
+        })
+    }
+
+    fn intern(&mut self, name: &str) -> UserId {
+        let name: Arc<str> = Arc::from(name);
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot {
+                    name: None,
+                    generation: 0,
+                    epoch: AtomicU64::new(0),
+                });
+                (self.slots.len() - 1) as u32
+            }
+        };
+        let slot = &mut self.slots[index as usize];
+        slot.name = Some(name.clone());
+        slot.touch(self.epoch);
+        self.ids.insert(name, index);
+        UserId {
+            index,
+            generation: slot.generation,
+        }
+    }
+
+    /// Frees the names neither used nor marked during the current and the previous epoch,
+    /// then starts a new epoch. Returns the number of freed names.
+    fn collect(&mut self) -> usize {
+        let Interner {
+            slots,
+            ids,
+            free,
+            epoch,
+        } = self;
+        let mut freed = 0;
+        for (index, slot) in slots.iter_mut().enumerate() {
+            if slot.name.is_none() || slot.epoch.load(Ordering::Relaxed) + 1 >= *epoch {
+                continue;
+            }
+            if let Some(name) = slot.name.take() {
+                ids.remove(&name);
+            }
+            slot.generation = slot.generation.wrapping_add(1);
+            free.push(index as u32);
+            freed += 1;
+        }
+        *epoch += 1;
+        freed
+    }
+}
+
+fn interner() -> &'static RwLock<Interner> {
+    static INTERNER: OnceLock<RwLock<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(Default::default)
+}
+
+impl UserId {
+    /// Gets the id of the user `name`, fails with [`Error::InvalidUserName`] if the name is empty,
+    /// longer than [`MAX_USER_NAME_LEN`] or contains whitespace or control characters.
+    pub fn new(name: &str) -> Result<Self> {
+        if name.is_empty()
+            || name.len() > MAX_USER_NAME_LEN
+            || name.chars().any(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(Error::InvalidUserName);
+        }
+        if let Some(id) = interner().read().unwrap().lookup(name) {
+            return Ok(id);
+        }
+        let mut interner = interner().write().unwrap();
+        // Interned by another thread meanwhile
+        if let Some(id) = interner.lookup(name) {
+            return Ok(id);
+        }
+        Ok(interner.intern(name))
+    }
+
+    /// The external name of the user, [`STALE_NAME`] if the name was freed.
+    pub fn name(&self) -> Arc<str> {
+        self.try_name().unwrap_or_else(|| Arc::from(STALE_NAME))
+    }
+
+    fn try_name(&self) -> Option<Arc<str>> {
+        let interner = interner().read().unwrap();
+        let slot = interner.slot(*self)?;
+        slot.touch(interner.epoch);
+        slot.name.clone()
+    }
+
+    /// Keeps the name of the user interned through the next collection (see [`collect_unused`]).
+    pub fn mark(&self) {
+        let interner = interner().read().unwrap();
+        if let Some(slot) = interner.slot(*self) {
+            slot.touch(interner.epoch);
+        }
+    }
+}
+
+/// Frees the user names neither used nor marked since the collection before the previous one,
+/// the owners of long lived ids mark them in between (see [`run_collection`]).
+/// Returns the number of freed names.
+pub fn collect_unused() -> usize {
+    interner().write().unwrap().collect()
+}
+
+/// Periodically marks the ids held by the server and the channels, then frees the unused names.
+pub async fn run_collection(server: Arc<ServerHandle>, registry: Arc<RegistryHandle>) {
+    let mut interval = tokio::time::interval(COLLECTION_INTERVAL);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(err) = mark_all(&server, &registry).await {
+            // Nothing is freed unless everything was marked
+            error!(%err, "User names collection error");
+            continue;
+        }
+        let freed = collect_unused();
+        debug!(freed, "User names collected");
+    }
+}
+
+async fn mark_all(server: &ServerHandle, registry: &RegistryHandle) -> Result {
+    server.mark_users().await?;
+    registry.mark_users().await
+}
+
+/// Converts the names of the tests, panics on invalid names (see [`UserId::new`] otherwise).
+#[cfg(test)]
+impl From<&str> for UserId {
+    fn from(name: &str) -> Self {
+        UserId::new(name).expect("valid user name")
+    }
+}
+
+#[cfg(test)]
+impl From<String> for UserId {
+    fn from(name: String) -> Self {
+        UserId::from(name.as_str())
+    }
+}
+
+/// Users are ordered by name (the ids depend on the interning order).
+impl Ord for UserId {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        if self == other {
+            return std::cmp::Ordering::Equal;
+        }
+        self.name().cmp(&other.name())
+    }
+}
+
+impl PartialOrd for UserId {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+impl fmt::Debug for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.name(), f)
+    }
+}
+
+impl Serialize for UserId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self.try_name() {
+            Some(name) => serializer.serialize_str(&name),
+            None => Err(serde::ser::Error::custom("stale user id")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for UserId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct UserIdVisitor;
+
+        impl<'de> de::Visitor<'de> for UserIdVisitor {
+            type Value = UserId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a user name")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> std::result::Result<UserId, E> {
+                UserId::new(name).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(UserIdVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_id_interning() {
+        let bob = UserId::new("bob").unwrap();
+        let alice = UserId::new("alice").unwrap();
+        assert_eq!(UserId::new("bob").unwrap(), bob);
+        assert_eq!(&*alice.name(), "alice");
+        assert!(alice < bob, "ordered by name");
+
+        let json = serde_json::to_string(&vec![alice, bob]).unwrap();
+        assert_eq!(json, "[\"alice\",\"bob\"]");
+        let users: Vec<UserId> = serde_json::from_str(&json).unwrap();
+        assert_eq!(users, vec![alice, bob]);
+
+        let bytes = bincode::serialize(&alice).unwrap();
+        assert_eq!(bytes, bincode::serialize("alice").unwrap());
+        assert_eq!(bincode::deserialize::<UserId>(&bytes).unwrap(), alice);
+    }
+
+    #[test]
+    fn test_invalid_names_not_interned() {
+        for name in vec![
+            "",
+            "two words",
+            "bell\u{7}",
+            &"x".repeat(MAX_USER_NAME_LEN + 1),
+        ]
+        .into_iter()
+        {
+            assert!(matches!(UserId::new(name), Err(Error::InvalidUserName)));
+            assert!(!interner().read().unwrap().ids.contains_key(name));
+        }
+        assert!(serde_json::from_str::<UserId>("\"two words\"").is_err());
+    }
+
+    #[test]
+    fn test_unused_names_freed() {
+        // A private interner, the global one is shared by the concurrent tests
+        let mut interner = Interner::default();
+        let carol = interner.intern("carol");
+        let dave = interner.intern("dave");
+        assert_eq!(interner.collect(), 0);
+        assert_eq!(interner.collect(), 0);
+
+        // Dave is marked, carol is not used anymore
+        interner.slot(dave).unwrap().touch(interner.epoch);
+        assert_eq!(interner.collect(), 1);
+        assert!(interner.slot(carol).is_none());
+        assert!(interner.lookup("carol").is_none());
+        assert_eq!(interner.lookup("dave"), Some(dave));
+
+        // The slot is reused under a new generation, the stale id does not resolve to the new name
+        let erin = interner.intern("erin");
+        assert_eq!(erin.index, carol.index);
+        assert_ne!(erin, carol);
+        assert!(interner.slot(carol).is_none());
+        assert_eq!(interner.slot(erin).unwrap().name.as_deref(), Some("erin"));
+    }
+
+    #[test]
+    fn test_stale_id_not_serialized() {
+        let stale = UserId {
+            index: u32::MAX,
+            generation: 0,
+        };
+        assert_eq!(&*stale.name(), STALE_NAME);
+        assert!(serde_json::to_string(&stale).is_err());
+    }
+}
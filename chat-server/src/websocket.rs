@@ -1,20 +1,255 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 
 use crate::{
-    channel::Message, errors::Result, registry_actor::RegistryHandle, server_actor::ServerHandle,
-    UserId, ID,
+    attachment::{Attachment, AttachmentRef},
+    bot_actor::Bot,
+    channel::{
+        ChannelMember, DirectChannel, Gap, IncomingWebhook, Message, Moderation, OutgoingWebhook,
+        Retention, Role, UnreadCount, Visibility,
+    },
+    channel_actor::{ChannelHandle, Publication},
+    commands::{self, Builtin, Input},
+    errors::{Error, Result},
+    metrics::metrics,
+    outgoing_webhook::{Delivery, EventKind},
+    rate_limit::TokenBucket,
+    registry_actor::RegistryHandle,
+    search::{self, SearchQuery},
+    server_actor::ServerHandle,
+    UserId, ID, LOBBY_CHANNEL_ID, MAX_HISTORY_SIZE,
 };
-use futures::{FutureExt, StreamExt};
+use futures::{FutureExt, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use warp::ws::WebSocket;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, UnboundedReceiverStream},
+    StreamMap,
+};
+use tracing::{debug, field, info, info_span, trace, warn, Instrument, Span};
+use warp::ws::{Message as WsMessage, WebSocket};
+
+fn default_history_limit() -> usize {
+    MAX_HISTORY_SIZE
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
     // Join { user: ID },
-    SendMessage { user: UserId, content: String },
+    /// Posts `content` into the channel, unless it is a slash command (see [`crate::commands`]).
+    SendMessage {
+        user: UserId,
+        /// Defaults to the lobby channel
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel_id: Option<ID>,
+        content: String,
+        /// Uploaded files (see [`crate::attachment`]), a message with attachments cannot be a command
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        attachments: Vec<AttachmentRef>,
+    },
+    SendDirectMessage {
+        user: UserId,
+        to: UserId,
+        content: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        attachments: Vec<AttachmentRef>,
+    },
+    /// Replaces the content of a message sent by the user.
+    EditMessage {
+        user: UserId,
+        channel_id: ID,
+        seq: u64,
+        content: String,
+    },
+    /// Deletes a message sent by the user (or any message for a moderator).
+    DeleteMessage {
+        user: UserId,
+        channel_id: ID,
+        seq: u64,
+    },
+    ListDirectChannels {
+        user: UserId,
+    },
+    CreateChannel {
+        user: UserId,
+        name: String,
+        visibility: Visibility,
+    },
+    JoinChannel {
+        user: UserId,
+        channel_id: ID,
+    },
+    InviteToChannel {
+        user: UserId,
+        channel_id: ID,
+        invitee: UserId,
+    },
+    RespondToInvitation {
+        user: UserId,
+        channel_id: ID,
+        accept: bool,
+    },
+    GetHistory {
+        user: UserId,
+        channel_id: ID,
+        #[serde(default = "default_history_limit")]
+        limit: usize,
+        /// Only messages with a lower sequence number (e.g. for filling a gap in the received messages).
+        #[serde(default)]
+        before: Option<u64>,
+    },
+    GetMembers {
+        user: UserId,
+        channel_id: ID,
+    },
+    Moderate {
+        user: UserId,
+        channel_id: ID,
+        target: UserId,
+        moderation: Moderation,
+    },
+    SetRole {
+        user: UserId,
+        channel_id: ID,
+        target: UserId,
+        role: Role,
+    },
+    SetSlowMode {
+        user: UserId,
+        channel_id: ID,
+        seconds: u32,
+    },
+    /// Sets how long the messages of the channel are kept, only owners can set it.
+    SetRetention {
+        user: UserId,
+        channel_id: ID,
+        retention: Retention,
+    },
+    /// Creates an incoming webhook posting into the channel as the bot user `name`.
+    CreateIncomingWebhook {
+        user: UserId,
+        channel_id: ID,
+        name: UserId,
+    },
+    ListIncomingWebhooks {
+        user: UserId,
+        channel_id: ID,
+    },
+    DeleteIncomingWebhook {
+        user: UserId,
+        channel_id: ID,
+        webhook_id: ID,
+    },
+    /// Creates an outgoing webhook POSTing the channel events of the given kinds (all of them if empty) to `url`.
+    CreateOutgoingWebhook {
+        user: UserId,
+        channel_id: ID,
+        url: String,
+        #[serde(default)]
+        events: Vec<EventKind>,
+    },
+    ListOutgoingWebhooks {
+        user: UserId,
+        channel_id: ID,
+    },
+    DeleteOutgoingWebhook {
+        user: UserId,
+        channel_id: ID,
+        webhook_id: ID,
+    },
+    /// Lists the outgoing webhook deliveries of the channel that exhausted their attempts.
+    ListDeadLetters {
+        user: UserId,
+        channel_id: ID,
+    },
+    /// Registers the bot user `name` handling the slash commands `commands`.
+    RegisterBot {
+        user: UserId,
+        name: UserId,
+        commands: Vec<String>,
+    },
+    DeleteBot {
+        user: UserId,
+        name: UserId,
+    },
+    /// Authenticates the connection as the bot of `token`,
+    /// the connection then receives the invocations of the bot commands.
+    AuthenticateBot {
+        token: String,
+    },
+    /// Sent by a bot connection, `content` is only sent to the user that invoked the command.
+    RespondToCommand {
+        invocation_id: ID,
+        content: String,
+    },
+    /// Sent (repeatedly) by the connection user while typing in a channel.
+    Typing {
+        channel_id: ID,
+    },
+    /// Marks all the messages up to `seq` as read.
+    MarkRead {
+        user: UserId,
+        channel_id: ID,
+        /// Sequence number of the last message read
+        seq: u64,
+    },
+    GetUnreadCounts {
+        user: UserId,
+    },
+    /// Searches the messages of the channels of the user, see [`SearchQuery`].
+    Search {
+        user: UserId,
+        #[serde(flatten)]
+        query: SearchQuery,
+    },
+    /// Resumes a session after reconnecting.
+    /// The messages of each channel after the `last_seen` sequence number are replayed before any new message.
+    Resume {
+        session_id: ID,
+        last_seen: HashMap<ID, u64>,
+    },
+}
+
+impl ClientMessage {
+    /// The user on behalf of which the message is sent (if the message carries the user).
+    pub fn user(&self) -> Option<&UserId> {
+        let user = match self {
+            ClientMessage::SendMessage { user, .. }
+            | ClientMessage::SendDirectMessage { user, .. }
+            | ClientMessage::EditMessage { user, .. }
+            | ClientMessage::DeleteMessage { user, .. }
+            | ClientMessage::ListDirectChannels { user }
+            | ClientMessage::CreateChannel { user, .. }
+            | ClientMessage::JoinChannel { user, .. }
+            | ClientMessage::InviteToChannel { user, .. }
+            | ClientMessage::RespondToInvitation { user, .. }
+            | ClientMessage::GetHistory { user, .. }
+            | ClientMessage::GetMembers { user, .. }
+            | ClientMessage::Moderate { user, .. }
+            | ClientMessage::SetRole { user, .. }
+            | ClientMessage::SetSlowMode { user, .. }
+            | ClientMessage::SetRetention { user, .. }
+            | ClientMessage::CreateIncomingWebhook { user, .. }
+            | ClientMessage::ListIncomingWebhooks { user, .. }
+            | ClientMessage::DeleteIncomingWebhook { user, .. }
+            | ClientMessage::CreateOutgoingWebhook { user, .. }
+            | ClientMessage::ListOutgoingWebhooks { user, .. }
+            | ClientMessage::DeleteOutgoingWebhook { user, .. }
+            | ClientMessage::ListDeadLetters { user, .. }
+            | ClientMessage::MarkRead { user, .. }
+            | ClientMessage::RegisterBot { user, .. }
+            | ClientMessage::DeleteBot { user, .. }
+            | ClientMessage::Search { user, .. }
+            | ClientMessage::GetUnreadCounts { user } => user,
+            ClientMessage::Typing { .. }
+            | ClientMessage::Resume { .. }
+            | ClientMessage::AuthenticateBot { .. }
+            | ClientMessage::RespondToCommand { .. } => return None,
+        };
+        Some(user)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,6 +257,194 @@ pub enum ClientMessage {
 pub enum ServerMessage {
     InvalidCommand,
     ChatMessage(Message),
+    /// The message with its new content.
+    MessageEdited(Message),
+    MessageDeleted {
+        channel_id: ID,
+        seq: u64,
+        by: UserId,
+    },
+    DirectChannels {
+        channels: Vec<DirectChannel>,
+    },
+    ChannelCreated {
+        channel_id: ID,
+    },
+    ChannelJoined {
+        channel_id: ID,
+    },
+    Invitation {
+        channel_id: ID,
+        from: UserId,
+    },
+    History {
+        channel_id: ID,
+        messages: Vec<Message>,
+    },
+    /// Sent before the replayed messages of a channel when some of the messages after `after` are missing.
+    HistoryGap {
+        channel_id: ID,
+        after: u64,
+        gap: Gap,
+    },
+    Members {
+        channel_id: ID,
+        members: Vec<ChannelMember>,
+    },
+    Moderated {
+        channel_id: ID,
+        user: UserId,
+        by: UserId,
+        moderation: Moderation,
+    },
+    RoleChanged {
+        channel_id: ID,
+        user: UserId,
+        role: Role,
+    },
+    SlowModeChanged {
+        channel_id: ID,
+        seconds: u32,
+    },
+    RetentionChanged {
+        channel_id: ID,
+        retention: Retention,
+    },
+    TopicChanged {
+        channel_id: ID,
+        topic: String,
+        by: UserId,
+    },
+    /// Ephemeral response to a slash command, only sent to the user that invoked it.
+    CommandResponse {
+        channel_id: ID,
+        command: String,
+        /// The bot that responded (`None` for the built-in commands)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bot: Option<UserId>,
+        content: String,
+    },
+    /// Sent only to the creator of the bot, the token authenticates the bot connections.
+    BotRegistered {
+        bot: Bot,
+    },
+    BotDeleted {
+        name: UserId,
+    },
+    BotAuthenticated {
+        name: UserId,
+    },
+    /// Sent to the connections of a bot when one of its commands is invoked.
+    CommandInvoked {
+        invocation_id: ID,
+        channel_id: ID,
+        user: UserId,
+        command: String,
+        args: String,
+    },
+    /// Sent only to the creator of the webhook, the token is the secret of the webhook URL
+    /// (it is not stored, it cannot be listed later).
+    IncomingWebhookCreated {
+        channel_id: ID,
+        webhook: IncomingWebhook,
+        token: String,
+    },
+    IncomingWebhooks {
+        channel_id: ID,
+        webhooks: Vec<IncomingWebhook>,
+    },
+    IncomingWebhookDeleted {
+        channel_id: ID,
+        webhook_id: ID,
+    },
+    /// Sent only to the creator of the webhook, the secret signs the deliveries.
+    OutgoingWebhookCreated {
+        channel_id: ID,
+        webhook: OutgoingWebhook,
+    },
+    OutgoingWebhooks {
+        channel_id: ID,
+        webhooks: Vec<OutgoingWebhook>,
+    },
+    OutgoingWebhookDeleted {
+        channel_id: ID,
+        webhook_id: ID,
+    },
+    DeadLetters {
+        channel_id: ID,
+        deliveries: Vec<Delivery>,
+    },
+    RateLimited {
+        retry_after_ms: u64,
+    },
+    UserTyping {
+        channel_id: ID,
+        user: UserId,
+        typing: bool,
+    },
+    ReadReceipt {
+        channel_id: ID,
+        user: UserId,
+        seq: u64,
+    },
+    UnreadCounts {
+        channels: Vec<UnreadCount>,
+    },
+    /// Messages found by a search, the latest first.
+    SearchResults {
+        messages: Vec<Message>,
+    },
+    /// Sent once the connection user is known, the session can be resumed by a new connection.
+    Session {
+        session_id: ID,
+    },
+    SessionResumed {
+        session_id: ID,
+        user: UserId,
+    },
+    Error {
+        message: String,
+    },
+    /// Sent to all the connections right before the server closes them.
+    ServerShutdown,
+}
+
+impl From<&Error> for ServerMessage {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::RateLimited(retry_after) => ServerMessage::RateLimited {
+                retry_after_ms: retry_after.as_millis() as u64,
+            },
+            err => ServerMessage::Error {
+                message: err.to_string(),
+            },
+        }
+    }
+}
+
+pub(crate) type ConnectionSender =
+    mpsc::UnboundedSender<std::result::Result<WsMessage, warp::Error>>;
+
+/// A websocket connection along with the handles its client messages are dispatched to.
+struct Connection {
+    id: u128,
+    sender: ConnectionSender,
+    server: Arc<ServerHandle>,
+    registry: Arc<RegistryHandle>,
+    // Limits all the messages received by the connection
+    rate_limiter: TokenBucket,
+    // The last user that sent a message through the connection
+    user: Option<UserId>,
+    // The bot the connection authenticated as (if any), the connection cannot act as another user
+    bot: Option<UserId>,
+    // Span of the connection, records the user once known
+    span: Span,
+    // Number of the messages received so far
+    requests: u64,
+    // Publications of the channels the user is member of
+    subscriptions: StreamMap<ID, BroadcastStream<Arc<Publication>>>,
+    // Maps channel_id -> sequence number of the last chat message sent to the client
+    last_seq: HashMap<ID, u64>,
 }
 
 pub async fn handle_connection(
@@ -29,80 +452,1117 @@ pub async fn handle_connection(
     server: Arc<ServerHandle>,
     registry: Arc<RegistryHandle>,
 ) -> Result {
-    let (outgoing, mut ws_incoming) = ws.split();
+    let (outgoing, ws_incoming) = ws.split();
     let (connection_tx, connection_rx) = mpsc::unbounded_channel();
 
-    let connection_id = server.connect(connection_tx).await?;
-    println!("Conn #<{}>: Opened", &connection_id);
+    let (subscriptions_tx, subscriptions_rx) = mpsc::unbounded_channel();
+
+    let sender = connection_tx.clone();
+    let connection_id = server.connect(connection_tx, subscriptions_tx).await?;
+    let span = info_span!(
+        "connection",
+        connection_id,
+        transport = "websocket",
+        user = field::Empty
+    );
+    info!(parent: &span, "Opened");
 
     let connection_rx = UnboundedReceiverStream::new(connection_rx);
-    tokio::spawn(connection_rx.forward(outgoing).map(move |result| {
-        if let Err(e) = result {
-            eprintln!("Conn #<{}>: message error {}", connection_id, e);
+    tokio::spawn(
+        connection_rx
+            .forward(outgoing)
+            .map(|result| {
+                if let Err(err) = result {
+                    warn!(%err, "Sending error");
+                }
+            })
+            .instrument(span.clone()),
+    );
+
+    run_connection(
+        connection_id,
+        sender,
+        ws_incoming,
+        subscriptions_rx,
+        server,
+        registry,
+        span,
+    )
+    .await
+}
+
+/// Handles the messages of a connection registered to `server` until the client closes it
+/// or stops receiving the messages of `sender`.
+/// `incoming` yields the messages received from the client, whatever their transport.
+pub(crate) async fn run_connection(
+    connection_id: u128,
+    sender: ConnectionSender,
+    incoming: impl Stream<Item = std::result::Result<WsMessage, warp::Error>> + Unpin,
+    subscriptions_rx: mpsc::UnboundedReceiver<(ChannelHandle, u64)>,
+    server: Arc<ServerHandle>,
+    registry: Arc<RegistryHandle>,
+    span: Span,
+) -> Result {
+    let rate_limiter = TokenBucket::new(server.rate_limits().connection);
+    let connection = Connection {
+        id: connection_id,
+        sender,
+        server,
+        registry,
+        rate_limiter,
+        user: None,
+        bot: None,
+        span: span.clone(),
+        requests: 0,
+        subscriptions: StreamMap::new(),
+        last_seq: HashMap::new(),
+    };
+    connection
+        .run(incoming, subscriptions_rx)
+        .instrument(span)
+        .await
+}
+
+impl Connection {
+    /// Handles the client messages and the channel publications until the connection closes.
+    async fn run(
+        mut self,
+        mut incoming: impl Stream<Item = std::result::Result<WsMessage, warp::Error>> + Unpin,
+        mut subscriptions_rx: mpsc::UnboundedReceiver<(ChannelHandle, u64)>,
+    ) -> Result {
+        loop {
+            tokio::select! {
+                msg = incoming.next() => {
+                    let msg = match msg {
+                        Some(Ok(msg)) => msg,
+                        Some(Err(err)) => {
+                            warn!(%err, "Receiving error");
+                            break;
+                        }
+                        None => break,
+                    };
+                    if msg.is_close() {
+                        break;
+                    }
+                    self.requests += 1;
+                    let span = info_span!("client_message", request = self.requests);
+                    self.handle_raw_message(msg).instrument(span).await;
+                }
+                Some((channel, since)) = subscriptions_rx.recv() => {
+                    if let Err(err) = self.subscribe(&channel, Some(since)).await {
+                        warn!(%err, channel_id = %channel.channel_id(), "Subscription error");
+                    }
+                }
+                Some((channel_id, publication)) = self.subscriptions.next() => match publication {
+                    Ok(publication) => {
+                        // Linked to the handling of the command that published it
+                        let span = info_span!("deliver", %channel_id);
+                        span.follows_from(&publication.span);
+                        span.in_scope(|| self.deliver(channel_id, &publication));
+                    }
+                    Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                        warn!(%channel_id, missed, "Missed publications");
+                        metrics().dropped_sends.with_label_values(&["lagged"]).inc_by(missed);
+                        if let Err(err) = self.resync(channel_id).await {
+                            warn!(%err, %channel_id, "Resync error");
+                        }
+                    }
+                },
+                // The client stopped receiving (e.g. a closed event stream)
+                _ = self.sender.closed() => break,
+            }
         }
-    }));
 
-    while let Some(msg) = ws_incoming.next().await {
-        let msg = match msg {
-            Ok(msg) => msg,
-            Err(e) => {
-                eprintln!("Conn #<{}>: message error {}", &connection_id, e);
-                break;
+        info!("Closed");
+        self.server.disconnect(self.id).await?;
+        Ok(())
+    }
+
+    /// Parses and handles a message received from the client, the errors are sent back to the client.
+    async fn handle_raw_message(&mut self, msg: WsMessage) {
+        debug!(?msg, "Received");
+        metrics().messages_received.inc();
+        if let Err(retry_after) = self.rate_limiter.try_acquire(Instant::now()) {
+            warn!("Rate limited");
+            let _ = self.reply(&(&Error::RateLimited(retry_after)).into());
+            return;
+        }
+        let text = match msg.to_str() {
+            Ok(text) => text,
+            Err(_) => {
+                warn!(?msg, "Unsupported (binary?) message");
+                return;
             }
         };
+        match serde_json::from_str(text) {
+            Ok(client_message) => {
+                if let Err(err) = self.handle_client_message(client_message).await {
+                    warn!(%err, "Client message error");
+                    let _ = self.reply(&(&err).into());
+                }
+            }
+            Err(err) => {
+                warn!(%err, "Invalid client message");
+                let _ = self.reply(&ServerMessage::InvalidCommand);
+            }
+        }
+    }
 
-        println!("Conn #<{}>: received RAW msg {:?}", &connection_id, &msg);
-        if let Ok(msg) = msg.to_str() {
-            if let Ok(client_message) = serde_json::from_str(msg) {
-                match client_message {
-                    ClientMessage::SendMessage { user, content } => {
-                        // TODO handle this error
-                        if let Err(err) =
-                            handle_send_message(&server, &registry, connection_id, user, content)
-                                .await
-                        {
-                            eprintln!("Conn #<{}>: Send message error {}", &connection_id, &err);
-                        }
-                    }
+    /// Sends `message` only to this connection (bypassing the channel fan-out).
+    fn reply(&self, message: &ServerMessage) -> Result {
+        let message = serde_json::to_string(message)?;
+        // The connection may be already closed, there is no one to notify in that case
+        if self.sender.send(Ok(WsMessage::text(message))).is_err() {
+            metrics().dropped_closed();
+        }
+        Ok(())
+    }
+
+    async fn handle_client_message(&mut self, msg: ClientMessage) -> Result {
+        // The first message naming a user binds the connection to it, the connection cannot act as another user
+        // afterwards (nor take a user held by the session of another connection, see ServerHandle::register_user)
+        if let Some(user) = msg.user() {
+            if let Some(current) = &self.user {
+                if current != user {
+                    return Err(Error::UserMismatch);
                 }
             } else {
-                eprintln!("Conn #<{}>: Invalid client message", &connection_id);
+                let session_id = self.server.register_user(self.id, *user).await?;
+                self.set_user(*user);
+                self.subscribe_all().await?;
+                self.reply(&ServerMessage::Session { session_id })?;
+                // The user is known from now on, let the client catch up
+                if !matches!(msg, ClientMessage::GetUnreadCounts { .. }) {
+                    let channels = self.registry.get_unread_counts(*user).await?;
+                    self.reply(&ServerMessage::UnreadCounts { channels })?;
+                }
+            }
+        }
+
+        match msg {
+            ClientMessage::SendMessage {
+                user,
+                channel_id,
+                content,
+                attachments,
+            } => {
+                let channel_id = channel_id.unwrap_or(LOBBY_CHANNEL_ID);
+                self.handle_send_message(user, channel_id, content, attachments)
+                    .await
+            }
+            ClientMessage::SendDirectMessage {
+                user,
+                to,
+                content,
+                attachments,
+            } => {
+                self.handle_send_direct_message(user, to, content, attachments)
+                    .await
+            }
+            ClientMessage::EditMessage {
+                user,
+                channel_id,
+                seq,
+                content,
+            } => {
+                // Published by the channel to its subscribers
+                let channel = self.registry.get_channel(channel_id).await?;
+                channel.edit_message(user, seq, content).await?;
+                Ok(())
+            }
+            ClientMessage::DeleteMessage {
+                user,
+                channel_id,
+                seq,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                channel.delete_message(user, seq).await
+            }
+            ClientMessage::ListDirectChannels { user } => {
+                let channels = self.registry.list_direct_channels(user).await?;
+                self.reply(&ServerMessage::DirectChannels { channels })
+            }
+            ClientMessage::CreateChannel {
+                user,
+                name,
+                visibility,
+            } => {
+                let channel = self.registry.create_channel(user, name, visibility).await?;
+                self.subscribe_users(vec![user], &channel).await?;
+                self.reply(&ServerMessage::ChannelCreated {
+                    channel_id: channel.channel_id(),
+                })
+            }
+            ClientMessage::JoinChannel { user, channel_id } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                channel.join(user).await?;
+                self.subscribe_users(vec![user], &channel).await?;
+                self.reply(&ServerMessage::ChannelJoined { channel_id })
+            }
+            ClientMessage::InviteToChannel {
+                user,
+                channel_id,
+                invitee,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                channel.invite(user, invitee).await?;
+                let invitation = ServerMessage::Invitation {
+                    channel_id,
+                    from: user,
+                };
+                self.server
+                    .publish_to_users(vec![invitee], invitation)
+                    .await
+            }
+            ClientMessage::RespondToInvitation {
+                user,
+                channel_id,
+                accept,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                channel.respond_to_invitation(user, accept).await?;
+                if accept {
+                    self.subscribe_users(vec![user], &channel).await?;
+                    self.reply(&ServerMessage::ChannelJoined { channel_id })?;
+                }
+                Ok(())
+            }
+            ClientMessage::GetHistory {
+                user,
+                channel_id,
+                limit,
+                before,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                let messages = channel.get_history(user, limit, before).await?;
+                self.reply(&ServerMessage::History {
+                    channel_id,
+                    messages,
+                })
+            }
+            ClientMessage::GetMembers { user, channel_id } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                let members = channel.get_members(user).await?;
+                self.reply(&ServerMessage::Members {
+                    channel_id,
+                    members,
+                })
+            }
+            ClientMessage::Moderate {
+                user,
+                channel_id,
+                target,
+                moderation,
+            } => {
+                // The channel publishes the moderation to its subscribers
+                let channel = self.registry.get_channel(channel_id).await?;
+                channel.moderate(user, target, moderation).await
+            }
+            ClientMessage::SetRole {
+                user,
+                channel_id,
+                target,
+                role,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                channel.set_role(user, target, role).await?;
+                let role_changed = ServerMessage::RoleChanged {
+                    channel_id,
+                    user: target,
+                    role,
+                };
+                channel.publish(role_changed)
+            }
+            ClientMessage::SetSlowMode {
+                user,
+                channel_id,
+                seconds,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                channel.set_slow_mode(user, seconds).await?;
+                let slow_mode_changed = ServerMessage::SlowModeChanged {
+                    channel_id,
+                    seconds,
+                };
+                channel.publish(slow_mode_changed)
+            }
+            ClientMessage::SetRetention {
+                user,
+                channel_id,
+                retention,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                channel.set_retention(user, retention).await?;
+                let retention_changed = ServerMessage::RetentionChanged {
+                    channel_id,
+                    retention,
+                };
+                channel.publish(retention_changed)
+            }
+            ClientMessage::CreateIncomingWebhook {
+                user,
+                channel_id,
+                name,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                let (webhook, token) = channel.create_incoming_webhook(user, name).await?;
+                self.reply(&ServerMessage::IncomingWebhookCreated {
+                    channel_id,
+                    webhook,
+                    token,
+                })
+            }
+            ClientMessage::ListIncomingWebhooks { user, channel_id } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                let webhooks = channel.list_incoming_webhooks(user).await?;
+                self.reply(&ServerMessage::IncomingWebhooks {
+                    channel_id,
+                    webhooks,
+                })
+            }
+            ClientMessage::DeleteIncomingWebhook {
+                user,
+                channel_id,
+                webhook_id,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                channel.delete_incoming_webhook(user, webhook_id).await?;
+                self.reply(&ServerMessage::IncomingWebhookDeleted {
+                    channel_id,
+                    webhook_id,
+                })
+            }
+            ClientMessage::CreateOutgoingWebhook {
+                user,
+                channel_id,
+                url,
+                events,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                let webhook = channel.create_outgoing_webhook(user, url, events).await?;
+                self.reply(&ServerMessage::OutgoingWebhookCreated {
+                    channel_id,
+                    webhook,
+                })
+            }
+            ClientMessage::ListOutgoingWebhooks { user, channel_id } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                let webhooks = channel.list_outgoing_webhooks(user).await?;
+                self.reply(&ServerMessage::OutgoingWebhooks {
+                    channel_id,
+                    webhooks,
+                })
+            }
+            ClientMessage::DeleteOutgoingWebhook {
+                user,
+                channel_id,
+                webhook_id,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                channel.delete_outgoing_webhook(user, webhook_id).await?;
+                self.reply(&ServerMessage::OutgoingWebhookDeleted {
+                    channel_id,
+                    webhook_id,
+                })
+            }
+            ClientMessage::ListDeadLetters { user, channel_id } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                let deliveries = channel.list_dead_letters(user).await?;
+                self.reply(&ServerMessage::DeadLetters {
+                    channel_id,
+                    deliveries,
+                })
+            }
+            ClientMessage::RegisterBot {
+                user,
+                name,
+                commands,
+            } => {
+                let bot = self.registry.bots().register(user, name, commands).await?;
+                self.reply(&ServerMessage::BotRegistered { bot })
+            }
+            ClientMessage::DeleteBot { user, name } => {
+                self.registry.bots().delete(user, name).await?;
+                self.reply(&ServerMessage::BotDeleted { name })
+            }
+            ClientMessage::AuthenticateBot { token } => {
+                if self.user.is_some() {
+                    return Err(Error::UserMismatch);
+                }
+                let name = self.registry.bots().authenticate(token).await?;
+                self.server
+                    .register_authenticated_user(self.id, name)
+                    .await?;
+                self.set_user(name);
+                self.bot = Some(name);
+                self.subscribe_all().await?;
+                self.reply(&ServerMessage::BotAuthenticated { name })
+            }
+            ClientMessage::RespondToCommand {
+                invocation_id,
+                content,
+            } => {
+                let bot = self.bot.ok_or(Error::BotNotFound)?;
+                let invocation = self.registry.bots().respond(bot, invocation_id).await?;
+                let response = ServerMessage::CommandResponse {
+                    channel_id: invocation.channel_id,
+                    command: invocation.command,
+                    bot: Some(bot),
+                    content,
+                };
+                self.server
+                    .publish_to_users(vec![invocation.invoker], response)
+                    .await
+            }
+            ClientMessage::Typing { channel_id } => {
+                let user = self.user.ok_or(Error::UnknownUser)?;
+                if !self.subscriptions.contains_key(&channel_id) {
+                    return Err(Error::NotAMember);
+                }
+                // The throttled indicators are renewed without looking the channel up
+                if !self.server.renew_typing(user, channel_id).await? {
+                    return Ok(());
+                }
+                let channel = self.registry.get_channel(channel_id).await?;
+                self.server.typing(user, channel).await
+            }
+            ClientMessage::MarkRead {
+                user,
+                channel_id,
+                seq,
+            } => {
+                let channel = self.registry.get_channel(channel_id).await?;
+                if channel.mark_read(user, seq).await? {
+                    let receipt = ServerMessage::ReadReceipt {
+                        channel_id,
+                        user,
+                        seq,
+                    };
+                    channel.publish(receipt)?;
+                }
+                Ok(())
+            }
+            ClientMessage::GetUnreadCounts { user } => {
+                let channels = self.registry.get_unread_counts(user).await?;
+                self.reply(&ServerMessage::UnreadCounts { channels })
+            }
+            ClientMessage::Search { user, query } => {
+                let messages = search::search(&self.registry, user, &query).await?;
+                self.reply(&ServerMessage::SearchResults { messages })
+            }
+            ClientMessage::Resume {
+                session_id,
+                last_seen,
+            } => {
+                if self.user.is_some() {
+                    return Err(Error::UserMismatch);
+                }
+                let user = self.server.resume_session(self.id, session_id).await?;
+                self.set_user(user);
+                // Subscribe before reading the logs, the messages published meanwhile are delivered after the replay
+                self.subscribe_all().await?;
+                self.reply(&ServerMessage::SessionResumed { session_id, user })?;
+                self.replay(user, last_seen).await
             }
-        } else {
-            eprintln!(
-                "Conn #<{}>: Unsupported (binary?) formatted RAW msg {:?}",
-                &connection_id, &msg
-            )
         }
     }
 
-    println!("Conn #<{}>: Closed", &connection_id);
-    server.disconnect(connection_id).await?;
-    Ok(())
-}
+    /// Sends the messages of each channel with a sequence number greater than the `last_seen` one.
+    async fn replay(&mut self, user: UserId, last_seen: HashMap<ID, u64>) -> Result {
+        for (channel_id, last_seen) in last_seen {
+            let channel = self.registry.get_channel(channel_id).await?;
+            self.replay_channel(&channel, user, last_seen).await?;
+        }
+        Ok(())
+    }
 
-async fn handle_send_message(
-    server: &ServerHandle,
-    registry: &RegistryHandle,
-    connection_id: u128,
-    user: UserId,
-    msg: String,
-) -> Result {
-    // TODO remove this (currently we handle only one hardcoded channel)
-    let channel_id: ID = uuid::Uuid::parse_str("13cdc63e-55e2-403b-9ac6-4aa7c2155bf4").unwrap();
-    let channel = registry.get_channel(channel_id).await?;
-    let msg = channel.add_message(user.clone(), msg).await?;
-    let server_message = ServerMessage::ChatMessage(msg);
-    // TODO remove this (currently we subscribe users to the channel implicitly move to REST API)
-    server.register_user(connection_id, user).await?;
-    server.publish_to_channel(&channel, server_message).await?;
-    Ok(())
+    async fn replay_channel(
+        &mut self,
+        channel: &ChannelHandle,
+        user: UserId,
+        after: u64,
+    ) -> Result {
+        let replayed = channel.get_messages_after(user, after).await?;
+        if let Some(gap) = replayed.gap {
+            // The client cannot rely on the replay alone, it reloads the history
+            self.reply(&ServerMessage::HistoryGap {
+                channel_id: channel.channel_id(),
+                after,
+                gap,
+            })?;
+        }
+        for m in replayed.messages {
+            self.last_seq.insert(channel.channel_id(), m.seq);
+            self.reply(&ServerMessage::ChatMessage(m))?;
+        }
+        Ok(())
+    }
+
+    /// Subscribes the connection to `channel` if the connection user is a member of it,
+    /// replaying the chat messages published after `since` but before the subscription.
+    /// Returns the sequence number of the last message published before the subscription
+    /// (`None` if the connection has not been subscribed now).
+    async fn subscribe(
+        &mut self,
+        channel: &ChannelHandle,
+        since: Option<u64>,
+    ) -> Result<Option<u64>> {
+        let user = match &self.user {
+            Some(user) => *user,
+            None => return Ok(None),
+        };
+        let channel_id = channel.channel_id();
+        if self.subscriptions.contains_key(&channel_id) {
+            return Ok(None);
+        }
+        let (subscription, last_seq) = match channel.subscribe(user).await? {
+            Some(subscribed) => subscribed,
+            None => return Ok(None),
+        };
+        self.subscriptions
+            .insert(channel_id, BroadcastStream::new(subscription));
+        // The messages published from now on follow `last_seq`
+        let delivered = self.last_seq.entry(channel_id).or_default();
+        *delivered = (*delivered).max(last_seq);
+        if let Some(since) = since.filter(|since| *since < last_seq) {
+            self.replay_channel(channel, user, since).await?;
+        }
+        Ok(Some(last_seq))
+    }
+
+    /// Subscribes this connection and all the other connections of `users` to `channel`.
+    async fn subscribe_users(&mut self, users: Vec<UserId>, channel: &ChannelHandle) -> Result {
+        if let Some(since) = self.subscribe(channel, None).await? {
+            self.server
+                .subscribe_users(users, channel.clone(), since)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the subscriptions of the connection with the channels the connection user is member of.
+    async fn subscribe_all(&mut self) -> Result {
+        self.subscriptions = StreamMap::new();
+        self.last_seq.clear();
+        let user = match &self.user {
+            Some(user) => *user,
+            None => return Ok(()),
+        };
+        let channels = self.registry.get_user_channels(user).await?;
+        let subscriptions =
+            futures::future::join_all(channels.iter().map(|channel| channel.subscribe(user))).await;
+        for (channel, subscription) in channels.iter().zip(subscriptions) {
+            if let Some((subscription, last_seq)) = subscription? {
+                self.subscriptions
+                    .insert(channel.channel_id(), BroadcastStream::new(subscription));
+                self.last_seq.insert(channel.channel_id(), last_seq);
+            }
+        }
+        Ok(())
+    }
+
+    /// Subscribes again to a channel whose publications were missed,
+    /// replaying the missed chat messages if the connection user is still a member.
+    async fn resync(&mut self, channel_id: ID) -> Result {
+        // The missed publications may have removed the user from the channel
+        self.subscriptions.remove(&channel_id);
+        let since = self.last_seq.remove(&channel_id).unwrap_or(0);
+        let channel = self.registry.get_channel(channel_id).await?;
+        self.subscribe(&channel, Some(since)).await?;
+        Ok(())
+    }
+
+    fn set_user(&mut self, user: UserId) {
+        self.user = Some(user);
+        self.span.record("user", &user.name().as_ref());
+    }
+
+    /// Forwards a publication of a subscribed channel to the client.
+    fn deliver(&mut self, channel_id: ID, publication: &Publication) {
+        match &publication.message {
+            ServerMessage::ChatMessage(m) => {
+                // Already sent by a replay
+                let last_seq = self.last_seq.entry(channel_id).or_default();
+                if m.seq <= *last_seq {
+                    return;
+                }
+                *last_seq = m.seq;
+            }
+            ServerMessage::UserTyping { user, .. } if self.user.as_ref() == Some(user) => return,
+            ServerMessage::Moderated {
+                user,
+                moderation: Moderation::Kick | Moderation::Ban,
+                ..
+            } if self.user.as_ref() == Some(user) => {
+                self.subscriptions.remove(&channel_id);
+                self.last_seq.remove(&channel_id);
+            }
+            _ => {}
+        }
+        trace!(%channel_id, "Delivered");
+        // The connection may be already closed, there is no one to notify in that case
+        match self
+            .sender
+            .send(Ok(WsMessage::text(publication.text.clone())))
+        {
+            Ok(()) => metrics().messages_delivered.inc(),
+            Err(_) => metrics().dropped_closed(),
+        }
+    }
+
+    /// Adds the message to the channel, the channel publishes it to its subscribers (the sender included).
+    async fn handle_send_message(
+        &mut self,
+        user: UserId,
+        channel_id: ID,
+        msg: String,
+        attachments: Vec<AttachmentRef>,
+    ) -> Result {
+        self.server.acquire_post_token(user).await?;
+        let channel = self.registry.get_channel(channel_id).await?;
+        if !attachments.is_empty() {
+            let attachments = self.registry.blobs().resolve(attachments).await?;
+            return self.post_message(user, channel, msg, attachments).await;
+        }
+        match commands::parse(msg) {
+            Input::Message(msg) => self.post_message(user, channel, msg, Vec::new()).await,
+            Input::Command { name, args } => self.handle_command(user, channel, name, args).await,
+        }
+    }
+
+    async fn post_message(
+        &mut self,
+        user: UserId,
+        channel: ChannelHandle,
+        msg: String,
+        attachments: Vec<Attachment>,
+    ) -> Result {
+        let channel_id = channel.channel_id();
+        if !self.subscriptions.contains_key(&channel_id) {
+            // Posting joins public channels
+            channel.join(user).await?;
+            self.subscribe_users(vec![user], &channel).await?;
+        }
+        channel
+            .add_message_with_attachments(user, msg, attachments)
+            .await?;
+        Ok(())
+    }
+
+    /// Runs the slash command `name` typed by `user` into `channel`.
+    /// The built-in commands respond to this connection only, the bots to all the connections of the user.
+    async fn handle_command(
+        &mut self,
+        user: UserId,
+        channel: ChannelHandle,
+        name: String,
+        args: String,
+    ) -> Result {
+        let channel_id = channel.channel_id();
+        debug!(command = %name, "Slash command");
+        let builtin = match Builtin::from_name(&name) {
+            Some(builtin) => builtin,
+            None => {
+                // Bots only serve the members of the channel
+                if !self.subscriptions.contains_key(&channel_id) {
+                    return Err(Error::NotAMember);
+                }
+                let invocation = self.registry.bots().invoke(user, channel_id, name).await?;
+                let invoked = ServerMessage::CommandInvoked {
+                    invocation_id: invocation.id,
+                    channel_id,
+                    user,
+                    command: invocation.command,
+                    args,
+                };
+                return self
+                    .server
+                    .publish_to_users(vec![invocation.bot], invoked)
+                    .await;
+            }
+        };
+        let content = match builtin {
+            Builtin::Me if !args.is_empty() => {
+                return self
+                    .post_message(user, channel, format!("* {} {}", user, args), Vec::new())
+                    .await;
+            }
+            Builtin::Topic if args.is_empty() => {
+                let topic = channel.get_topic(user).await?;
+                if topic.is_empty() {
+                    "No topic is set".to_string()
+                } else {
+                    topic
+                }
+            }
+            Builtin::Topic => {
+                channel.set_topic(user, args.clone()).await?;
+                let topic_changed = ServerMessage::TopicChanged {
+                    channel_id,
+                    topic: args,
+                    by: user,
+                };
+                return channel.publish(topic_changed);
+            }
+            Builtin::Invite if !args.is_empty() && !args.contains(char::is_whitespace) => {
+                let invitee = UserId::new(&args)?;
+                channel.invite(user, invitee).await?;
+                let invitation = ServerMessage::Invitation {
+                    channel_id,
+                    from: user,
+                };
+                self.server
+                    .publish_to_users(vec![invitee], invitation)
+                    .await?;
+                format!("Invited {}", invitee)
+            }
+            Builtin::Help => {
+                let mut lines: Vec<String> = Builtin::ALL
+                    .iter()
+                    .map(|builtin| builtin.usage().to_string())
+                    .collect();
+                for (command, bot) in self.registry.bots().commands().await? {
+                    lines.push(format!("/{} - handled by {}", command, bot));
+                }
+                lines.join("\n")
+            }
+            Builtin::Me | Builtin::Invite => {
+                return Err(Error::InvalidCommand(format!("Usage: {}", builtin.usage())))
+            }
+        };
+        self.reply(&ServerMessage::CommandResponse {
+            channel_id,
+            command: name,
+            bot: None,
+            content,
+        })
+    }
+
+    async fn handle_send_direct_message(
+        &mut self,
+        user: UserId,
+        to: UserId,
+        msg: String,
+        attachments: Vec<AttachmentRef>,
+    ) -> Result {
+        self.server.acquire_post_token(user).await?;
+        let attachments = self.registry.blobs().resolve(attachments).await?;
+        let channel = self.registry.get_direct_channel(user, to).await?;
+        if !self.subscriptions.contains_key(&channel.channel_id()) {
+            // The channel may have just been created
+            self.subscribe_users(vec![user, to], &channel).await?;
+        }
+        channel
+            .add_message_with_attachments(user, msg, attachments)
+            .await?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::test_util::{users, TestEnv};
+    use std::time::Duration;
+
+    /// A client of a connection handled by [`run_connection`].
+    struct TestClient {
+        incoming: mpsc::UnboundedSender<std::result::Result<WsMessage, warp::Error>>,
+        outgoing: mpsc::UnboundedReceiver<std::result::Result<WsMessage, warp::Error>>,
+    }
+
+    impl TestClient {
+        async fn connect(server: &Arc<ServerHandle>, registry: &Arc<RegistryHandle>) -> Self {
+            let (sender, outgoing) = mpsc::unbounded_channel();
+            let (subscriptions, subscriptions_rx) = mpsc::unbounded_channel();
+            let (incoming, incoming_rx) = mpsc::unbounded_channel();
+            let connection_id = server.connect(sender.clone(), subscriptions).await.unwrap();
+            tokio::spawn(run_connection(
+                connection_id,
+                sender,
+                UnboundedReceiverStream::new(incoming_rx),
+                subscriptions_rx,
+                server.clone(),
+                registry.clone(),
+                Span::none(),
+            ));
+            Self { incoming, outgoing }
+        }
+
+        fn send(&self, message: ClientMessage) {
+            let message = serde_json::to_string(&message).unwrap();
+            self.incoming.send(Ok(WsMessage::text(message))).unwrap();
+        }
+
+        async fn recv(&mut self) -> ServerMessage {
+            let message = tokio::time::timeout(Duration::from_secs(5), self.outgoing.recv())
+                .await
+                .expect("no message received")
+                .unwrap()
+                .unwrap();
+            serde_json::from_str(message.to_str().unwrap()).unwrap()
+        }
+
+        /// Receives the messages until the first chat message.
+        async fn recv_chat_message(&mut self) -> Message {
+            loop {
+                if let ServerMessage::ChatMessage(m) = self.recv().await {
+                    return m;
+                }
+            }
+        }
+
+        async fn assert_nothing_received(&mut self) {
+            let received =
+                tokio::time::timeout(Duration::from_millis(200), self.outgoing.recv()).await;
+            assert!(received.is_err(), "unexpected message {:?}", received);
+        }
+    }
+
+    fn handles(env: &TestEnv) -> (Arc<ServerHandle>, Arc<RegistryHandle>) {
+        (Arc::new(env.server()), Arc::new(env.registry()))
+    }
+
+    async fn create_channel(client: &mut TestClient, user: UserId) -> ID {
+        client.send(ClientMessage::CreateChannel {
+            user,
+            name: "ops".into(),
+            visibility: Visibility::Public,
+        });
+        loop {
+            if let ServerMessage::ChannelCreated { channel_id } = client.recv().await {
+                return channel_id;
+            }
+        }
+    }
+
+    fn send_message(client: &TestClient, user: UserId, channel_id: ID, content: &str) {
+        client.send(ClientMessage::SendMessage {
+            user,
+            channel_id: Some(channel_id),
+            content: content.into(),
+            attachments: Vec::new(),
+        });
+    }
+
+    #[tokio::test]
+    async fn test_kicked_user_unsubscribed() {
+        let env = TestEnv::new();
+        let (server, registry) = handles(&env);
+        let (alice, bob, _) = users();
+        let mut alice_client = TestClient::connect(&server, &registry).await;
+        let mut bob_client = TestClient::connect(&server, &registry).await;
+        let channel_id = create_channel(&mut alice_client, alice).await;
+        bob_client.send(ClientMessage::JoinChannel {
+            user: bob,
+            channel_id,
+        });
+        while !matches!(bob_client.recv().await, ServerMessage::ChannelJoined { .. }) {}
+
+        send_message(&alice_client, alice, channel_id, "hello");
+        assert_eq!(bob_client.recv_chat_message().await.content, "hello");
+        assert_eq!(alice_client.recv_chat_message().await.content, "hello");
+
+        alice_client.send(ClientMessage::Moderate {
+            user: alice,
+            channel_id,
+            target: bob,
+            moderation: Moderation::Kick,
+        });
+        assert!(matches!(
+            bob_client.recv().await,
+            ServerMessage::Moderated { user, .. } if user == bob
+        ));
+        send_message(&alice_client, alice, channel_id, "secret");
+        assert!(matches!(
+            alice_client.recv().await,
+            ServerMessage::Moderated { .. }
+        ));
+        assert_eq!(alice_client.recv_chat_message().await.content, "secret");
+        bob_client.assert_nothing_received().await;
+    }
+
+    #[tokio::test]
+    async fn test_impersonation_refused() {
+        let env = TestEnv::new();
+        let (server, registry) = handles(&env);
+        let (alice, _, eve) = users();
+        let mut alice_client = TestClient::connect(&server, &registry).await;
+        let channel_id = create_channel(&mut alice_client, alice).await;
+
+        // A second connection claiming the channel member
+        let mut impersonator = TestClient::connect(&server, &registry).await;
+        send_message(&impersonator, alice, channel_id, "forged");
+        assert!(matches!(
+            impersonator.recv().await,
+            ServerMessage::Error { message } if message == Error::UserTaken.to_string()
+        ));
+
+        // A connection bound to another user
+        let mut eve_client = TestClient::connect(&server, &registry).await;
+        eve_client.send(ClientMessage::GetUnreadCounts { user: eve });
+        assert!(matches!(
+            eve_client.recv().await,
+            ServerMessage::Session { .. }
+        ));
+        eve_client.recv().await;
+        send_message(&eve_client, alice, channel_id, "forged");
+        assert!(matches!(
+            eve_client.recv().await,
+            ServerMessage::Error { message } if message == Error::UserMismatch.to_string()
+        ));
+        alice_client.assert_nothing_received().await;
+        impersonator.assert_nothing_received().await;
+    }
+
+    #[tokio::test]
+    async fn test_resume_replays_missed_messages() {
+        let env = TestEnv::new();
+        let (server, registry) = handles(&env);
+        let (alice, _, _) = users();
+        let mut client = TestClient::connect(&server, &registry).await;
+        client.send(ClientMessage::GetUnreadCounts { user: alice });
+        let session_id = match client.recv().await {
+            ServerMessage::Session { session_id } => session_id,
+            other => panic!("unexpected message {:?}", other),
+        };
+        let channel_id = create_channel(&mut client, alice).await;
+        for content in vec!["one", "two", "three"].into_iter() {
+            send_message(&client, alice, channel_id, content);
+            client.recv_chat_message().await;
+        }
+
+        let mut resumed = TestClient::connect(&server, &registry).await;
+        resumed.send(ClientMessage::Resume {
+            session_id,
+            last_seen: vec![(channel_id, 1)].into_iter().collect(),
+        });
+        assert!(matches!(
+            resumed.recv().await,
+            ServerMessage::SessionResumed { user, .. } if user == alice
+        ));
+        assert_eq!(resumed.recv_chat_message().await.seq, 2);
+        assert_eq!(resumed.recv_chat_message().await.seq, 3);
+
+        send_message(&resumed, alice, channel_id, "four");
+        assert_eq!(resumed.recv_chat_message().await.seq, 4);
+        resumed.assert_nothing_received().await;
+    }
+
+    #[tokio::test]
+    async fn test_replayed_messages_not_delivered_again() {
+        let env = TestEnv::new();
+        let (server, registry) = handles(&env);
+        let (sender, mut outgoing) = mpsc::unbounded_channel();
+        let mut connection = Connection {
+            id: 0,
+            sender,
+            rate_limiter: TokenBucket::new(server.rate_limits().connection),
+            server,
+            registry,
+            user: Some("alice".into()),
+            bot: None,
+            span: Span::none(),
+            requests: 0,
+            subscriptions: StreamMap::new(),
+            last_seq: HashMap::new(),
+        };
+        let channel_id = crate::new_id();
+        connection.last_seq.insert(channel_id, 3);
+        for seq in 2..=4 {
+            let message = ServerMessage::ChatMessage(Message::new(
+                channel_id,
+                "bob".into(),
+                "hi".into(),
+                seq,
+            ));
+            let text = serde_json::to_string(&message).unwrap();
+            let publication = Publication {
+                message,
+                text,
+                span: Span::none(),
+            };
+            connection.deliver(channel_id, &publication);
+        }
+        let delivered: ServerMessage =
+            serde_json::from_str(outgoing.recv().await.unwrap().unwrap().to_str().unwrap())
+                .unwrap();
+        assert!(matches!(delivered, ServerMessage::ChatMessage(m) if m.seq == 4));
+        assert!(outgoing.recv().now_or_never().is_none());
+        assert_eq!(connection.last_seq.get(&channel_id), Some(&4));
+    }
+
+    #[tokio::test]
+    async fn test_bot_response_only_sent_to_invoker() {
+        let env = TestEnv::new();
+        let (server, registry) = handles(&env);
+        let (alice, bob, _) = users();
+        let deployer = UserId::from("deployer");
+        let mut alice_client = TestClient::connect(&server, &registry).await;
+        let channel_id = create_channel(&mut alice_client, alice).await;
+        alice_client.send(ClientMessage::RegisterBot {
+            user: alice,
+            name: deployer,
+            commands: vec!["deploy".into()],
+        });
+        let token = loop {
+            if let ServerMessage::BotRegistered { bot } = alice_client.recv().await {
+                break bot.token;
+            }
+        };
+        let mut bot_client = TestClient::connect(&server, &registry).await;
+        bot_client.send(ClientMessage::AuthenticateBot { token });
+        bot_client.send(ClientMessage::JoinChannel {
+            user: deployer,
+            channel_id,
+        });
+        while !matches!(bot_client.recv().await, ServerMessage::ChannelJoined { .. }) {}
+        let mut bob_client = TestClient::connect(&server, &registry).await;
+        bob_client.send(ClientMessage::JoinChannel {
+            user: bob,
+            channel_id,
+        });
+        while !matches!(bob_client.recv().await, ServerMessage::ChannelJoined { .. }) {}
+
+        send_message(&alice_client, alice, channel_id, "/deploy api");
+        let invocation_id = loop {
+            if let ServerMessage::CommandInvoked {
+                invocation_id,
+                user,
+                args,
+                ..
+            } = bot_client.recv().await
+            {
+                assert_eq!(user, alice);
+                assert_eq!(args, "api");
+                break invocation_id;
+            }
+        };
+        bot_client.send(ClientMessage::RespondToCommand {
+            invocation_id,
+            content: "deployed".into(),
+        });
+        loop {
+            if let ServerMessage::CommandResponse { bot, content, .. } = alice_client.recv().await {
+                assert_eq!(bot, Some(deployer));
+                assert_eq!(content, "deployed");
+                break;
+            }
+        }
+        bob_client.assert_nothing_received().await;
+
+        // Only bot connections respond
+        bob_client.send(ClientMessage::RespondToCommand {
+            invocation_id,
+            content: "forged".into(),
+        });
+        assert!(matches!(
+            bob_client.recv().await,
+            ServerMessage::Error { .. }
+        ));
+        alice_client.assert_nothing_received().await;
+    }
 
     // #[test]
     // fn test_join_serialization() {
@@ -119,8 +1579,10 @@ mod tests {
     fn test_send_message_serialization() {
         let id: ID = uuid::Uuid::parse_str("13cdc63e-55e2-403b-9ac6-4aa7c2155bf4").unwrap();
         let json = serde_json::to_string(&ClientMessage::SendMessage {
-            user: id.to_string(),
+            user: id.to_string().into(),
+            channel_id: None,
             content: "test message".into(),
+            attachments: Vec::new(),
         })
         .unwrap();
         assert_eq!(
@@ -128,4 +1590,48 @@ mod tests {
             "{\"type\":\"SendMessage\",\"user\":\"13cdc63e-55e2-403b-9ac6-4aa7c2155bf4\",\"content\":\"test message\"}"
         );
     }
+
+    #[test]
+    fn test_send_direct_message_deserialization() {
+        let json =
+            "{\"type\":\"SendDirectMessage\",\"user\":\"alice\",\"to\":\"bob\",\"content\":\"hi\"}";
+        match serde_json::from_str(json).unwrap() {
+            ClientMessage::SendDirectMessage {
+                user, to, content, ..
+            } => {
+                assert_eq!(&*user.name(), "alice");
+                assert_eq!(&*to.name(), "bob");
+                assert_eq!(content, "hi");
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_moderate_deserialization() {
+        let json = "{\"type\":\"Moderate\",\"user\":\"alice\",\"channel_id\":\"13cdc63e-55e2-403b-9ac6-4aa7c2155bf4\",\"target\":\"bob\",\"moderation\":{\"action\":\"Mute\",\"seconds\":60}}";
+        match serde_json::from_str(json).unwrap() {
+            ClientMessage::Moderate {
+                target, moderation, ..
+            } => {
+                assert_eq!(&*target.name(), "bob");
+                assert_eq!(moderation, Moderation::Mute { seconds: 60 });
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_create_channel_deserialization() {
+        let json = "{\"type\":\"CreateChannel\",\"user\":\"alice\",\"name\":\"ops\",\"visibility\":\"InviteOnly\"}";
+        match serde_json::from_str(json).unwrap() {
+            ClientMessage::CreateChannel {
+                user, visibility, ..
+            } => {
+                assert_eq!(&*user.name(), "alice");
+                assert_eq!(visibility, Visibility::InviteOnly);
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
 }